@@ -0,0 +1,18 @@
+use std::env;
+use std::path::Path;
+
+// the module `--features recompiled` builds in: the one CHIP8_RECOMPILED names,
+// relative to this directory, or the fixture the tests run
+fn main() {
+    println!("cargo:rerun-if-env-changed=CHIP8_RECOMPILED");
+    let root = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let module = match env::var("CHIP8_RECOMPILED") {
+        Ok(path) => Path::new(&root).join(path),
+        Err(_) => Path::new(&root).join("src/recompiled_fixture.rs"),
+    };
+    println!("cargo:rerun-if-changed={}", module.display());
+    println!(
+        "cargo:rustc-env=CHIP8_RECOMPILED_MODULE={}",
+        module.display()
+    );
+}
@@ -0,0 +1,149 @@
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// one 60 Hz frame worth of samples
+pub const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub enabled: bool,
+    /// pitch of the buzzer in Hz
+    pub frequency: f32,
+    /// 0.0 (silent) to 1.0 (full scale)
+    pub volume: f32,
+    /// WAV file the buzzer is rendered to
+    pub output: Option<PathBuf>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            enabled: true,
+            frequency: 440.0,
+            volume: 0.25,
+            output: None,
+        }
+    }
+}
+
+// square wave buzzer that sounds while the sound timer is non-zero
+pub struct Beeper {
+    frequency: f32,
+    amplitude: f32,
+    phase: f32,
+    sink: Option<WavWriter>,
+}
+
+impl Beeper {
+    pub fn new(config: &AudioConfig) -> io::Result<Self> {
+        let sink = match (&config.output, config.enabled) {
+            (Some(path), true) => Some(WavWriter::create(File::create(path)?)?),
+            _ => None,
+        };
+
+        Ok(Beeper {
+            frequency: config.frequency,
+            amplitude: config.volume.clamp(0.0, 1.0) * i16::MAX as f32,
+            phase: 0.0,
+            sink,
+        })
+    }
+
+    // render one frame of audio
+    pub fn frame(&mut self, on: bool) -> io::Result<()> {
+        let sink = match self.sink.as_mut() {
+            Some(sink) => sink,
+            None => return Ok(()),
+        };
+
+        let step = self.frequency / SAMPLE_RATE as f32;
+        let mut samples = [0i16; SAMPLES_PER_FRAME];
+        for sample in samples.iter_mut() {
+            if on {
+                *sample = if self.phase < 0.5 {
+                    self.amplitude as i16
+                } else {
+                    -self.amplitude as i16
+                };
+            }
+            self.phase = (self.phase + step).fract();
+        }
+
+        sink.write_samples(&samples)
+    }
+
+    // render one frame of sampled sound, unsigned 8-bit at the output sample rate
+    pub fn play(&mut self, pcm: &[u8]) -> io::Result<()> {
+        let sink = match self.sink.as_mut() {
+            Some(sink) => sink,
+            None => return Ok(()),
+        };
+
+        let amplitude = self.amplitude;
+        let samples: Vec<i16> = pcm
+            .iter()
+            .map(|&sample| ((sample as f32 - 128.0) / 128.0 * amplitude) as i16)
+            .collect();
+        sink.write_samples(&samples)
+    }
+}
+
+// mono 16-bit PCM WAV writer, the header sizes are patched in on drop
+struct WavWriter {
+    out: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn create(file: File) -> io::Result<Self> {
+        let mut writer = WavWriter {
+            out: BufWriter::new(file),
+            data_len: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let out = &mut self.out;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(36 + self.data_len).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        out.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?; // byte rate
+        out.write_all(&2u16.to_le_bytes())?; // block align
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&self.data_len.to_le_bytes())
+    }
+
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            println!("failed to finish WAV file: {}", e);
+        }
+    }
+}
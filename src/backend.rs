@@ -0,0 +1,123 @@
+use crate::cpu::{StepOutcome, CPU};
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+#[cfg(feature = "recompiled")]
+use crate::recompiled;
+use clap::ValueEnum;
+use std::io;
+
+// what runs the CHIP-8 code
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum BackendKind {
+    #[default]
+    Interpreter,
+    // compiles hot basic blocks to native code, needs `--features jit`
+    Jit,
+    // the ROM built in by `recompile`, needs `--features recompiled`
+    Recompiled,
+}
+
+pub enum Backend {
+    Interpreter,
+    #[cfg(feature = "jit")]
+    Jit(Box<Jit>),
+    #[cfg(feature = "recompiled")]
+    Recompiled,
+}
+
+impl Backend {
+    pub fn new(kind: BackendKind) -> io::Result<Self> {
+        match kind {
+            BackendKind::Interpreter => Ok(Backend::Interpreter),
+            #[cfg(feature = "jit")]
+            BackendKind::Jit => Ok(Backend::Jit(Box::new(Jit::new()?))),
+            #[cfg(not(feature = "jit"))]
+            BackendKind::Jit => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the JIT backend needs a build with `--features jit`",
+            )),
+            #[cfg(feature = "recompiled")]
+            BackendKind::Recompiled => Ok(Backend::Recompiled),
+            #[cfg(not(feature = "recompiled"))]
+            BackendKind::Recompiled => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the recompiled backend needs a build with `--features recompiled`, with \
+                 CHIP8_RECOMPILED naming a module from `recompile`",
+            )),
+        }
+    }
+
+    pub fn is_jit(&self) -> bool {
+        #[cfg(feature = "jit")]
+        if let Backend::Jit(_) = self {
+            return true;
+        }
+        false
+    }
+
+    // run exactly `count` instructions, returns the last outcome other than running
+    // the interpreter saw, or what the other backends end up doing
+    pub fn run(&mut self, cpu: &mut CPU, count: u32) -> StepOutcome {
+        match self {
+            Backend::Interpreter => {
+                let mut outcome = StepOutcome::Running;
+                for _ in 0..count {
+                    match cpu.tick() {
+                        StepOutcome::Running => (),
+                        seen => outcome = seen,
+                    }
+                }
+                outcome
+            }
+            #[cfg(feature = "jit")]
+            Backend::Jit(jit) => {
+                jit.run(cpu, count);
+                cpu.outcome()
+            }
+            #[cfg(feature = "recompiled")]
+            Backend::Recompiled => {
+                for _ in 0..count {
+                    recompiled::step(cpu);
+                }
+                cpu.outcome()
+            }
+        }
+    }
+
+    // run up to `count` instructions, stopping once the program halts, exits or
+    // idles: the rest of the frame would go around the same loop, as nothing it
+    // waits on changes before the next one
+    pub fn run_until_idle(&mut self, cpu: &mut CPU, count: u32) -> StepOutcome {
+        match self {
+            Backend::Interpreter => {
+                for _ in 0..count {
+                    let outcome = cpu.tick();
+                    if outcome != StepOutcome::Running {
+                        return outcome;
+                    }
+                }
+                StepOutcome::Running
+            }
+            #[cfg(any(feature = "jit", feature = "recompiled"))]
+            _ => self.run(cpu, count),
+        }
+    }
+
+    // run at least one and at most `budget` instructions, returns how many ran
+    #[cfg_attr(not(feature = "jit"), allow(unused_variables))]
+    pub fn step(&mut self, cpu: &mut CPU, budget: u32) -> u32 {
+        match self {
+            Backend::Interpreter => {
+                cpu.tick();
+                1
+            }
+            #[cfg(feature = "jit")]
+            Backend::Jit(jit) => jit.step(cpu, budget.max(1)),
+            #[cfg(feature = "recompiled")]
+            Backend::Recompiled => {
+                recompiled::step(cpu);
+                1
+            }
+        }
+    }
+}
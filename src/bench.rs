@@ -0,0 +1,90 @@
+use crate::backend::Backend;
+use crate::cpu::CPU;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// what a benchmark run managed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BenchResult {
+    pub instructions: u64,
+    pub frames: u64,
+    pub elapsed: Duration,
+    // the program counter left memory, so the run ended early
+    pub ran_off: bool,
+}
+
+impl BenchResult {
+    pub fn mips(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64().max(1e-9) / 1e6
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} instructions in {} frames, {:.2} s: {:.2} MIPS",
+            self.instructions,
+            self.frames,
+            self.elapsed.as_secs_f64(),
+            self.mips()
+        )?;
+        if self.ran_off {
+            write!(f, " (stopped early, the program counter left memory)")?;
+        }
+        Ok(())
+    }
+}
+
+// run frames of `ipf` instructions flat out, without a frontend, until `duration` has passed
+pub fn run(cpu: &mut CPU, backend: &mut Backend, ipf: u32, duration: Duration) -> BenchResult {
+    let start = Instant::now();
+    let end = cpu.heap.len() - 1;
+    let mut result = BenchResult {
+        instructions: 0,
+        frames: 0,
+        elapsed: Duration::ZERO,
+        ran_off: false,
+    };
+
+    while start.elapsed() < duration && !result.ran_off {
+        let mut remaining = ipf;
+        while remaining > 0 {
+            if cpu.program_counter >= end {
+                result.ran_off = true;
+                break;
+            }
+            let count = backend.step(cpu, remaining);
+            result.instructions += count as u64;
+            remaining -= count;
+        }
+        cpu.update_timers();
+        result.frames += 1;
+    }
+
+    result.elapsed = start.elapsed();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+
+    #[test]
+    fn test_runs_until_program_counter_leaves_memory() {
+        // JP 0xFFC, then zeroes up to the end of memory
+        let mut cpu = cpu_with_program(&[0x1F, 0xFC]);
+        let result = run(
+            &mut cpu,
+            &mut Backend::Interpreter,
+            10,
+            Duration::from_secs(10),
+        );
+        assert!(result.ran_off);
+        assert_eq!(
+            (result.instructions, result.frames, cpu.program_counter),
+            (3, 1, 0x1000)
+        );
+    }
+}
@@ -0,0 +1,491 @@
+// RCA CDP1802, the CPU of the COSMAC VIP
+
+/// What the 1802 is wired to: memory, the N lines that select an I/O port for
+/// OUT/INP, and the EF1-EF4 flag inputs the branch instructions test.
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+
+    // OUT 1-7, `value` is the byte at R(X)
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    // INP 1-7, the byte read goes to both R(X) and D
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    // whether flag input EF`n` (1-4) is asserted
+    fn flag(&mut self, _n: u8) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cdp1802 {
+    pub r: [u16; 16], // scratchpad registers
+    pub d: u8,        // accumulator
+    pub df: bool,     // carry/borrow
+    pub p: u8,        // which R is the program counter
+    pub x: u8,        // which R is the data pointer
+    pub t: u8,        // X and P saved by an interrupt or MARK
+    pub ie: bool,     // interrupts enabled
+    pub q: bool,      // the Q output, the VIP's tone generator
+    pub idle: bool,   // stopped by IDL until a DMA or interrupt
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Self {
+        Cdp1802::new()
+    }
+}
+
+impl Cdp1802 {
+    // in the state a reset leaves it, running from 0x0000 with R0 as the PC
+    pub fn new() -> Self {
+        Cdp1802 {
+            r: [0; 16],
+            d: 0,
+            df: false,
+            p: 0,
+            x: 0,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.r[self.p as usize]
+    }
+
+    // the byte at R(P), advancing R(P) past it
+    fn immediate<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let pc = self.pc();
+        self.r[self.p as usize] = pc.wrapping_add(1);
+        bus.read(pc)
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    fn add_rx(&mut self, delta: i16) {
+        let x = self.x as usize;
+        self.r[x] = self.r[x].wrapping_add(delta as u16);
+    }
+
+    // D = a + b + carry, DF is the carry out
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // D = a - b - borrow, DF is set when there was no borrow
+    fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    /// Interrupt request: saves X and P in T and jumps to the routine R1 points
+    /// at, with R2 as the data pointer. Ignored while interrupts are disabled;
+    /// returns whether it was taken, which costs one machine cycle.
+    pub fn interrupt(&mut self) -> bool {
+        if !self.ie {
+            return false;
+        }
+        self.t = self.x << 4 | self.p;
+        self.x = 2;
+        self.p = 1;
+        self.ie = false;
+        self.idle = false;
+        true
+    }
+
+    /// DMA out cycle: the byte at R0 goes to the device, and R0 moves on to the
+    /// next one. Takes one machine cycle.
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    /// Run one instruction, returns the machine cycles it took: 2, or 3 for the
+    /// long branches and skips. While idle only a cycle passes.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let opcode = self.immediate(bus);
+        let n = opcode & 0xF;
+        let rn = n as usize;
+
+        match opcode >> 4 {
+            // IDL, then LDN
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[rn]),
+            0x1 => self.r[rn] = self.r[rn].wrapping_add(1),
+            0x2 => self.r[rn] = self.r[rn].wrapping_sub(1),
+            // short branches replace the low byte of the PC
+            0x3 => {
+                let taken = match n {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    0x3 => self.df,
+                    0x4..=0x7 => bus.flag(n - 3),
+                    // SKP
+                    0x8 => false,
+                    0x9 => !self.q,
+                    0xA => self.d != 0,
+                    0xB => !self.df,
+                    _ => !bus.flag(n - 0xB),
+                };
+                let p = self.p as usize;
+                if taken {
+                    let low = bus.read(self.r[p]);
+                    self.r[p] = self.r[p] & 0xFF00 | low as u16;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(1);
+                }
+            }
+            // LDA
+            0x4 => {
+                self.d = bus.read(self.r[rn]);
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            }
+            // STR
+            0x5 => bus.write(self.r[rn], self.d),
+            0x6 => match n {
+                // IRX
+                0x0 => self.add_rx(1),
+                // OUT
+                0x1..=0x7 => {
+                    let value = bus.read(self.rx());
+                    bus.output(n, value);
+                    self.add_rx(1);
+                }
+                // the 1802 has no 68 instruction, it does nothing
+                0x8 => (),
+                // INP
+                _ => {
+                    let value = bus.input(n - 8);
+                    bus.write(self.rx(), value);
+                    self.d = value;
+                }
+            },
+            0x7 => self.misc(n, bus),
+            // GLO, GHI, PLO, PHI
+            0x8 => self.d = self.r[rn] as u8,
+            0x9 => self.d = (self.r[rn] >> 8) as u8,
+            0xA => self.r[rn] = self.r[rn] & 0xFF00 | self.d as u16,
+            0xB => self.r[rn] = self.r[rn] & 0x00FF | (self.d as u16) << 8,
+            0xC => {
+                self.long(n, bus);
+                return 3;
+            }
+            // SEP, SEX
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => self.alu(n, bus),
+        }
+        2
+    }
+
+    // 7N: the interrupt returns, carry arithmetic, Q and MARK
+    fn misc<B: Bus>(&mut self, n: u8, bus: &mut B) {
+        match n {
+            // RET, DIS
+            0x0 | 0x1 => {
+                let xp = bus.read(self.rx());
+                self.add_rx(1);
+                self.x = xp >> 4;
+                self.p = xp & 0xF;
+                self.ie = n == 0;
+            }
+            // LDXA
+            0x2 => {
+                self.d = bus.read(self.rx());
+                self.add_rx(1);
+            }
+            // STXD
+            0x3 => {
+                bus.write(self.rx(), self.d);
+                self.add_rx(-1);
+            }
+            // ADC, SDB, SMB and their immediate forms ADCI, SDBI, SMBI
+            0x4 | 0x5 | 0x7 | 0xC | 0xD | 0xF => {
+                let operand = if n < 0x8 {
+                    bus.read(self.rx())
+                } else {
+                    self.immediate(bus)
+                };
+                match n & 0x7 {
+                    0x4 => self.add(operand, self.d, self.df),
+                    0x5 => self.subtract(operand, self.d, !self.df),
+                    _ => self.subtract(self.d, operand, !self.df),
+                }
+            }
+            // SHRC
+            0x6 => {
+                let carry = self.d & 1 != 0;
+                self.d = self.d >> 1 | (self.df as u8) << 7;
+                self.df = carry;
+            }
+            // SAV
+            0x8 => bus.write(self.rx(), self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            // REQ, SEQ
+            0xA => self.q = false,
+            0xB => self.q = true,
+            // SHLC
+            _ => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as u8;
+                self.df = carry;
+            }
+        }
+    }
+
+    // CN: long branches to the next two bytes, and long skips over them
+    fn long<B: Bus>(&mut self, n: u8, bus: &mut B) {
+        let condition = match n & 0x3 {
+            0x0 => true,
+            0x1 => self.q,
+            0x2 => self.d == 0,
+            _ => self.df,
+        };
+        let p = self.p as usize;
+        match n {
+            // LBR, LBQ, LBZ, LBDF and the inverted LBNQ, LBNZ, LBNF
+            0x0..=0x3 | 0x9..=0xB => {
+                if condition == (n < 0x8) {
+                    let high = bus.read(self.r[p]);
+                    let low = bus.read(self.r[p].wrapping_add(1));
+                    self.r[p] = (high as u16) << 8 | low as u16;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+            }
+            // NOP
+            0x4 => (),
+            // LSKP
+            0x8 => self.r[p] = self.r[p].wrapping_add(2),
+            // LSIE, then LSNQ, LSNZ, LSNF and LSQ, LSZ, LSDF
+            _ => {
+                let skip = match n {
+                    0xC => self.ie,
+                    0x5..=0x7 => !condition,
+                    _ => condition,
+                };
+                if skip {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+            }
+        }
+    }
+
+    // FN: logic and arithmetic on D, with M(R(X)) or an immediate byte
+    fn alu<B: Bus>(&mut self, n: u8, bus: &mut B) {
+        // SHR and SHL work on D alone
+        match n {
+            0x6 => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+                return;
+            }
+            0xE => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+                return;
+            }
+            _ => (),
+        }
+        let operand = if n < 0x8 {
+            bus.read(self.rx())
+        } else {
+            self.immediate(bus)
+        };
+        match n & 0x7 {
+            // LDX, LDI
+            0x0 => self.d = operand,
+            0x1 => self.d |= operand,
+            0x2 => self.d &= operand,
+            0x3 => self.d ^= operand,
+            0x4 => self.add(operand, self.d, false),
+            // SD, SDI
+            0x5 => self.subtract(operand, self.d, false),
+            // SM, SMI
+            _ => self.subtract(self.d, operand, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 64K of RAM, with a latch for the last OUT and flags to test
+    struct TestBus {
+        ram: Vec<u8>,
+        out: Vec<(u8, u8)>,
+        flags: [bool; 4],
+    }
+
+    impl TestBus {
+        fn new(program: &[u8]) -> Self {
+            let mut ram = vec![0; 0x10000];
+            ram[..program.len()].copy_from_slice(program);
+            TestBus {
+                ram,
+                out: Vec::new(),
+                flags: [false; 4],
+            }
+        }
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.ram[addr as usize]
+        }
+
+        fn write(&mut self, addr: u16, value: u8) {
+            self.ram[addr as usize] = value;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.out.push((port, value));
+        }
+
+        fn input(&mut self, port: u8) -> u8 {
+            0x40 | port
+        }
+
+        fn flag(&mut self, n: u8) -> bool {
+            self.flags[n as usize - 1]
+        }
+    }
+
+    // run until IDL, returns the machine cycles taken
+    fn run(cpu: &mut Cdp1802, bus: &mut TestBus) -> u32 {
+        let mut cycles = 0;
+        while !cpu.idle {
+            cycles += cpu.step(bus);
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_arithmetic_and_shifts() {
+        let mut cpu = Cdp1802::new();
+        let mut bus = TestBus::new(&[
+            0xF8, 0x80, // LDI 0x80
+            0xFC, 0x90, // ADI 0x90: 0x10, carry
+            0xA3, // PLO R3
+            0x7C, 0x00, // ADCI 0: 0x11
+            0xB3, // PHI R3
+            0xFF, 0x20, // SMI 0x20: 0xF1, borrow
+            0x7F, 0x00, // SMBI 0: 0xF0
+            0xA4, // PLO R4
+            0xFD, 0x01, // SDI 1: 0x11, borrow
+            0x76, // SHRC: 0x08, DF from bit 0
+            0xB4, // PHI R4
+            0xFE, // SHL: 0x10
+            0x7E, // SHLC: 0x20
+            0x00, // IDL
+        ]);
+        let cycles = run(&mut cpu, &mut bus);
+        assert_eq!(cpu.r[3], 0x1110);
+        assert_eq!(cpu.r[4], 0x08F0);
+        assert_eq!((cpu.d, cpu.df), (0x20, false));
+        assert_eq!(cycles, 14 * 2);
+    }
+
+    #[test]
+    fn test_branches_and_skips() {
+        let mut cpu = Cdp1802::new();
+        let mut bus = TestBus::new(&[
+            0xF8, 0x00, // 00: LDI 0
+            0x32, 0x06, // 02: BZ 06
+            0x7B, // 04: SEQ, skipped
+            0x00, // 05: IDL
+            0x3A, 0x04, // 06: BNZ 04, not taken
+            0x34, 0x04, // 08: B1 04, EF1 is clear
+            0xC6, // 0A: LSNZ, not taken
+            0xC0, 0x01, 0x00, // 0B: LBR 0100
+        ]);
+        bus.ram[0x100..0x106].copy_from_slice(&[
+            0xCE, // LSZ over the SEQ
+            0x7B, 0x00, // SEQ, IDL
+            0x38, // SKP
+            0x7B, // SEQ, skipped
+            0x00, // IDL
+        ]);
+        let cycles = run(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc(), 0x106);
+        assert!(!cpu.q);
+        assert_eq!(cycles, 2 * 6 + 3 * 3);
+
+        // with EF1 asserted B1 is taken, and runs into the SEQ
+        let mut cpu = Cdp1802::new();
+        bus.flags[0] = true;
+        run(&mut cpu, &mut bus);
+        assert!(cpu.q);
+        assert_eq!(cpu.pc(), 0x06);
+    }
+
+    #[test]
+    fn test_subroutines_io_and_interrupts() {
+        let mut cpu = Cdp1802::new();
+        let mut bus = TestBus::new(&[
+            0xF8, 0x20, 0xA3, // 00: R3 = 0x20
+            0xF8, 0x80, 0xA2, // 03: R2 = 0x80
+            0xF8, 0x40, 0xA1, // 06: R1 = 0x40
+            0xE2, // 09: SEX R2
+            0xD3, // 0A: SEP R3
+        ]);
+        bus.ram[0x20..0x2A].copy_from_slice(&[
+            0xF8, 0x5A, // LDI 0x5A
+            0x73, // STXD
+            0x60, // IRX
+            0x65, // OUT 5, 0x5A
+            0x6B, // INP 3: 0x43 to M(R2) and D
+            0x00, // IDL, until the interrupt
+            0x7A, 0x00, // REQ, IDL
+            0x00,
+        ]);
+        // the interrupt routine saves T and returns with interrupts enabled again
+        bus.ram[0x40..0x44].copy_from_slice(&[0x22, 0x78, 0x7B, 0x70]);
+        run(&mut cpu, &mut bus);
+        assert_eq!(bus.out, vec![(5, 0x5A)]);
+        assert_eq!(cpu.d, 0x43);
+        assert_eq!(bus.ram[0x81], 0x43);
+
+        assert!(cpu.interrupt());
+        assert_eq!((cpu.p, cpu.x, cpu.t), (1, 2, 0x23));
+        // DEC R2, SAV, SEQ, RET
+        for _ in 0..4 {
+            cpu.step(&mut bus);
+        }
+        assert_eq!(bus.ram[0x80], 0x23);
+        assert!(cpu.q && cpu.ie);
+        assert_eq!((cpu.p, cpu.x, cpu.pc()), (3, 2, 0x27));
+        run(&mut cpu, &mut bus);
+        assert!(!cpu.q);
+
+        // DMA reads through R0, and wakes the CPU from IDL
+        cpu.r[0] = 0x20;
+        assert_eq!(cpu.dma_out(&mut bus), 0xF8);
+        assert_eq!(cpu.r[0], 0x21);
+        assert!(!cpu.idle);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::display::Display;
+
+// RCA CDP1861, the VIP's video chip. A frame is 262 lines of 14 machine cycles,
+// 128 of them show a picture fetched from memory by DMA, 8 bytes to a line
+pub const CYCLES_PER_LINE: u32 = 14;
+pub const CYCLES_PER_FRAME: u32 = 262 * CYCLES_PER_LINE;
+const PICTURE_START: u32 = 80;
+const PICTURE_LINES: usize = 128;
+// INT is held for the two lines before the picture, so the interrupt routine can
+// point R0 at display memory in time for the first DMA
+const INTERRUPT_START: u32 = 78;
+// EF1 is asserted for the 4 lines before the picture and its last 4
+const FLAG_LINES: [u32; 2] = [76, 204];
+// the DMA of a line starts 2 cycles into it
+const DMA_START: u32 = 2;
+const DMA_BYTES: u32 = 8;
+
+pub struct Cdp1861 {
+    // turned on by INP 1 and off by OUT 1
+    pub enabled: bool,
+    // machine cycles into the frame
+    cycle: u32,
+    // bytes fetched by DMA on the current line
+    fetched: u32,
+    // the lines of the frame being drawn, column 0 in the most significant bit
+    picture: [u64; PICTURE_LINES],
+}
+
+impl Default for Cdp1861 {
+    fn default() -> Self {
+        Cdp1861::new()
+    }
+}
+
+impl Cdp1861 {
+    pub fn new() -> Self {
+        Cdp1861 {
+            enabled: false,
+            cycle: 0,
+            fetched: 0,
+            picture: [0; PICTURE_LINES],
+        }
+    }
+
+    fn line(&self) -> u32 {
+        self.cycle / CYCLES_PER_LINE
+    }
+
+    // the line of the picture being drawn, if any
+    fn picture_line(&self) -> Option<usize> {
+        let line = self.line().checked_sub(PICTURE_START)? as usize;
+        if line < PICTURE_LINES {
+            Some(line)
+        } else {
+            None
+        }
+    }
+
+    // whether INT is asserted
+    pub fn interrupt(&self) -> bool {
+        self.enabled && (INTERRUPT_START..PICTURE_START).contains(&self.line())
+    }
+
+    // whether EF1 is asserted
+    pub fn flag(&self) -> bool {
+        FLAG_LINES
+            .iter()
+            .any(|&start| (start..start + 4).contains(&self.line()))
+    }
+
+    // whether the chip wants a DMA cycle now
+    pub fn dma_pending(&self) -> bool {
+        self.enabled
+            && self.picture_line().is_some()
+            && self.cycle % CYCLES_PER_LINE >= DMA_START
+            && self.fetched < DMA_BYTES
+    }
+
+    // take the byte a DMA cycle fetched
+    pub fn dma(&mut self, byte: u8) {
+        if let Some(line) = self.picture_line() {
+            let shift = 56 - 8 * self.fetched;
+            let row = &mut self.picture[line];
+            *row = *row & !(0xFF << shift) | (byte as u64) << shift;
+            self.fetched += 1;
+        }
+    }
+
+    // let `cycles` machine cycles pass, returns true when that finishes a frame
+    pub fn advance(&mut self, cycles: u32) -> bool {
+        let line = self.line();
+        self.cycle += cycles;
+        if self.line() != line {
+            self.fetched = 0;
+        }
+        if self.cycle < CYCLES_PER_FRAME {
+            return false;
+        }
+        self.cycle -= CYCLES_PER_FRAME;
+        if !self.enabled {
+            self.picture = [0; PICTURE_LINES];
+        }
+        true
+    }
+
+    // copy the last frame to `display`, which shows every nth line of the picture
+    // for a display n times less tall. The VIP interpreter repeats each of its 32
+    // rows on 4 lines
+    pub fn show(&self, display: &mut Display) {
+        let step = PICTURE_LINES / display.height();
+        for (y, row) in display.rows_mut().iter_mut().enumerate() {
+            *row = self.picture[y * step];
+        }
+    }
+}
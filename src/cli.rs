@@ -0,0 +1,391 @@
+use crate::backend::BackendKind;
+use crate::config::{invalid, Settings};
+use crate::cpu::Quirks;
+use crate::frontend::{Color, FrontendKind};
+use crate::keymap::HostKeys;
+use crate::timing::Timing;
+use crate::trace::{parse_address_range, parse_class, parse_frame_range, TraceFormat};
+use crate::variant::Variant;
+use clap::{Args, Parser, Subcommand};
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+// command line flags, anything given here wins over the config file
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "CHIP-8 emulator",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// ROM file to run (binary, or text assembly)
+    #[arg(required = true)]
+    pub rom: Option<PathBuf>,
+
+    /// Config file [default: chip8.toml in the working directory, if present]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Window scale factor: 1, 2, 4, 8, 16 or 32
+    #[arg(long)]
+    pub scale: Option<u32>,
+
+    /// CHIP-8 instructions executed per 60 Hz frame
+    #[arg(long = "ipf", value_name = "N")]
+    pub instructions_per_frame: Option<u32>,
+
+    /// Run a fixed number of instructions per frame, or as many as fit in a COSMAC VIP frame
+    #[arg(long, value_enum)]
+    pub timing: Option<Timing>,
+
+    /// Instruction set the ROM was written for: CHIP-8, CHIP-8X, CHIP-8E, hi-res CHIP-8 or MegaChip
+    #[arg(long, value_enum)]
+    pub variant: Option<Variant>,
+
+    /// Start paused, F2 then advances one frame at a time
+    #[arg(long)]
+    pub paused: bool,
+
+    /// Run N frames, one per host frame, then pause as F2 does
+    #[arg(long, value_name = "N")]
+    pub frame_advance: Option<u32>,
+
+    /// Frames run per host frame while fast-forwarding (hold Tab)
+    #[arg(long, value_name = "N")]
+    pub fast_forward: Option<u32>,
+
+    /// Start in slow motion, running N times slower (F3 toggles it)
+    #[arg(long, value_name = "N")]
+    pub slow_motion: Option<u32>,
+
+    #[arg(long, value_enum)]
+    pub frontend: Option<FrontendKind>,
+
+    /// Run the CHIP-8 code in the interpreter, or compile hot blocks with the JIT (needs the `jit` feature)
+    #[arg(long, value_enum, default_value = "interpreter")]
+    pub backend: BackendKind,
+
+    /// Color of lit pixels, as #RRGGBB
+    #[arg(long, value_name = "COLOR")]
+    pub foreground: Option<Color>,
+
+    /// Color of unlit pixels, as #RRGGBB
+    #[arg(long, value_name = "COLOR")]
+    pub background: Option<Color>,
+
+    /// Turn a quirk on or off, e.g. `--quirk vf_reset` or `--quirk shift_in_place=false`
+    #[arg(long = "quirk", value_name = "NAME[=BOOL]")]
+    pub quirks: Vec<String>,
+
+    /// Keymap layout: qwerty, azerty, dvorak or numpad
+    #[arg(long, value_name = "NAME")]
+    pub layout: Option<String>,
+
+    /// Bind a CHIP-8 key to one or more host keys, e.g. `--key 5=Up` or `--key 5=Up,Space`
+    #[arg(long = "key", value_name = "HEX=KEY[,KEY...]")]
+    pub keys: Vec<String>,
+
+    /// Read gamepads (needs the `gamepad` feature)
+    #[arg(long)]
+    pub gamepad: bool,
+
+    /// Replay key presses from a script of `<frame> <key> down|up` lines
+    #[arg(long, value_name = "FILE")]
+    pub input_script: Option<PathBuf>,
+
+    /// Run a Rhai script with hooks into the program (needs the `scripting` feature)
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
+    /// Disable the buzzer
+    #[arg(long)]
+    pub mute: bool,
+
+    /// Render the buzzer to a WAV file
+    #[arg(long, value_name = "FILE")]
+    pub audio_output: Option<PathBuf>,
+
+    /// Wait for a gdb remote protocol client on 127.0.0.1:PORT before running
+    #[arg(long, value_name = "PORT")]
+    pub gdb: Option<u16>,
+
+    /// Wait for a Debug Adapter Protocol client on 127.0.0.1:PORT before running
+    #[arg(long, value_name = "PORT", conflicts_with = "gdb")]
+    pub dap: Option<u16>,
+
+    /// Log every executed instruction and what it changed to FILE
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value = "text", requires = "trace")]
+    pub trace_format: TraceFormat,
+
+    /// Only trace instructions at these addresses, in hex, e.g. `--trace-addr 200-2FF`
+    #[arg(long, value_name = "START-END", value_parser = parse_address_range, requires = "trace")]
+    pub trace_addr: Option<RangeInclusive<usize>>,
+
+    /// Only trace these opcode classes (the first hex digit), e.g. `--trace-class 8,D`
+    #[arg(long, value_name = "HEX", value_parser = parse_class, value_delimiter = ',', requires = "trace")]
+    pub trace_class: Vec<u8>,
+
+    /// Only trace these frames, e.g. `--trace-frames 100-120`
+    #[arg(long, value_name = "START-END", value_parser = parse_frame_range, requires = "trace")]
+    pub trace_frames: Option<RangeInclusive<u64>>,
+
+    /// Stop after this many frames
+    #[arg(long, value_name = "N")]
+    pub frames: Option<u64>,
+
+    /// Emulate a COSMAC VIP running this CHIP-8 interpreter image, loaded at 0x000
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["gdb", "dap", "trace"])]
+    pub vip_interpreter: Option<PathBuf>,
+
+    /// Boot the VIP through this monitor ROM, mapped at 0x8000
+    #[arg(long, value_name = "FILE", requires = "vip_interpreter")]
+    pub vip_monitor: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Compare two binary traces and show where they diverge
+    TraceDiff {
+        a: PathBuf,
+        b: PathBuf,
+
+        /// Matching records to show before the divergence
+        #[arg(long, value_name = "N", default_value_t = 3)]
+        context: usize,
+    },
+
+    /// Run a ROM on two CPU configurations, or against another emulator's trace, until they diverge
+    DiffRun(DiffRunArgs),
+
+    /// Run a ROM headless as fast as possible and report millions of instructions per second
+    Bench(BenchArgs),
+
+    /// Show, export or reset the RPL flags a ROM saved with Fx75
+    Flags(FlagsArgs),
+
+    /// Translate a ROM's reachable code into a Rust module for `--features recompiled`
+    Recompile {
+        /// ROM file to translate (binary, or text assembly)
+        rom: PathBuf,
+
+        /// Where to write the module [default: standard output]
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Config file with the machine the ROM runs on [default: chip8.toml in the
+        /// working directory, if present]
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct FlagsArgs {
+    /// ROM file the flags belong to (binary, or text assembly)
+    pub rom: PathBuf,
+
+    /// Config file [default: chip8.toml in the working directory, if present]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Copy the saved flags to FILE, 16 raw bytes as in the save directory
+    #[arg(long, value_name = "FILE")]
+    pub export: Option<PathBuf>,
+
+    /// Delete the saved flags, after exporting them if --export is given too
+    #[arg(long)]
+    pub reset: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// ROM file to run (binary, or text assembly)
+    pub rom: PathBuf,
+
+    /// Config file [default: chip8.toml in the working directory, if present]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// CHIP-8 instructions executed per 60 Hz frame
+    #[arg(long = "ipf", value_name = "N")]
+    pub instructions_per_frame: Option<u32>,
+
+    /// How long to run for
+    #[arg(long, value_name = "SECONDS", default_value_t = 5.0)]
+    pub seconds: f64,
+
+    /// Decode every instruction each time it runs, to measure what the decode cache saves
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Run the CHIP-8 code in the interpreter, or compile hot blocks with the JIT (needs the `jit` feature)
+    #[arg(long, value_enum, default_value = "interpreter")]
+    pub backend: BackendKind,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffRunArgs {
+    /// ROM file to run (binary, or text assembly)
+    pub rom: PathBuf,
+
+    /// Config file [default: chip8.toml in the working directory, if present]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Turn a quirk on or off in both runs
+    #[arg(long = "quirk", value_name = "NAME[=BOOL]")]
+    pub quirks: Vec<String>,
+
+    /// Turn a quirk on or off in run a only
+    #[arg(long = "quirk-a", value_name = "NAME[=BOOL]")]
+    pub quirks_a: Vec<String>,
+
+    /// Turn a quirk on or off in run b only
+    #[arg(
+        long = "quirk-b",
+        value_name = "NAME[=BOOL]",
+        conflicts_with = "reference"
+    )]
+    pub quirks_b: Vec<String>,
+
+    /// Compare run a against a trace from another emulator instead of a second run
+    #[arg(long, value_name = "FILE")]
+    pub reference: Option<PathBuf>,
+
+    /// What runs the CHIP-8 code in run a
+    #[arg(long, value_enum, default_value = "interpreter")]
+    pub backend_a: BackendKind,
+
+    /// What runs the CHIP-8 code in run b, `--backend-b jit` checks the JIT against the interpreter
+    #[arg(
+        long,
+        value_enum,
+        default_value = "interpreter",
+        conflicts_with = "reference"
+    )]
+    pub backend_b: BackendKind,
+
+    /// CHIP-8 instructions executed per 60 Hz frame
+    #[arg(long = "ipf", value_name = "N")]
+    pub instructions_per_frame: Option<u32>,
+
+    /// Replay key presses from a script of `<frame> <key> down|up` lines, into both runs
+    #[arg(long, value_name = "FILE")]
+    pub input_script: Option<PathBuf>,
+
+    /// Frames to run two configurations for
+    #[arg(long, value_name = "N", default_value_t = 600)]
+    pub frames: u64,
+
+    /// Seed for RND, shared by both runs
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub seed: u64,
+}
+
+impl Cli {
+    // layer the command line on top of the settings from the config file
+    pub fn apply(&self, settings: &mut Settings) -> io::Result<()> {
+        if let Some(scale) = self.scale {
+            settings.scale = scale;
+        }
+        if let Some(ipf) = self.instructions_per_frame {
+            settings.instructions_per_frame = ipf;
+        }
+        if let Some(timing) = self.timing {
+            settings.timing = timing;
+        }
+        if let Some(variant) = self.variant {
+            settings.variant = variant;
+        }
+        if let Some(fast_forward) = self.fast_forward {
+            settings.fast_forward = fast_forward;
+        }
+        if let Some(slow_motion) = self.slow_motion {
+            settings.slow_motion = slow_motion;
+        }
+        if let Some(frontend) = self.frontend {
+            settings.frontend = frontend;
+        }
+        // headless runs go as fast as the host allows, and have no hotkeys to unpause with
+        if settings.frontend != FrontendKind::Window {
+            let speed_flags = [
+                ("--paused", self.paused),
+                ("--frame-advance", self.frame_advance.is_some()),
+                ("--slow-motion", self.slow_motion.is_some()),
+                ("--fast-forward", self.fast_forward.is_some()),
+            ];
+            if let Some((flag, _)) = speed_flags.iter().find(|(_, given)| *given) {
+                return Err(invalid(format!("{} needs the window frontend", flag)));
+            }
+        }
+        if let Some(color) = self.foreground {
+            settings.palette.foreground = color;
+        }
+        if let Some(color) = self.background {
+            settings.palette.background = color;
+        }
+
+        for quirk in &self.quirks {
+            apply_quirk(&mut settings.quirks, quirk)?;
+        }
+
+        if let Some(layout) = &self.layout {
+            settings.keymap.layout = layout.clone();
+        }
+        for key in &self.keys {
+            let (chip8_key, host_keys) = key
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("invalid --key {}, expected HEX=KEY", key)))?;
+            let host_keys = host_keys
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+            settings
+                .keymap
+                .bindings
+                .insert(chip8_key.to_string(), HostKeys::Many(host_keys));
+        }
+
+        if self.gamepad {
+            settings.gamepad.enabled = true;
+        }
+        if self.mute {
+            settings.audio.enabled = false;
+        }
+        if let Some(path) = &self.audio_output {
+            settings.audio.output = Some(path.clone());
+        }
+
+        settings.validate()
+    }
+}
+
+// `NAME` or `NAME=BOOL`, as given to --quirk
+pub fn apply_quirk(quirks: &mut Quirks, quirk: &str) -> io::Result<()> {
+    let (name, enabled) = match quirk.split_once('=') {
+        Some((name, value)) => (
+            name,
+            value
+                .parse()
+                .map_err(|_| invalid(format!("invalid value in --quirk {}", quirk)))?,
+        ),
+        None => (quirk, true),
+    };
+    if !quirks.set(name, enabled) {
+        return Err(invalid(format!(
+            "unknown quirk {:?}, expected one of {}",
+            name,
+            Quirks::NAMES.join(", ")
+        )));
+    }
+    Ok(())
+}
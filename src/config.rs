@@ -0,0 +1,326 @@
+use crate::audio::AudioConfig;
+use crate::cpu::Quirks;
+use crate::display::DISPLAY_WIDTH;
+use crate::font::FontConfig;
+use crate::frontend::{FrontendKind, Palette};
+use crate::input::GamepadConfig;
+use crate::keymap::{Keymap, KeymapConfig};
+use crate::machine::{Machine, MachineConfig};
+use crate::timing::Timing;
+use crate::variant::Variant;
+use crate::vip::STACK_FROM_TOP;
+use minifb::Scale;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// config file picked up from the working directory when --config isn't given
+pub const DEFAULT_CONFIG_FILE: &str = "chip8.toml";
+
+// fully resolved settings for one run
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// window scale factor: 1, 2, 4, 8, 16 or 32
+    pub scale: u32,
+    pub instructions_per_frame: u32,
+    /// fixed instructions per frame, or COSMAC VIP machine cycles per instruction
+    pub timing: Timing,
+    /// the instruction set the ROM was written for
+    pub variant: Variant,
+    /// load address, memory and stack, in place of the variant's
+    pub machine: MachineConfig,
+    /// hex digit glyphs and where they go
+    pub font: FontConfig,
+    /// frames run per host frame while fast-forwarding
+    pub fast_forward: u32,
+    /// how many times slower slow motion runs
+    pub slow_motion: u32,
+    pub frontend: FrontendKind,
+    pub palette: Palette,
+    pub quirks: Quirks,
+    pub keymap: KeymapConfig,
+    pub gamepad: GamepadConfig,
+    pub audio: AudioConfig,
+    /// where SCHIP's RPL flags are kept between runs, a file per ROM
+    pub save_dir: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            scale: 16,
+            instructions_per_frame: 10,
+            timing: Timing::Fixed,
+            variant: Variant::Chip8,
+            machine: MachineConfig::default(),
+            font: FontConfig::default(),
+            fast_forward: 4,
+            slow_motion: 4,
+            frontend: FrontendKind::Window,
+            palette: Palette::default(),
+            quirks: Quirks::default(),
+            keymap: KeymapConfig::default(),
+            gamepad: GamepadConfig::default(),
+            audio: AudioConfig::default(),
+            save_dir: PathBuf::from("saves"),
+        }
+    }
+}
+
+impl Settings {
+    pub fn window_scale(&self) -> io::Result<Scale> {
+        scale(self.scale)
+    }
+
+    // the scale is for a 64 pixel wide screen, wider ones get a smaller one so the
+    // window comes out about as wide
+    pub fn window_scale_for(&self, width: usize) -> io::Result<Scale> {
+        scale((self.scale as usize * DISPLAY_WIDTH / width).max(1) as u32)
+    }
+
+    // the keymap layout with the configured overrides applied
+    pub fn keymap(&self) -> io::Result<Keymap> {
+        self.keymap.build().map_err(invalid)
+    }
+
+    // the variant's memory map with the configured changes, keeping the top of memory
+    // free for the VIP interpreter's state when 0nnn runs machine code
+    pub fn machine(&self) -> io::Result<Machine> {
+        let mut machine = self.machine.resolve(self.variant)?;
+        if self.quirks.machine_code {
+            machine.reserved = machine.reserved.max(STACK_FROM_TOP);
+        }
+        Ok(machine)
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        self.window_scale()?;
+        self.keymap()?;
+        self.machine()?;
+        self.gamepad.mapping()?;
+        if self.instructions_per_frame == 0 {
+            return Err(invalid("instructions_per_frame must be at least 1"));
+        }
+        if self.fast_forward == 0 || self.slow_motion == 0 {
+            return Err(invalid("fast_forward and slow_motion must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+// a parsed config file: global defaults plus `[rom."<path or sha1>"]` sections
+#[derive(Debug, Default)]
+pub struct Config {
+    global: toml::Table,
+    roms: BTreeMap<String, toml::Table>,
+    base_dir: PathBuf,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Self::parse(&contents, base_dir).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    // parse config text, relative ROM paths are resolved against `base_dir`
+    pub fn parse(contents: &str, base_dir: &Path) -> io::Result<Config> {
+        let mut global: toml::Table = contents.parse().map_err(invalid)?;
+
+        let mut roms = BTreeMap::new();
+        match global.remove("rom") {
+            Some(toml::Value::Table(sections)) => {
+                for (key, section) in sections {
+                    match section {
+                        toml::Value::Table(table) => {
+                            roms.insert(key, table);
+                        }
+                        _ => return Err(invalid(format!("[rom.{:?}] must be a table", key))),
+                    }
+                }
+            }
+            Some(_) => return Err(invalid("`rom` must be a table of per-ROM sections")),
+            None => {}
+        }
+
+        let config = Config {
+            global,
+            roms,
+            base_dir: base_dir.to_path_buf(),
+        };
+
+        // catch mistakes up front rather than when a matching ROM is loaded
+        config.resolve(Vec::new())?;
+        for (key, section) in &config.roms {
+            config
+                .resolve(vec![section])
+                .map_err(|e| invalid(format!("[rom.{:?}]: {}", key, e)))?;
+        }
+
+        Ok(config)
+    }
+
+    // settings for a ROM: global values, then sections matching its path, then its hash
+    pub fn settings_for(&self, rom_path: &Path, rom_data: &[u8]) -> io::Result<Settings> {
+        let hash = rom_hash(rom_data);
+        let rom_path = rom_path.canonicalize().ok();
+
+        let mut by_path = Vec::new();
+        let mut by_hash = Vec::new();
+        for (key, section) in &self.roms {
+            if key.eq_ignore_ascii_case(&hash) {
+                by_hash.push(section);
+            } else if rom_path.is_some() && self.base_dir.join(key).canonicalize().ok() == rom_path
+            {
+                by_path.push(section);
+            }
+        }
+
+        by_path.extend(by_hash);
+        self.resolve(by_path)
+    }
+
+    fn resolve(&self, sections: Vec<&toml::Table>) -> io::Result<Settings> {
+        let mut merged = self.global.clone();
+        for section in sections {
+            merge(&mut merged, section);
+        }
+
+        let settings = Settings::deserialize(toml::Value::Table(merged)).map_err(invalid)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+// SHA-1 of the ROM image as lowercase hex, the same key the CHIP-8 community database uses
+pub fn rom_hash(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+// recursively overlay `over` onto `base`, so a section only replaces the values it names
+fn merge(base: &mut toml::Table, over: &toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+pub fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn scale(n: u32) -> io::Result<Scale> {
+    match n {
+        1 => Ok(Scale::X1),
+        2 => Ok(Scale::X2),
+        4 => Ok(Scale::X4),
+        8 => Ok(Scale::X8),
+        16 => Ok(Scale::X16),
+        32 => Ok(Scale::X32),
+        n => Err(invalid(format!(
+            "unsupported scale {}, expected 1, 2, 4, 8, 16 or 32",
+            n
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::Color;
+    use minifb::Key;
+
+    #[test]
+    fn test_defaults_without_config() {
+        let settings = Config::default()
+            .settings_for(Path::new("missing.ch8"), &[])
+            .unwrap();
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn test_rom_section_by_hash_overrides_global() {
+        let rom = [0x12, 0x00];
+        let config = format!(
+            "
+            instructions_per_frame = 20
+
+            [palette]
+            foreground = \"#33FF66\"
+
+            [quirks]
+            vf_reset = true
+
+            [rom.{}]
+            instructions_per_frame = 7
+
+            [rom.{}.quirks]
+            clip_sprites = true
+            ",
+            rom_hash(&rom),
+            rom_hash(&rom)
+        );
+
+        let config = Config::parse(&config, Path::new(".")).unwrap();
+        let settings = config.settings_for(Path::new("game.ch8"), &rom).unwrap();
+        assert_eq!(settings.instructions_per_frame, 7);
+        assert_eq!(settings.palette.foreground, Color(0x33FF66));
+        assert!(settings.quirks.vf_reset);
+        assert!(settings.quirks.clip_sprites);
+
+        let other = config.settings_for(Path::new("game.ch8"), &[0x00]).unwrap();
+        assert_eq!(other.instructions_per_frame, 20);
+        assert!(!other.quirks.clip_sprites);
+    }
+
+    #[test]
+    fn test_rom_section_by_path() {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = "
+            [keymap]
+            layout = \"azerty\"
+
+            [rom.\"coffee.ch8\"]
+            scale = 8
+
+            [rom.\"coffee.ch8\".keymap]
+            5 = [\"Up\", \"Space\"]
+        ";
+
+        let config = Config::parse(config, base_dir).unwrap();
+        let settings = config
+            .settings_for(&base_dir.join("coffee.ch8"), &[])
+            .unwrap();
+        assert!(matches!(settings.window_scale().unwrap(), Scale::X8));
+        let keymap = settings.keymap().unwrap();
+        assert_eq!(keymap.host_keys(0x5), &[Key::Up, Key::Space]);
+        assert_eq!(keymap.host_keys(0x4), &[Key::A]);
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        assert!(Config::parse("scale = 3", Path::new(".")).is_err());
+        assert!(Config::parse("speed = 10", Path::new(".")).is_err());
+        assert!(Config::parse("[keymap]\n5 = \"NoSuchKey\"", Path::new(".")).is_err());
+        assert!(Config::parse("[keymap]\nlayout = \"colemak\"", Path::new(".")).is_err());
+        assert!(Config::parse("[palette]\nforeground = \"green\"", Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn test_machine_code_reserves_the_top() {
+        let mut settings = Settings::default();
+        assert!(settings.machine().unwrap().check_rom(0xE00).is_ok());
+        settings.quirks.machine_code = true;
+        let machine = settings.machine().unwrap();
+        assert_eq!(machine.reserved, 0x131);
+        assert!(machine.check_rom(0xE00 - 0x131).is_ok());
+        assert!(machine.check_rom(0xE00 - 0x130).is_err());
+    }
+}
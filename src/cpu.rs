@@ -1,18 +1,146 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
 use std::sync::Arc;
+use std::time::Instant;
+
+use crate::{
+    display::Display,
+    font::{Font, BIG_GLYPH, SMALL_FONT_SIZE, SMALL_GLYPH},
+    instruction::Instruction,
+    keyboard::Keyboard,
+    machine::{Machine, DEFAULT_STACK_DEPTH},
+    megachip::{self, Sample},
+    memory::Memory,
+    trace::{Snapshot, TraceRecord, Tracer},
+    variant::{self, Extended, Variant},
+    vip,
+};
+
+/// Behaviours that differ between CHIP-8 interpreters. The defaults match what
+/// this emulator has always done; ROMs written for other interpreters can flip them.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quirks {
+    /// 8xy1/8xy2/8xy3 reset VF to 0, as on the COSMAC VIP
+    pub vf_reset: bool,
+    /// Fx55/Fx65 leave I pointing one past the last register touched
+    pub memory_increment: bool,
+    /// 8xy6/8xyE shift Vx in place instead of shifting Vy into Vx
+    pub shift_in_place: bool,
+    /// Bnnn jumps to nnn + Vx (x being the high nibble of nnn) instead of nnn + V0
+    pub jump_with_vx: bool,
+    /// sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    /// 0nnn runs the 1802 machine code subroutine at nnn, as on the COSMAC VIP
+    pub machine_code: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            vf_reset: false,
+            memory_increment: false,
+            shift_in_place: true,
+            jump_with_vx: false,
+            clip_sprites: false,
+            machine_code: false,
+        }
+    }
+}
 
-use crate::{display::Display, keyboard::Keyboard};
+impl Quirks {
+    pub const NAMES: [&'static str; 6] = [
+        "vf_reset",
+        "memory_increment",
+        "shift_in_place",
+        "jump_with_vx",
+        "clip_sprites",
+        "machine_code",
+    ];
+
+    // set a quirk by name, returns false if there is no such quirk
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let quirk = match name {
+            "vf_reset" => &mut self.vf_reset,
+            "memory_increment" => &mut self.memory_increment,
+            "shift_in_place" => &mut self.shift_in_place,
+            "jump_with_vx" => &mut self.jump_with_vx,
+            "clip_sprites" => &mut self.clip_sprites,
+            "machine_code" => &mut self.machine_code,
+            _ => return false,
+        };
+        *quirk = enabled;
+        true
+    }
+}
+
+/// What an instruction says about where the program is going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    Running,
+    /// waiting on a timer or a key, nothing changes until one does
+    Idle,
+    /// a jump to itself, the usual way a CHIP-8 program ends
+    Halted,
+    /// SCHIP's 00FD, or CHIP-8E's 00ED
+    Exited,
+}
 
+// the longest idle loop looked for, in instructions
+const IDLE_LOOP_LEN: usize = 8;
+
+// instructions an idle loop is made of. Registers are only set from constants,
+// other registers, the delay timer or a skip on a key, so another time around
+// the loop does the same until a timer or key changes
+fn waits(instruction: Instruction) -> bool {
+    use crate::instruction::Instruction::*;
+    matches!(
+        instruction,
+        LdVxDt(_)
+            | LdByte(..)
+            | LdReg(..)
+            | SeByte(..)
+            | SneByte(..)
+            | SeReg(..)
+            | SneReg(..)
+            | Skp(_)
+            | Sknp(_)
+    )
+}
+
+/// Progress of an Fx0A key wait. Like the original interpreter, the wait only
+/// completes once a key that went down during the wait comes back up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyWait {
+    pub since: Instant,
+    pub pressed: Option<u8>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub registers: [u8; 16],    // 16 general-purpose 8-bit registers
-    pub i_register: u16,        // 16-bit I register
+    pub i_register: u32,        // I register, 24 bits wide for MegaChip
     pub delay_timer: u8,        // delay timer register
     pub sound_timer: u8,        // sound timer register
     pub program_counter: usize, // program counter (aka location in memory)
-    pub heap: [u8; 4096],       // 4KB heap
-    pub stack: [u16; 16],       // 16-entry stack
+    pub heap: Memory,           // 4KB heap, behind the memory bus
+    pub stack: Vec<u16>,        // as many entries as the machine's stack depth
     pub stack_pointer: usize,   // stack pointer
     pub keyboard: Arc<Keyboard>,
     pub display: Display,
+    pub quirks: Quirks,
+    pub key_wait: Option<KeyWait>, // set while Fx0A is waiting for a key
+    pub cycles: u64,               // instructions executed
+    pub frames: u64,               // 60 Hz frames run, counted by `update_timers`
+    pub rng: StdRng,               // source of RND bytes, seeded for reproducible runs
+    pub tracer: Option<Tracer>,    // logs executed instructions when set
+    pub keypad2: Arc<Keyboard>,    // CHIP-8X's second keypad
+    pub timer_wait: bool,          // set while CHIP-8E's Fx4F waits on the delay timer
+    pub sample: Option<Sample>,    // MegaChip's digitized sound while it plays
+    pub rpl: [u8; 16],             // RPL user flags, kept on disk between runs
+    pub font: Font,                // glyphs for Fx29 and Fx30
+    pub font_address: usize,       // where the font is loaded
 }
 
 impl CPU {
@@ -22,105 +150,253 @@ impl CPU {
             i_register: 0,
             delay_timer: 0,
             sound_timer: 0,
-            heap: [0; 4096],
+            heap: Memory::new(),
             program_counter: 0x200,
-            stack: [0; 16],
+            stack: vec![0; DEFAULT_STACK_DEPTH],
             stack_pointer: 0,
-            keyboard: keyboard.clone(),
-            display: Display::new(keyboard),
+            keyboard,
+            display: Display::new(),
+            quirks: Quirks::default(),
+            key_wait: None,
+            cycles: 0,
+            frames: 0,
+            rng: StdRng::from_entropy(),
+            tracer: None,
+            keypad2: Arc::new(Keyboard::new()),
+            timer_wait: false,
+            sample: None,
+            rpl: [0; 16],
+            font: Font::default(),
+            font_address: 0,
         };
 
-        // Load built-in hex sprites into interpreter memory area (0x000-0x1FF)
-        let hex_sprites: [u8; 80] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-        ];
-
-        // Load sprites into interpreter memory area (0x000-0x1FF)
-        for (i, &byte) in hex_sprites.iter().enumerate() {
-            cpu.heap[i] = byte;
-        }
+        // built-in hex sprites in the interpreter memory area (0x000-0x1FF)
+        cpu.load_font(Font::default(), 0);
 
         cpu
     }
 
-    pub fn tick(&mut self) {
-        let op_byte1 = self.heap[self.program_counter] as u16;
-        let op_byte2 = self.heap[self.program_counter + 1] as u16;
-        let opcode: u16 = op_byte1 << 8 | op_byte2;
+    // log every instruction from here on, memory writes are journaled for it
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.heap.record_writes(true);
+        self.tracer = Some(tracer);
+    }
 
-        let x = ((opcode & 0x0F00) >> 8) as u8;
-        let y = ((opcode & 0x00F0) >> 4) as u8;
-        let n = (opcode & 0x000F) as u8;
-        let kk = (opcode & 0x00FF) as u8;
-        let op_minor = (opcode & 0x000F) as u8;
-        let addr = opcode & 0x0FFF;
+    pub fn stop_trace(&mut self) -> Option<Tracer> {
+        self.heap.record_writes(false);
+        self.tracer.take()
+    }
 
-        // println!("opcode: {:04x}", opcode);
-        // println!("heap: {:?}", self.heap);
+    pub fn tick(&mut self) -> StepOutcome {
+        if self.tracer.is_some() {
+            return self.tick_traced();
+        }
+        let pc = self.program_counter;
+        self.heap.fetch(pc);
+        let instruction = self.step(pc);
+        self.outcome_of(pc, instruction)
+    }
+
+    // `tick` with the changes the instruction makes going to the tracer
+    fn tick_traced(&mut self) -> StepOutcome {
+        let pc = self.program_counter;
+        let opcode = self.heap.fetch(pc);
+
+        let traced = self
+            .tracer
+            .as_ref()
+            .is_some_and(|tracer| tracer.wants(pc, opcode, self.frames));
+        let before = if traced {
+            Some(Snapshot::of(self))
+        } else {
+            None
+        };
 
+        let instruction = self.step(pc);
+
+        // the journal is drained even for filtered out instructions so it doesn't grow
+        let writes = self.heap.take_writes();
+        if let Some(before) = before {
+            let record = TraceRecord {
+                cycle: self.cycles - 1,
+                frame: self.frames,
+                pc: pc as u32,
+                opcode,
+                changes: before.changes(&Snapshot::of(self), &writes),
+                variant: self.heap.variant(),
+            };
+            if let Some(tracer) = &mut self.tracer {
+                tracer.record(&record);
+            }
+        }
+        self.outcome_of(pc, instruction)
+    }
+
+    // execute the instruction at `pc`, decoded through the heap's cache
+    fn step(&mut self, pc: usize) -> Instruction {
         self.program_counter += 2;
+        let instruction = match self.heap.decode(pc) {
+            Ok(instruction) => instruction,
+            Err(e) => panic!("{}", e),
+        };
+        self.execute(instruction);
+        self.cycles += 1;
+        instruction
+    }
+
+    // what having run `instruction` at `pc` says about the program
+    fn outcome_of(&self, pc: usize, instruction: Instruction) -> StepOutcome {
+        match instruction {
+            Instruction::Jp(addr) if addr as usize == pc => StepOutcome::Halted,
+            Instruction::Extended(Extended::Exit | Extended::Stop) => StepOutcome::Exited,
+            _ if self.key_wait.is_some() || self.timer_wait => StepOutcome::Idle,
+            Instruction::Jp(addr) if (addr as usize) < pc && self.idle_loop(addr as usize, pc) => {
+                StepOutcome::Idle
+            }
+            _ => StepOutcome::Running,
+        }
+    }
+
+    /// What the program is doing, judged from where the PC is. For backends that
+    /// don't run an instruction at a time, `tick` reports the same as it goes.
+    pub fn outcome(&self) -> StepOutcome {
+        let pc = self.program_counter;
+        match self.peek(pc) {
+            Some(Instruction::Jp(addr)) if addr as usize == pc => return StepOutcome::Halted,
+            Some(Instruction::Extended(Extended::Exit | Extended::Stop)) => {
+                return StepOutcome::Exited
+            }
+            _ if self.key_wait.is_some() || self.timer_wait => return StepOutcome::Idle,
+            _ => (),
+        }
+        // the PC may be anywhere in an idle loop, look ahead for the jump back
+        for end in (pc..pc + 2 * IDLE_LOOP_LEN).step_by(2) {
+            match self.peek(end) {
+                Some(Instruction::Jp(addr))
+                    if addr as usize <= pc && self.idle_loop(addr as usize, end) =>
+                {
+                    return StepOutcome::Idle
+                }
+                Some(instruction) if waits(instruction) => (),
+                _ => break,
+            }
+        }
+        StepOutcome::Running
+    }
 
-        // println!("program_counter: {}", self.program_counter);
-
-        match opcode {
-            0x0000 => return,                    // Shut down the entire process
-            0x00E0 => self.cls(),                // CLS - Clear the Display
-            0x00EE => self.ret(),                // Return from a subroutine
-            0x0000..=0x0FFF => self.sys(addr),   // SYS addr
-            0x1000..=0x1FFF => self.jmp(addr),   // Jump to location nnn
-            0x2000..=0x2FFF => self.call(addr),  // Call subroutine at nnn
-            0x3000..=0x3FFF => self.se(x, kk),   // Skip next instruction if Vx == kk
-            0x4000..=0x4FFF => self.sne(x, kk),  // Skip next instruction if Vx != kk
-            0x5000..=0x5FF0 => self.se_xy(x, y), // Skip next instruction if Vx == Vy
-            0x6000..=0x6FFF => self.ld(x, kk),   // LD Vx, byte
-            0x7000..=0x7FFF => self.add(x, kk),  // ADD Vx, byte
-            0x8000..=0x8FFF => match op_minor {
-                0 => self.ld(x, self.registers[y as usize]), // LD Vx, Vy
-                1 => self.or_xy(x, y),                       // OR Vx, Vy
-                2 => self.and_xy(x, y),                      // AND Vx, Vy
-                3 => self.xor_xy(x, y),                      // XOR Vx, Vy
-                4 => self.add_xy(x, y),                      // ADD Vx, Vy
-                5 => self.sub_xy(x, y),                      // SUB Vx, Vy
-                6 => self.shr_xy(x),                         // SHR Vx
-                7 => self.subn_xy(x, y),                     // SUBN Vx, Vy
-                0xE => self.shl_xy(x),                       // SHL Vx
-                _ => panic!("invalid opcode: {:04x}", opcode),
-            },
-            0x9000..=0x9FF0 => self.sne(x, y), // Skip next instruction if Vx != Vy
-            0xA000..=0xAFFF => self.ld_i(addr), // LD I, addr
-            0xB000..=0xBFFF => self.jmp(addr + self.registers[0] as u16), // JP V0, addr
-            0xC000..=0xCFFF => self.rnd(x, kk), // RND Vx, byte
-            0xD000..=0xDFFF => self.drw(x, y, n), // DRW Vx, Vy, nibble
-            op if (op & 0xF0FF) == 0xE09E => self.skp(x), // SKP Vx
-            op if (op & 0xF0FF) == 0xE0A1 => self.sknp(x), // SKNP Vx
-            op if (op & 0xF0FF) == 0xF007 => self.ld_vx(x), // LD Vx, DT
-            op if (op & 0xF0FF) == 0xF00A => self.ld_k(x), // LD Vx, K
-            op if (op & 0xF0FF) == 0xF015 => self.ld_dt(x), // LD DT, Vx
-            op if (op & 0xF0FF) == 0xF018 => self.ld_st(x), // LD ST, Vx
-            op if (op & 0xF0FF) == 0xF01E => self.add_i(x), // ADD I, Vx
-            op if (op & 0xF0FF) == 0xF029 => self.ld_f(x), // LD F, Vx
-            op if (op & 0xF0FF) == 0xF033 => self.ld_b(x), // LD B, Vx
-            op if (op & 0xF0FF) == 0xF055 => self.ld_i_vx(x), // LD [I], Vx
-            op if (op & 0xF0FF) == 0xF065 => self.ld_vx_i(x), // LD Vx, [I]
-            _ => panic!("invalid opcode: {:04x}", opcode),
-        }
-
-        // Update timers
+    // whether the loop from `start` to the jump back at `end` only waits: it reads
+    // the delay timer and keys and compares, and nothing it does changes anything
+    // the next time around
+    fn idle_loop(&self, start: usize, end: usize) -> bool {
+        end - start < 2 * IDLE_LOOP_LEN
+            && (start..end)
+                .step_by(2)
+                .all(|addr| self.peek(addr).is_some_and(waits))
+    }
+
+    // the instruction at `addr`, without going through the memory bus
+    fn peek(&self, addr: usize) -> Option<Instruction> {
+        if addr + 1 >= self.heap.len() {
+            return None;
+        }
+        let opcode = (self.heap[addr] as u16) << 8 | self.heap[addr + 1] as u16;
+        self.heap.variant().decode(opcode).ok()
+    }
+
+    /// run one decoded instruction, the program counter already points past it
+    pub fn execute(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Cls => self.cls(),
+            Instruction::Ret => self.ret(),
+            Instruction::Sys(addr) => self.sys(addr),
+            Instruction::Jp(addr) => self.jmp(addr),
+            Instruction::Call(addr) => self.call(addr),
+            Instruction::SeByte(x, kk) => self.se(x, kk),
+            Instruction::SneByte(x, kk) => self.sne(x, kk),
+            Instruction::SeReg(x, y) => self.se_xy(x, y),
+            Instruction::LdByte(x, kk) => self.ld(x, kk),
+            Instruction::AddByte(x, kk) => self.add(x, kk),
+            Instruction::LdReg(x, y) => self.ld(x, self.registers[y as usize]),
+            Instruction::Or(x, y) => self.or_xy(x, y),
+            Instruction::And(x, y) => self.and_xy(x, y),
+            Instruction::Xor(x, y) => self.xor_xy(x, y),
+            Instruction::AddReg(x, y) => self.add_xy(x, y),
+            Instruction::Sub(x, y) => self.sub_xy(x, y),
+            Instruction::Shr(x, y) => self.shr_xy(x, y),
+            Instruction::Subn(x, y) => self.subn_xy(x, y),
+            Instruction::Shl(x, y) => self.shl_xy(x, y),
+            Instruction::SneReg(x, y) => self.sne_xy(x, y),
+            Instruction::LdI(addr) => self.ld_i(addr),
+            Instruction::JpV0(addr) => self.jmp_v0((addr >> 8) as u8, addr),
+            Instruction::Rnd(x, kk) => self.rnd(x, kk),
+            Instruction::Drw(x, y, n) => self.drw(x, y, n),
+            Instruction::Skp(x) => self.skp(x),
+            Instruction::Sknp(x) => self.sknp(x),
+            Instruction::LdVxDt(x) => self.ld_vx(x),
+            Instruction::LdVxK(x) => self.ld_k(x),
+            Instruction::LdDtVx(x) => self.ld_dt(x),
+            Instruction::LdStVx(x) => self.ld_st(x),
+            Instruction::AddI(x) => self.add_i(x),
+            Instruction::LdF(x) => self.ld_f(x),
+            Instruction::LdB(x) => self.ld_b(x),
+            Instruction::LdIVx(x) => self.ld_i_vx(x),
+            Instruction::LdVxI(x) => self.ld_vx_i(x),
+            Instruction::Extended(extended) => variant::execute(self, extended),
+        }
+    }
+
+    /// Run programs written for `variant`: decode its instructions and give it the
+    /// screen and memory it expects. Loading the program at the right address is up
+    /// to the caller.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.heap.set_variant(variant);
+        self.heap.resize(variant.memory_size());
+        self.display = variant.display();
+    }
+
+    // the memory size and stack depth of `machine`, after the variant is set
+    pub fn set_machine(&mut self, machine: &Machine) {
+        self.heap.resize(machine.memory_size);
+        self.stack.resize(machine.stack_depth, 0);
+    }
+
+    /// Start `rom` over on `machine` in place. Registers, timers, the stack, the screen
+    /// and memory are cleared, then the font and the ROM are loaded again. The variant,
+    /// quirks, font, RND, RPL flags, keypads and whatever a debugger attached (the
+    /// tracer and watchpoints) stay as they are.
+    pub fn reset(&mut self, machine: &Machine, rom: &[u8]) {
+        let variant = self.heap.variant();
+        self.registers = [0; 16];
+        self.i_register = 0;
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.stack.fill(0);
+        self.stack_pointer = 0;
+        self.key_wait = None;
+        self.timer_wait = false;
+        self.sample = None;
+        self.cycles = 0;
+        self.frames = 0;
+        self.display = variant.display();
+        self.set_machine(machine);
+        self.heap.clear();
+        self.heap.load(self.font_address, &self.font.bytes());
+        self.heap.load(machine.load_address, rom);
+        self.program_counter = machine.entry(variant, rom);
+    }
+
+    // put `font` at `address` for Fx29 and Fx30, clearing where the last one was
+    pub fn load_font(&mut self, font: Font, address: usize) {
+        self.heap
+            .load(self.font_address, &vec![0; self.font.size()]);
+        self.heap.load(address, &font.bytes());
+        self.font = font;
+        self.font_address = address;
+    }
+
+    /// count both timers down by one, called once per 60 Hz frame
+    pub fn update_timers(&mut self) {
+        self.frames += 1;
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -131,8 +407,11 @@ impl CPU {
 
     /// (0nnn) SYS addr
     /// This instruction is only used on the old computers on which Chip-8 was originally implemented. It is ignored by modern interpreters.
-    fn sys(&mut self, _addr: u16) {
-        // this instruction is ignored by modern interpreters
+    /// With the `machine_code` quirk it runs the 1802 subroutine at addr, as the VIP interpreter did.
+    fn sys(&mut self, addr: u16) {
+        if self.quirks.machine_code {
+            vip::call_machine_code(self, addr);
+        }
     }
 
     // 0x00E0: Clear the display
@@ -147,12 +426,12 @@ impl CPU {
 
     /// (Axnn) LD I, addr
     fn ld_i(&mut self, addr: u16) {
-        self.i_register = addr;
+        self.i_register = addr as u32;
     }
 
     /// (7xkk) Add sets the value `kk` into register `vx`
     fn add(&mut self, vx: u8, kk: u8) {
-        self.registers[vx as usize] += kk;
+        self.registers[vx as usize] = self.registers[vx as usize].wrapping_add(kk);
     }
 
     /// (3xkk) Skip if equal
@@ -174,16 +453,26 @@ impl CPU {
         self.program_counter = addr as usize;
     }
 
+    /// (Bnnn) JP V0, addr
+    /// jump to `addr` + V0, or `addr` + Vx with the `jump_with_vx` quirk
+    fn jmp_v0(&mut self, x: u8, addr: u16) {
+        let offset = if self.quirks.jump_with_vx {
+            self.registers[x as usize]
+        } else {
+            self.registers[0]
+        };
+        self.jmp(addr + offset as u16);
+    }
+
     /// (2nnn) CALL sub-routine at `addr`
     fn call(&mut self, addr: u16) {
         let sp = self.stack_pointer;
-        let stack = &mut self.stack;
 
-        if sp >= stack.len() {
+        if sp >= self.stack.len() {
             panic!("Stack overflow!")
         }
 
-        stack[sp] = self.program_counter as u16;
+        self.stack[sp] = self.program_counter as u16;
         self.stack_pointer += 1;
         self.program_counter = addr as usize;
     }
@@ -221,6 +510,9 @@ impl CPU {
         let y_ = self.registers[y as usize];
 
         self.registers[x as usize] = x_ & y_;
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     /// (8xy1) OR Vx, Vy
@@ -230,6 +522,9 @@ impl CPU {
         let y_ = self.registers[y as usize];
 
         self.registers[x as usize] = x_ | y_;
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     /// (8xy3) XOR Vx, Vy
@@ -239,6 +534,9 @@ impl CPU {
         let y_ = self.registers[y as usize];
 
         self.registers[x as usize] = x_ ^ y_;
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     /// (8xy5) SUB Vx, Vy
@@ -253,14 +551,17 @@ impl CPU {
             // clear the borrow flag
             self.registers[0xF] = 0;
         }
-        self.registers[x as usize] = x_val - y_val;
+        self.registers[x as usize] = x_val.wrapping_sub(y_val);
     }
 
     /// (8xy6) SHR Vx {, Vy}
     /// set Vx = Vx SHR 1
     /// if the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0
     /// then Vx is divided by 2
-    fn shr_xy(&mut self, x: u8) {
+    fn shr_xy(&mut self, x: u8, y: u8) {
+        if !self.quirks.shift_in_place {
+            self.registers[x as usize] = self.registers[y as usize];
+        }
         // set VF to 1 if the least significant bit is 1
         if self.registers[x as usize] & 0x1 == 1 {
             self.registers[0xF] = 1;
@@ -281,14 +582,17 @@ impl CPU {
         } else {
             self.registers[0xF] = 0;
         }
-        self.registers[x as usize] = y_val - x_val;
+        self.registers[x as usize] = y_val.wrapping_sub(x_val);
     }
 
     /// (8xyE) SHL Vx {, Vy}
     /// set Vx = Vx SHL 1
     /// if the most-significant bit of Vx is 1, then VF is set to 1, otherwise 0
     /// then Vx is multiplied by 2
-    fn shl_xy(&mut self, x: u8) {
+    fn shl_xy(&mut self, x: u8, y: u8) {
+        if !self.quirks.shift_in_place {
+            self.registers[x as usize] = self.registers[y as usize];
+        }
         // set VF to 1 if the most significant bit is 1
         if self.registers[x as usize] & 0x80 == 0x80 {
             self.registers[0xF] = 1;
@@ -302,17 +606,29 @@ impl CPU {
     /// (Cxkk) RND Vx, byte
     /// set Vx = random byte AND kk
     fn rnd(&mut self, x: u8, kk: u8) {
-        self.registers[x as usize] = rand::random::<u8>() & kk;
+        self.registers[x as usize] = self.rng.gen::<u8>() & kk;
     }
 
     /// (Dxyn) DRW Vx, Vy, nibble
     /// display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision
     fn drw(&mut self, x: u8, y: u8, n: u8) {
+        // MegaChip sprites have their own size and a color per pixel
+        if self.display.indexed().is_some() {
+            return megachip::draw(self, x, y);
+        }
         let x_coord = self.registers[x as usize];
         let y_coord = self.registers[y as usize];
-        let sprite = &self.heap[self.i_register as usize..(self.i_register + n as u16) as usize];
+        let mut sprite = [0; 15];
+        for (row, byte) in sprite.iter_mut().enumerate().take(n as usize) {
+            *byte = self.heap.read(self.i_register as usize + row);
+        }
 
-        let collision = self.display.draw(x_coord, y_coord, sprite);
+        let collision = self.display.draw(
+            x_coord,
+            y_coord,
+            &sprite[..n as usize],
+            self.quirks.clip_sprites,
+        );
         self.registers[0xF] = if collision { 1 } else { 0 };
     }
 
@@ -342,13 +658,34 @@ impl CPU {
 
     /// (Fx0A) LD Vx, K
     /// wait for a key press, store the value of the key in Vx
+    /// the key is the first one pressed after the wait began, and the instruction
+    /// completes when that key is released
     fn ld_k(&mut self, vx: u8) {
-        if let Some(key) = self.keyboard.wait_for_key_press() {
-            self.registers[vx as usize] = key;
-        } else {
-            // if no key is pressed, decrease PC to repeat this instruction
-            self.program_counter -= 2;
+        let mut wait = *self.key_wait.get_or_insert(KeyWait {
+            since: Instant::now(),
+            pressed: None,
+        });
+
+        for event in self.keyboard.take_events() {
+            // keys already down when the wait started don't count
+            if event.time < wait.since {
+                continue;
+            }
+
+            match wait.pressed {
+                None if event.pressed => wait.pressed = Some(event.key),
+                Some(key) if key == event.key && !event.pressed => {
+                    self.registers[vx as usize] = key;
+                    self.key_wait = None;
+                    return;
+                }
+                _ => {}
+            }
         }
+
+        // no key released yet, decrease PC to repeat this instruction
+        self.key_wait = Some(wait);
+        self.program_counter -= 2;
     }
 
     /// (Fx15) LD DT, Vx
@@ -366,14 +703,22 @@ impl CPU {
     /// (Fx1E) ADD I, Vx
     /// I is added to Vx
     fn add_i(&mut self, vx: u8) {
-        self.i_register += self.registers[vx as usize] as u16;
+        self.i_register += self.registers[vx as usize] as u32;
     }
 
     /// (Fx29) LD F, Vx
     /// I is set to the location of the sprite for digit Vx
     fn ld_f(&mut self, vx: u8) {
-        let digit = self.registers[vx as usize] & 0xF; // Ensure we only use the lowest 4 bits
-        self.i_register = (digit * 5) as u16; // Each sprite is 5 bytes tall
+        let digit = (self.registers[vx as usize] & 0xF) as usize; // Ensure we only use the lowest 4 bits
+        self.i_register = (self.font_address + digit * SMALL_GLYPH) as u32;
+    }
+
+    /// (Fx30) LD HF, Vx
+    /// I is set to the location of the big sprite for digit Vx. SCHIP only has 0 to 9, and
+    /// like SCHIP, A to F point past them at whatever follows the font
+    pub(crate) fn ld_hf(&mut self, vx: u8) {
+        let digit = (self.registers[vx as usize] & 0xF) as usize;
+        self.i_register = (self.font_address + SMALL_FONT_SIZE + digit * BIG_GLYPH) as u32;
     }
 
     /// (Fx33) LD B, Vx
@@ -385,16 +730,22 @@ impl CPU {
         let tens = (vx_value % 100) / 10;
         let ones = vx_value % 10;
 
-        self.heap[self.i_register as usize] = hundreds;
-        self.heap[self.i_register as usize + 1] = tens;
-        self.heap[self.i_register as usize + 2] = ones;
+        self.heap.write(self.i_register as usize, hundreds);
+        self.heap.write(self.i_register as usize + 1, tens);
+        self.heap.write(self.i_register as usize + 2, ones);
     }
 
     /// (Fx55) LD [I], Vx
     /// The interpreter copies the values of registers V0 through Vx into memory, starting at location I.
     fn ld_i_vx(&mut self, vx: u8) {
         for i in 0..=vx {
-            self.heap[self.i_register as usize + i as usize] = self.registers[i as usize];
+            self.heap.write(
+                self.i_register as usize + i as usize,
+                self.registers[i as usize],
+            );
+        }
+        if self.quirks.memory_increment {
+            self.i_register += vx as u32 + 1;
         }
     }
 
@@ -402,7 +753,10 @@ impl CPU {
     /// The interpreter reads values from memory starting at location I into registers V0 through Vx.
     fn ld_vx_i(&mut self, vx: u8) {
         for i in 0..=vx {
-            self.registers[i as usize] = self.heap[self.i_register as usize + i as usize];
+            self.registers[i as usize] = self.heap.read(self.i_register as usize + i as usize);
+        }
+        if self.quirks.memory_increment {
+            self.i_register += vx as u32 + 1;
         }
     }
 
@@ -412,4 +766,174 @@ impl CPU {
             self.program_counter += 2;
         }
     }
+
+    /// (9xy0) Skip if registers not equal
+    fn sne_xy(&mut self, x: u8, y: u8) {
+        if self.registers[x as usize] != self.registers[y as usize] {
+            self.program_counter += 2;
+        }
+    }
+}
+
+// a CPU with `program` loaded at 0x200, for tests
+#[cfg(test)]
+pub(crate) fn cpu_with_program(program: &[u8]) -> CPU {
+    let mut cpu = CPU::new(Arc::new(Keyboard::new()));
+    cpu.heap.load(0x200, program);
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ld_k_completes_on_release_of_first_key() {
+        // LD V3, K
+        let mut cpu = cpu_with_program(&[0xF3, 0x0A]);
+        let keyboard = cpu.keyboard.clone();
+
+        // held before the wait started, so its release is ignored
+        keyboard.set_key(0x1, true);
+        cpu.tick();
+        keyboard.set_key(0x1, false);
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x200);
+
+        keyboard.set_key(0x7, true);
+        keyboard.set_key(0x2, true);
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x200);
+
+        // releasing the second key doesn't finish the wait
+        keyboard.set_key(0x2, false);
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x200);
+
+        keyboard.set_key(0x7, false);
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x202);
+        assert_eq!(cpu.registers[3], 0x7);
+        assert_eq!(cpu.key_wait, None);
+    }
+
+    #[test]
+    fn test_sne_xy_compares_registers() {
+        // LD V1, 5; LD V2, 5; SNE V1, V2; LD V3, 1; SNE V1, V3
+        let mut cpu =
+            cpu_with_program(&[0x61, 0x05, 0x62, 0x05, 0x91, 0x20, 0x63, 0x01, 0x91, 0x30]);
+        // V1 is compared with V2, not with the register number 2
+        for _ in 0..3 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.program_counter, 0x206);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x20C);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps_around() {
+        let mut cpu = cpu_with_program(&[
+            0x60, 0xF0, // LD V0, 0xF0
+            0x70, 0x20, // ADD V0, 0x20
+            0x61, 0x30, // LD V1, 0x30
+            0x81, 0x05, // SUB V1, V0
+            0x62, 0x40, // LD V2, 0x40
+            0x82, 0x17, // SUBN V2, V1
+        ]);
+        for _ in 0..6 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.registers[..3], [0x10, 0x20, 0xE0]);
+        assert_eq!(cpu.registers[0xF], 0);
+    }
+
+    #[test]
+    fn test_halt_exit_and_idle_outcomes() {
+        let mut cpu = cpu_with_program(&[
+            0x6A, 0x02, // 200: LD VA, 2
+            0xFA, 0x15, // 202: LD DT, VA
+            0xF0, 0x07, // 204: LD V0, DT
+            0x30, 0x00, // 206: SE V0, 0
+            0x12, 0x04, // 208: JP 0x204
+            0x70, 0x01, // 20A: ADD V0, 1
+            0x30, 0x03, // 20C: SE V0, 3
+            0x12, 0x0A, // 20E: JP 0x20A
+            0x12, 0x10, // 210: JP 0x210
+        ]);
+        for _ in 0..4 {
+            assert_eq!(cpu.tick(), StepOutcome::Running);
+        }
+        // waiting for the delay timer
+        assert_eq!(cpu.tick(), StepOutcome::Idle);
+        assert_eq!(cpu.outcome(), StepOutcome::Idle);
+        cpu.delay_timer = 0;
+        for _ in 0..3 {
+            cpu.tick();
+        }
+        // a loop that counts isn't idle
+        assert_eq!(cpu.outcome(), StepOutcome::Running);
+        let outcomes: Vec<StepOutcome> = (0..9).map(|_| cpu.tick()).collect();
+        assert!(!outcomes.contains(&StepOutcome::Idle));
+        assert_eq!(cpu.program_counter, 0x210);
+        assert_eq!(cpu.outcome(), StepOutcome::Halted);
+        assert_eq!(cpu.tick(), StepOutcome::Halted);
+        assert_eq!(cpu.program_counter, 0x210);
+
+        let mut cpu = cpu_with_program(&[0x00, 0xFD]);
+        cpu.set_variant(Variant::Schip);
+        assert_eq!(cpu.tick(), StepOutcome::Exited);
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn test_reset_in_place() {
+        // LD V0, 7; LD I, 0x300; LD B, V0; CALL 0x208; 0x208: LD R, V0
+        let program = [0x60, 0x07, 0xA3, 0x00, 0xF0, 0x33, 0x22, 0x08, 0xF0, 0x75];
+        let machine = Machine::for_variant(Variant::Schip);
+        let mut cpu = cpu_with_program(&[]);
+        cpu.set_variant(Variant::Schip);
+        cpu.quirks.vf_reset = true;
+        cpu.reset(&machine, &program);
+        for _ in 0..5 {
+            cpu.tick();
+        }
+        assert_eq!((cpu.heap[0x302], cpu.stack_pointer, cpu.rpl[0]), (7, 1, 7));
+
+        cpu.reset(&machine, &program[..2]);
+        assert_eq!(cpu.registers, [0; 16]);
+        assert_eq!((cpu.i_register, cpu.stack_pointer, cpu.cycles), (0, 0, 0));
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(&cpu.heap[0x200..0x204], &[0x60, 0x07, 0, 0]);
+        assert_eq!(cpu.heap[0x302], 0);
+        assert_eq!(cpu.heap[0x05], cpu.font.small[5]);
+        // what outlives a run stays
+        assert_eq!(cpu.rpl[0], 7);
+        assert!(cpu.quirks.vf_reset);
+        assert_eq!(cpu.heap.variant(), Variant::Schip);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack overflow!")]
+    fn test_stack_depth() {
+        // CALL 0x200, forever
+        let mut cpu = cpu_with_program(&[0x22, 0x00]);
+        cpu.set_machine(&Machine {
+            stack_depth: 40,
+            ..Machine::for_variant(Variant::Chip8)
+        });
+        for _ in 0..40 {
+            cpu.tick();
+        }
+        assert_eq!((cpu.stack_pointer, cpu.stack[39]), (40, 0x202));
+        cpu.tick();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid opcode: 5121")]
+    fn test_5xy1_is_invalid() {
+        let mut cpu = cpu_with_program(&[0x51, 0x21]);
+        cpu.tick();
+    }
 }
@@ -0,0 +1,605 @@
+use crate::cpu::CPU;
+use crate::debugger::{set_poll_mode, DebugServer, Debugger, StopReason};
+use crate::machine::Machine;
+use crate::rom_loader::{RomLoader, SourceMap};
+use serde_json::{json, Value};
+use std::io::{self, ErrorKind, Read, Write};
+use std::iter;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+
+// the CPU is the only thread
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const STACK_REFERENCE: u64 = 2;
+
+// Debug Adapter Protocol server on localhost, for editors that debug over a socket
+pub struct DapServer {
+    debugger: Debugger,
+    finished: bool,
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    input: Vec<u8>,
+    seq: u64,
+    // where the ROM is loaded, source map offsets are relative to it
+    machine: Machine,
+    source_map: Option<SourceMap>,
+    stop_on_entry: bool,
+}
+
+impl DapServer {
+    pub fn bind(port: u16, machine: Machine, source_map: Option<SourceMap>) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        Ok(DapServer {
+            debugger: Debugger::new(),
+            finished: false,
+            listener,
+            stream: None,
+            input: Vec::new(),
+            seq: 0,
+            machine,
+            source_map,
+            stop_on_entry: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // block until a client connects, the CPU stays halted until it is configured
+    pub fn wait_for_client(&mut self) -> io::Result<()> {
+        self.listener.set_nonblocking(false)?;
+        let (stream, _) = self.listener.accept()?;
+        self.listener.set_nonblocking(true)?;
+        self.attach(stream)
+    }
+
+    fn attach(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        self.stream = Some(stream);
+        self.input.clear();
+        Ok(())
+    }
+
+    // the program keeps running on its own once the client is gone
+    fn detach(&mut self, cpu: &mut CPU) {
+        self.stream = None;
+        self.debugger.breakpoints.clear();
+        cpu.heap.watchpoints.clear();
+        self.debugger.resume(cpu);
+    }
+
+    // messages are a `Content-Length` header, a blank line and a JSON body
+    fn process(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        while let Some(end) = self.input.windows(4).position(|w| w == b"\r\n\r\n") {
+            let header = String::from_utf8_lossy(&self.input[..end]).into_owned();
+            let length = header
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length:"))
+                .and_then(|length| length.trim().parse::<usize>().ok());
+            let length = match length {
+                Some(length) => length,
+                None => {
+                    println!("dap: message without Content-Length, disconnecting");
+                    self.detach(cpu);
+                    return Ok(());
+                }
+            };
+
+            let start = end + 4;
+            if self.input.len() < start + length {
+                // wait for the rest of the body
+                return Ok(());
+            }
+            let body: Vec<u8> = self.input.drain(..start + length).skip(start).collect();
+            match serde_json::from_slice::<Value>(&body) {
+                Ok(request) if request["type"] == "request" => self.handle(&request, cpu)?,
+                Ok(_) => (),
+                Err(e) => println!("dap: ignoring malformed message: {}", e),
+            }
+            if self.stream.is_none() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: &Value, cpu: &mut CPU) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" | "attach" => self.launch(args, cpu),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                if !self.stop_on_entry {
+                    self.debugger.resume(cpu);
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace(cpu)),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Call Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                ]
+            })),
+            "variables" => Ok(variables(args["variablesReference"].as_u64(), cpu)),
+            "continue" => {
+                self.debugger.resume(cpu);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.debugger.step_over(cpu);
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                self.debugger.step(cpu);
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                self.debugger.step_out(cpu);
+                Ok(Value::Null)
+            }
+            "pause" => {
+                self.debugger.halt();
+                Ok(Value::Null)
+            }
+            "terminate" => {
+                self.finished = true;
+                Ok(Value::Null)
+            }
+            "disconnect" => {
+                // a launched program ends with the session unless asked otherwise
+                if args["terminateDebuggee"].as_bool().unwrap_or(true) {
+                    self.finished = true;
+                }
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request {:?}", command)),
+        };
+
+        self.respond(request, result)?;
+
+        // events that have to follow their response
+        match command {
+            "initialize" => self.event("initialized", Value::Null),
+            "configurationDone" if self.stop_on_entry => self.stopped("entry"),
+            "pause" => self.stopped("pause"),
+            "disconnect" if !self.finished => {
+                self.detach(cpu);
+                Ok(())
+            }
+            "disconnect" | "terminate" => {
+                self.event("terminated", Value::Null)?;
+                self.stream = None;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // optionally loads `program` in place of the ROM given on the command line
+    fn launch(&mut self, args: &Value, cpu: &mut CPU) -> Result<Value, String> {
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+
+        if let Some(program) = args["program"].as_str() {
+            let rom = RomLoader::load(Path::new(program))
+                .map_err(|e| format!("error loading {}: {}", program, e))?;
+            self.machine
+                .check_rom(rom.data.len())
+                .map_err(|e| format!("error loading {}: {}", program, e))?;
+
+            cpu.reset(&self.machine, &rom.data);
+            self.source_map = rom.source_map;
+        }
+
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let source_map = self
+            .source_map
+            .as_ref()
+            .filter(|map| map.is_for(Path::new(path)));
+
+        // a ROM has a single source file, so its breakpoints replace all of them
+        self.debugger.breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+            match source_map.and_then(|map| map.offset_of(line)) {
+                Some((offset, line)) => {
+                    self.debugger
+                        .breakpoints
+                        .insert(self.machine.load_address + offset);
+                    breakpoints.push(json!({ "verified": true, "line": line }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": match source_map {
+                        Some(_) => "no code at or after this line",
+                        None => "not the assembly source of the running ROM",
+                    },
+                })),
+            }
+        }
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // the current instruction, then the call site of each return address, innermost first
+    fn stack_trace(&self, cpu: &CPU) -> Value {
+        let depth = cpu.stack_pointer.min(cpu.stack.len());
+        let addresses: Vec<usize> = iter::once(cpu.program_counter)
+            .chain(
+                cpu.stack[..depth]
+                    .iter()
+                    .rev()
+                    .map(|&ret| (ret as usize).saturating_sub(2)),
+            )
+            .collect();
+
+        let frames: Vec<Value> = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, &addr)| {
+                // a frame is named after the subroutine its caller called
+                let name = match addresses.get(id + 1) {
+                    Some(&call) => format!("sub_{:03X}", call_target(cpu, call)),
+                    None => "main".to_string(),
+                };
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:03X}", addr),
+                });
+                if let Some((file, line)) = self.location(addr) {
+                    frame["source"] = json!({ "path": file });
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect();
+
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn location(&self, addr: usize) -> Option<(String, usize)> {
+        let map = self.source_map.as_ref()?;
+        let line = map.line_at(addr.checked_sub(self.machine.load_address)?)?;
+        Some((map.file.display().to_string(), line))
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => (),
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        if let Some(stream) = &mut self.stream {
+            stream.set_nonblocking(false)?;
+            write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        }
+        Ok(())
+    }
+}
+
+impl DebugServer for DapServer {
+    fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    fn poll(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        if self.stream.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => self.attach(stream)?,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let halted = self.debugger.is_halted();
+        let stream = self.stream.as_mut().unwrap();
+        set_poll_mode(stream, halted)?;
+
+        let mut buffer = [0; 4096];
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                self.detach(cpu);
+                return Ok(());
+            }
+            Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(())
+            }
+            Err(e) => {
+                println!("dap connection lost: {}", e);
+                self.detach(cpu);
+                return Ok(());
+            }
+        }
+
+        self.process(cpu)
+    }
+
+    fn run(&mut self, cpu: &mut CPU, budget: u32) -> io::Result<()> {
+        match self.debugger.run(cpu, budget) {
+            Some(StopReason::Breakpoint(_)) => self.stopped("breakpoint"),
+            Some(StopReason::Watchpoint(..)) => self.stopped("data breakpoint"),
+            Some(StopReason::Step) => self.stopped("step"),
+            Some(StopReason::Interrupted) => self.stopped("pause"),
+            None => Ok(()),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+// the subroutine a `2nnn` at `addr` calls
+fn call_target(cpu: &CPU, addr: usize) -> u16 {
+    match cpu.heap.get(addr..addr + 2) {
+        Some(bytes) => ((bytes[0] as u16) << 8 | bytes[1] as u16) & 0x0FFF,
+        None => 0,
+    }
+}
+
+fn variables(reference: Option<u64>, cpu: &CPU) -> Value {
+    let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+    let variables: Vec<Value> = match reference {
+        Some(REGISTERS_REFERENCE) => (0..16)
+            .map(|n| variable(format!("V{:X}", n), format!("0x{:02X}", cpu.registers[n])))
+            .chain(vec![
+                variable("I".to_string(), format!("0x{:03X}", cpu.i_register)),
+                variable("DT".to_string(), cpu.delay_timer.to_string()),
+                variable("ST".to_string(), cpu.sound_timer.to_string()),
+                variable("PC".to_string(), format!("0x{:03X}", cpu.program_counter)),
+                variable("SP".to_string(), cpu.stack_pointer.to_string()),
+            ])
+            .collect(),
+        // return addresses, innermost first like the stack trace
+        Some(STACK_REFERENCE) => {
+            let depth = cpu.stack_pointer.min(cpu.stack.len());
+            (0..depth)
+                .rev()
+                .map(|n| variable(format!("[{}]", n), format!("0x{:03X}", cpu.stack[n])))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    json!({ "variables": variables })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+    use crate::variant::Variant;
+    use std::fs;
+    use std::thread;
+
+    // a minimal client that keeps events aside until they are asked for
+    struct Client {
+        stream: TcpStream,
+        seq: u64,
+        events: Vec<Value>,
+    }
+
+    impl Client {
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let body = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+            write!(
+                self.stream,
+                "Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+
+            loop {
+                let message = self.read();
+                if message["type"] == "response" {
+                    assert_eq!(message["request_seq"], self.seq);
+                    assert_eq!(message["success"], true, "{}", message);
+                    return message["body"].clone();
+                }
+                self.events.push(message);
+            }
+        }
+
+        fn event(&mut self, name: &str) -> Value {
+            if let Some(i) = self.events.iter().position(|e| e["event"] == name) {
+                return self.events.remove(i)["body"].clone();
+            }
+            loop {
+                let message = self.read();
+                if message["event"] == name {
+                    return message["body"].clone();
+                }
+                self.events.push(message);
+            }
+        }
+
+        fn read(&mut self) -> Value {
+            let mut header = Vec::new();
+            let mut byte = [0];
+            while !header.ends_with(b"\r\n\r\n") {
+                self.stream.read_exact(&mut byte).unwrap();
+                header.push(byte[0]);
+            }
+            let length: usize = String::from_utf8(header).unwrap()["Content-Length: ".len()..]
+                .trim()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            self.stream.read_exact(&mut body).unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        // line of the innermost frame, and the frame count
+        fn position(&mut self) -> (u64, usize) {
+            let trace = self.request("stackTrace", json!({ "threadId": THREAD_ID }));
+            let frames = trace["stackFrames"].as_array().unwrap();
+            (frames[0]["line"].as_u64().unwrap(), frames.len())
+        }
+
+        fn register(&mut self, name: &str) -> String {
+            let variables = self.request(
+                "variables",
+                json!({ "variablesReference": REGISTERS_REFERENCE }),
+            );
+            variables["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|v| v["name"] == name)
+                .unwrap()["value"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let source = std::env::temp_dir().join(format!("chip8-dap-{}.asm", std::process::id()));
+        fs::write(
+            &source,
+            "; dap test program\n\
+             6007        ; LD V0, 7\n\
+             2206        ; CALL 0x206\n\
+             1204        ; JP 0x204\n\
+             ; subroutine\n\
+             7001        ; ADD V0, 1\n\
+             00EE        ; RET\n",
+        )
+        .unwrap();
+        let path = source.display().to_string();
+
+        let mut cpu = cpu_with_program(&[]);
+        let mut server = DapServer::bind(0, Machine::for_variant(Variant::Chip8), None).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(addr).unwrap(),
+                seq: 0,
+                events: Vec::new(),
+            };
+            let capabilities = client.request("initialize", json!({ "adapterID": "chip8" }));
+            assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+            client.event("initialized");
+
+            client.request("launch", json!({ "program": path, "stopOnEntry": true }));
+            let breakpoints = client.request(
+                "setBreakpoints",
+                json!({ "source": { "path": path }, "breakpoints": [{ "line": 5 }, { "line": 9 }] }),
+            );
+            // line 5 is a comment, the breakpoint moves to the ADD below it
+            assert_eq!(
+                breakpoints["breakpoints"],
+                json!([{ "verified": true, "line": 6 }, {
+                    "verified": false,
+                    "line": 9,
+                    "message": "no code at or after this line",
+                }])
+            );
+
+            client.request("configurationDone", Value::Null);
+            assert_eq!(client.event("stopped")["reason"], "entry");
+            assert_eq!(client.position(), (2, 1));
+
+            client.request("continue", json!({ "threadId": THREAD_ID }));
+            assert_eq!(client.event("stopped")["reason"], "breakpoint");
+            let trace = client.request("stackTrace", json!({ "threadId": THREAD_ID }));
+            let frames = trace["stackFrames"].as_array().unwrap();
+            assert_eq!(frames.len(), 2);
+            assert_eq!(
+                (&frames[0]["name"], &frames[0]["line"]),
+                (&json!("sub_206"), &json!(6))
+            );
+            assert_eq!(
+                (&frames[1]["name"], &frames[1]["line"]),
+                (&json!("main"), &json!(3))
+            );
+            assert_eq!(client.register("V0"), "0x07");
+
+            let stack = client.request(
+                "variables",
+                json!({ "variablesReference": STACK_REFERENCE }),
+            );
+            assert_eq!(stack["variables"][0]["value"], "0x204");
+
+            client.request("next", json!({ "threadId": THREAD_ID }));
+            assert_eq!(client.event("stopped")["reason"], "step");
+            assert_eq!(client.position(), (7, 2));
+
+            client.request("stepOut", json!({ "threadId": THREAD_ID }));
+            assert_eq!(client.event("stopped")["reason"], "step");
+            assert_eq!(client.position(), (4, 1));
+            assert_eq!(client.register("V0"), "0x08");
+
+            client.request("disconnect", json!({}));
+        });
+
+        server.wait_for_client().unwrap();
+        while !server.is_finished() && !client.is_finished() {
+            server.poll(&mut cpu).unwrap();
+            server.run(&mut cpu, 10).unwrap();
+        }
+        client.join().unwrap();
+        fs::remove_file(source).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use crate::cpu::CPU;
+use crate::instruction::{decode, Instruction};
+use crate::memory::WatchHit;
+use std::collections::BTreeSet;
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+// how long a poll waits for the client while the CPU is halted, keeps headless runs from spinning
+const HALTED_POLL: Duration = Duration::from_millis(5);
+
+// a debugger protocol server that drives the main loop instead of `CPU::tick`
+pub trait DebugServer {
+    fn debugger(&self) -> &Debugger;
+
+    // handle whatever the client has sent, called once per host frame
+    fn poll(&mut self, cpu: &mut CPU) -> io::Result<()>;
+
+    // run up to `budget` instructions unless halted, telling the client why execution stopped
+    fn run(&mut self, cpu: &mut CPU, budget: u32) -> io::Result<()>;
+
+    // the client asked for the emulator to exit
+    fn is_finished(&self) -> bool;
+}
+
+// reads on `stream` return straight away while running, while halted they wait a little
+// for the client instead
+pub fn set_poll_mode(stream: &TcpStream, halted: bool) -> io::Result<()> {
+    stream.set_nonblocking(!halted)?;
+    if halted {
+        stream.set_read_timeout(Some(HALTED_POLL))?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Breakpoint(usize),
+    // watchpoints live on the memory bus, see `Memory::watchpoints`
+    Watchpoint(WatchHit),
+    Step,
+    Interrupted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunState {
+    Halted,
+    Running,
+    // run a single instruction, then halt
+    Stepping,
+    // run until the call stack is shallower than this, for stepping over and out of calls
+    SteppingOut(usize),
+}
+
+// run control shared by the debugger front ends, wraps `CPU::tick`
+pub struct Debugger {
+    pub breakpoints: BTreeSet<usize>,
+    pub state: RunState,
+    // a breakpoint or execute watchpoint we are resuming from must not fire again straight away
+    resume_from: Option<usize>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    // starts halted so a client can set breakpoints before anything runs
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            state: RunState::Halted,
+            resume_from: None,
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.state == RunState::Halted
+    }
+
+    pub fn resume(&mut self, cpu: &CPU) {
+        self.resume_from = Some(cpu.program_counter);
+        self.state = RunState::Running;
+    }
+
+    pub fn step(&mut self, cpu: &CPU) {
+        self.resume_from = Some(cpu.program_counter);
+        self.state = RunState::Stepping;
+    }
+
+    // like `step`, but runs a whole subroutine call as one step
+    pub fn step_over(&mut self, cpu: &CPU) {
+        let opcode = cpu
+            .heap
+            .get(cpu.program_counter..cpu.program_counter + 2)
+            .map_or(0, |bytes| (bytes[0] as u16) << 8 | bytes[1] as u16);
+        self.resume_from = Some(cpu.program_counter);
+        self.state = if let Ok(Instruction::Call(_)) = decode(opcode) {
+            RunState::SteppingOut(cpu.stack_pointer + 1)
+        } else {
+            RunState::Stepping
+        };
+    }
+
+    // run until the current subroutine returns
+    pub fn step_out(&mut self, cpu: &CPU) {
+        self.resume_from = Some(cpu.program_counter);
+        self.state = RunState::SteppingOut(cpu.stack_pointer);
+    }
+
+    pub fn halt(&mut self) {
+        self.state = RunState::Halted;
+    }
+
+    // execute up to `budget` instructions, returns why execution halted if it did
+    pub fn run(&mut self, cpu: &mut CPU, budget: u32) -> Option<StopReason> {
+        for _ in 0..budget {
+            if self.is_halted() {
+                return None;
+            }
+
+            let pc = cpu.program_counter;
+            if self.resume_from.take() != Some(pc) {
+                if self.breakpoints.contains(&pc) {
+                    self.halt();
+                    return Some(StopReason::Breakpoint(pc));
+                }
+                if let Some(hit) = cpu.heap.execute_hit(pc) {
+                    self.halt();
+                    return Some(StopReason::Watchpoint(hit));
+                }
+            }
+
+            // reads and writes fire while the instruction runs, it completes before we halt
+            cpu.heap.take_hits();
+            cpu.tick();
+            if let Some(&hit) = cpu.heap.take_hits().first() {
+                self.halt();
+                return Some(StopReason::Watchpoint(hit));
+            }
+
+            let stepped = match self.state {
+                RunState::Stepping => true,
+                RunState::SteppingOut(depth) => cpu.stack_pointer < depth,
+                _ => false,
+            };
+            if stepped {
+                self.halt();
+                return Some(StopReason::Step);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+    use crate::memory::{Access, WatchKind, Watchpoint};
+
+    #[test]
+    fn test_breakpoint_and_resume() {
+        // LD V0, 1; ADD V0, 1; JP 0x202
+        let mut cpu = cpu_with_program(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.insert(0x202);
+
+        assert_eq!(debugger.run(&mut cpu, 10), None);
+        debugger.resume(&cpu);
+        assert_eq!(
+            debugger.run(&mut cpu, 10),
+            Some(StopReason::Breakpoint(0x202))
+        );
+        assert_eq!(cpu.registers[0], 1);
+
+        // resuming executes the instruction under the breakpoint before it can fire again
+        debugger.resume(&cpu);
+        assert_eq!(
+            debugger.run(&mut cpu, 10),
+            Some(StopReason::Breakpoint(0x202))
+        );
+        assert_eq!(cpu.registers[0], 2);
+
+        debugger.step(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 10), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        // CALL 0x206; JP 0x200; ... 0x206: ADD V0, 1; RET
+        let mut cpu =
+            cpu_with_program(&[0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x70, 0x01, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+
+        debugger.step_over(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 10), Some(StopReason::Step));
+        assert_eq!((cpu.program_counter, cpu.registers[0]), (0x202, 1));
+
+        // stepping over a jump is a plain step
+        debugger.step_over(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 10), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter, 0x200);
+
+        // step into the call, then out of it
+        debugger.step(&cpu);
+        debugger.run(&mut cpu, 10);
+        assert_eq!(cpu.program_counter, 0x206);
+        debugger.step_out(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 10), Some(StopReason::Step));
+        assert_eq!((cpu.program_counter, cpu.stack_pointer), (0x202, 0));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // LD V0, 7; LD I, 0x300; LD B, V0; JP 0x206
+        let mut cpu = cpu_with_program(&[0x60, 0x07, 0xA3, 0x00, 0xF0, 0x33, 0x12, 0x06]);
+        let mut debugger = Debugger::new();
+        // LD B writes 0, 0, 7, only the 7 counts
+        cpu.heap.watchpoints.push(Watchpoint {
+            kind: WatchKind::Write,
+            addr: 0x300,
+            len: 3,
+            value: Some(7),
+        });
+        cpu.heap.watchpoints.push(Watchpoint {
+            kind: WatchKind::Execute,
+            addr: 0x206,
+            len: 2,
+            value: None,
+        });
+
+        debugger.resume(&cpu);
+        match debugger.run(&mut cpu, 10) {
+            Some(StopReason::Watchpoint(hit)) => {
+                assert_eq!(
+                    (hit.access, hit.addr, hit.pc),
+                    (Access::Write, 0x302, 0x204)
+                )
+            }
+            reason => panic!("unexpected stop {:?}", reason),
+        }
+        assert_eq!(cpu.program_counter, 0x206);
+
+        // execute watchpoints stop before the instruction, and let it run on resume
+        debugger.resume(&cpu);
+        debugger.run(&mut cpu, 10);
+        debugger.resume(&cpu);
+        match debugger.run(&mut cpu, 10) {
+            Some(StopReason::Watchpoint(hit)) => assert_eq!(hit.access, Access::Execute),
+            reason => panic!("unexpected stop {:?}", reason),
+        }
+        assert_eq!(cpu.program_counter, 0x206);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::backend::Backend;
+use crate::config::invalid;
+use crate::cpu::CPU;
+use crate::disasm::disassemble;
+use crate::input::InputMux;
+use crate::instruction::Instruction;
+use crate::variant::Variant;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+
+// a piece of machine state two runs are compared on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Pc,
+    I,
+    V(u8),
+    Sp,
+    Stack(u16),
+    Dt,
+    St,
+    Memory(u32),
+}
+
+impl Field {
+    fn value(self, cpu: &CPU) -> u32 {
+        match self {
+            Field::Pc => cpu.program_counter as u32,
+            Field::I => cpu.i_register,
+            Field::V(n) => cpu.registers[n as usize] as u32,
+            Field::Sp => cpu.stack_pointer as u32,
+            Field::Stack(n) => cpu.stack[n as usize] as u32,
+            Field::Dt => cpu.delay_timer as u32,
+            Field::St => cpu.sound_timer as u32,
+            Field::Memory(addr) => cpu.heap[addr as usize] as u32,
+        }
+    }
+
+    // whether `cpu` has the stack slot or the byte of memory the field names
+    fn fits(self, cpu: &CPU) -> bool {
+        match self {
+            Field::Stack(n) => (n as usize) < cpu.stack.len(),
+            Field::Memory(addr) => (addr as usize) < cpu.heap.len(),
+            _ => true,
+        }
+    }
+
+    // `PC`, `V3`, `S2`, `M302` and so on, as used in reference traces
+    fn parse(key: &str) -> Option<Field> {
+        let index = |digits: &str| u32::from_str_radix(digits, 16).ok();
+        let field = match key {
+            "PC" => Field::Pc,
+            "I" => Field::I,
+            "SP" => Field::Sp,
+            "DT" => Field::Dt,
+            "ST" => Field::St,
+            _ if key.len() == 2 && key.starts_with('V') => Field::V(index(&key[1..])? as u8),
+            _ if key.starts_with('S') => Field::Stack(u16::try_from(index(&key[1..])?).ok()?),
+            _ if key.starts_with('M') => Field::Memory(index(&key[1..])?),
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::Pc => write!(f, "PC"),
+            Field::I => write!(f, "I"),
+            Field::V(n) => write!(f, "V{:X}", n),
+            Field::Sp => write!(f, "SP"),
+            Field::Stack(n) => write!(f, "S[{}]", n),
+            Field::Dt => write!(f, "DT"),
+            Field::St => write!(f, "ST"),
+            Field::Memory(addr) => write!(f, "M[{:03X}]", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Difference {
+    pub field: Field,
+    pub a: u32,
+    pub b: u32,
+}
+
+// the first instruction after which the runs disagreed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub cycle: u64,
+    pub frame: u64,
+    pub pc: u32,
+    pub opcode: u16,
+    // the instruction set the runs decode opcodes as
+    pub variant: Variant,
+    // instructions run between the last two comparisons, more than one when a JIT ran a block
+    pub count: u32,
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count > 1 {
+            writeln!(
+                f,
+                "runs diverge after cycles {}-{} (frame {}), in the {} instructions from {:03X}: {:04X} {}",
+                self.cycle,
+                self.cycle + self.count as u64 - 1,
+                self.frame,
+                self.count,
+                self.pc,
+                self.opcode,
+                disassemble(self.variant, self.opcode)
+            )?;
+        } else {
+            writeln!(
+                f,
+                "runs diverge after cycle {} (frame {}), at {:03X}: {:04X} {}",
+                self.cycle,
+                self.frame,
+                self.pc,
+                self.opcode,
+                disassemble(self.variant, self.opcode)
+            )?;
+        }
+        for difference in &self.differences {
+            writeln!(
+                f,
+                "  {:<7} a={:02X} b={:02X}",
+                difference.field.to_string(),
+                difference.a,
+                difference.b
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// every field where the two CPUs disagree
+pub fn compare(a: &CPU, b: &CPU) -> Vec<Difference> {
+    let mut fields = vec![Field::Pc, Field::I];
+    fields.extend((0..16).map(Field::V));
+    fields.extend([Field::Sp, Field::Dt, Field::St]);
+    let depth = a.stack.len().min(b.stack.len());
+    fields.extend((0..depth as u16).map(Field::Stack));
+
+    let mut differences: Vec<Difference> = fields
+        .into_iter()
+        .map(|field| Difference {
+            field,
+            a: field.value(a),
+            b: field.value(b),
+        })
+        .filter(|difference| difference.a != difference.b)
+        .collect();
+    for (addr, (&x, &y)) in a.heap.iter().zip(b.heap.iter()).enumerate() {
+        if x != y {
+            differences.push(Difference {
+                field: Field::Memory(addr as u32),
+                a: x as u32,
+                b: y as u32,
+            });
+        }
+    }
+    differences
+}
+
+fn opcode_at(cpu: &CPU, pc: usize) -> u16 {
+    cpu.heap
+        .get(pc..pc + 2)
+        .map_or(0, |bytes| (bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+// run two CPUs in lockstep for `frames` frames, each fed by its own copy of the inputs
+// and run by its own backend. A JIT runs whole blocks, so it leads and the other side
+// runs as many instructions, the runs are compared after each block
+pub fn run_pair(
+    a: &mut CPU,
+    b: &mut CPU,
+    backends: &mut [Backend; 2],
+    inputs: &mut [InputMux],
+    frames: u64,
+    ipf: u32,
+) -> Option<Divergence> {
+    for frame in 0..frames {
+        for input in inputs.iter_mut() {
+            input.poll();
+        }
+        let mut remaining = ipf;
+        while remaining > 0 {
+            let pc = a.program_counter;
+            let opcode = opcode_at(a, pc);
+            let cycle = a.cycles;
+            let count = if backends[1].is_jit() && !backends[0].is_jit() {
+                let count = backends[1].step(b, remaining);
+                backends[0].run(a, count);
+                count
+            } else {
+                let count = backends[0].step(a, remaining);
+                backends[1].run(b, count);
+                count
+            };
+            remaining -= count;
+
+            let differences = compare(a, b);
+            if !differences.is_empty() {
+                return Some(Divergence {
+                    cycle,
+                    frame,
+                    pc: pc as u32,
+                    opcode,
+                    variant: a.heap.variant(),
+                    count,
+                    differences,
+                });
+            }
+        }
+        a.update_timers();
+        b.update_timers();
+    }
+    None
+}
+
+// one instruction from another emulator's trace: where it ran and the state after it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReferenceStep {
+    pub pc: u32,
+    pub opcode: Option<u16>,
+    pub state: Vec<(Field, u32)>,
+}
+
+// reference traces have a line per instruction of hex `KEY=VALUE` pairs, in any order
+//   PC=200 OP=6103 V1=03 I=000 SP=0 S0=000 DT=00 ST=00 M300=07
+// PC and OP are the instruction that ran, everything else is the state after it
+// only the keys present are compared, `#` starts a comment
+pub fn parse_reference(contents: &str) -> io::Result<Vec<ReferenceStep>> {
+    let mut steps = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| invalid(format!("line {}: {}", number + 1, message));
+
+        let mut pc = None;
+        let mut opcode = None;
+        let mut state = Vec::new();
+        for pair in line.split_whitespace() {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected KEY=VALUE, got {:?}", pair)))?;
+            let value = u32::from_str_radix(value, 16)
+                .map_err(|_| error(format!("invalid hex value in {:?}", pair)))?;
+            match key.to_ascii_uppercase().as_str() {
+                "PC" => pc = Some(value),
+                "OP" => {
+                    let value = u16::try_from(value)
+                        .map_err(|_| error(format!("opcode too wide in {:?}", pair)))?;
+                    opcode = Some(value);
+                }
+                key => {
+                    let field =
+                        Field::parse(key).ok_or_else(|| error(format!("unknown key {:?}", key)))?;
+                    state.push((field, value));
+                }
+            }
+        }
+
+        steps.push(ReferenceStep {
+            pc: pc.ok_or_else(|| error("missing PC".to_string()))?,
+            opcode,
+            state,
+        });
+    }
+    Ok(steps)
+}
+
+// run the CPU along a reference trace, `a` is our CPU and `b` the reference
+// RND has no way to agree with another emulator, so its result is taken from the reference
+pub fn run_reference(
+    cpu: &mut CPU,
+    steps: &[ReferenceStep],
+    input: &mut InputMux,
+    ipf: u32,
+) -> io::Result<Option<Divergence>> {
+    let mut fields = steps.iter().flat_map(|step| &step.state);
+    if let Some((field, _)) = fields.find(|(field, _)| !field.fits(cpu)) {
+        return Err(invalid(format!(
+            "the reference compares {}, which this machine doesn't have",
+            field
+        )));
+    }
+    for (n, step) in steps.iter().enumerate() {
+        let frame = n as u64 / ipf as u64;
+        if (n as u64).is_multiple_of(ipf as u64) {
+            if n > 0 {
+                cpu.update_timers();
+            }
+            input.poll();
+        }
+
+        let pc = cpu.program_counter;
+        let opcode = opcode_at(cpu, pc);
+        let mut differences = Vec::new();
+        if pc as u32 != step.pc {
+            differences.push(Difference {
+                field: Field::Pc,
+                a: pc as u32,
+                b: step.pc,
+            });
+        }
+        if step.opcode.is_some_and(|expected| expected != opcode) {
+            differences.push(Difference {
+                field: Field::Memory(pc as u32),
+                a: opcode as u32,
+                b: step.opcode.unwrap() as u32,
+            });
+        }
+        if !differences.is_empty() {
+            // the state already disagreed before this instruction, blame the one before it
+            let previous = n.checked_sub(1).map(|n| &steps[n]);
+            return Ok(Some(Divergence {
+                cycle: cpu.cycles.saturating_sub(1),
+                frame,
+                pc: previous.map_or(step.pc, |step| step.pc),
+                opcode: previous.and_then(|step| step.opcode).unwrap_or(opcode),
+                variant: cpu.heap.variant(),
+                count: 1,
+                differences,
+            }));
+        }
+
+        cpu.tick();
+        if let Ok(Instruction::Rnd(x, _)) = cpu.heap.variant().decode(opcode) {
+            if let Some(&(_, value)) = step.state.iter().find(|(field, _)| *field == Field::V(x)) {
+                cpu.registers[x as usize] = value as u8;
+            }
+        }
+
+        let mut differences: Vec<Difference> = step
+            .state
+            .iter()
+            .map(|&(field, value)| Difference {
+                field,
+                a: field.value(cpu),
+                b: value,
+            })
+            .filter(|difference| difference.a != difference.b)
+            .collect();
+        if let Some(next) = steps.get(n + 1) {
+            if cpu.program_counter as u32 != next.pc {
+                differences.push(Difference {
+                    field: Field::Pc,
+                    a: cpu.program_counter as u32,
+                    b: next.pc,
+                });
+            }
+        }
+        if !differences.is_empty() {
+            return Ok(Some(Divergence {
+                cycle: cpu.cycles - 1,
+                frame,
+                pc: pc as u32,
+                opcode,
+                variant: cpu.heap.variant(),
+                count: 1,
+                differences,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+
+    fn machine(program: &[u8]) -> (CPU, InputMux) {
+        let cpu = cpu_with_program(program);
+        let input = InputMux::new(cpu.keyboard.clone());
+        (cpu, input)
+    }
+
+    #[test]
+    fn test_run_pair() {
+        // LD V1, 3; LD V2, 4; SHR V1, V2; JP 0x206
+        let program = [0x61, 0x03, 0x62, 0x04, 0x81, 0x26, 0x12, 0x06];
+        let (mut a, input_a) = machine(&program);
+        let (mut b, input_b) = machine(&program);
+        let mut inputs = [input_a, input_b];
+        let mut backends = [Backend::Interpreter, Backend::Interpreter];
+        assert_eq!(
+            run_pair(&mut a, &mut b, &mut backends, &mut inputs, 2, 10),
+            None
+        );
+
+        let (mut a, _) = machine(&program);
+        let (mut b, _) = machine(&program);
+        b.quirks.shift_in_place = false;
+        let divergence = run_pair(&mut a, &mut b, &mut backends, &mut inputs, 2, 10).unwrap();
+        assert_eq!(
+            (divergence.cycle, divergence.pc, divergence.opcode),
+            (2, 0x204, 0x8126)
+        );
+        assert_eq!(
+            divergence.differences,
+            vec![
+                Difference {
+                    field: Field::V(1),
+                    a: 1,
+                    b: 2
+                },
+                Difference {
+                    field: Field::V(0xF),
+                    a: 1,
+                    b: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_reference() {
+        // LD V1, 3; RND V2, 0xFF; LD I, 0x300; LD B, V1; JP 0x208
+        let program = [0x61, 0x03, 0xC2, 0xFF, 0xA3, 0x00, 0xF1, 0x33, 0x12, 0x08];
+        let reference = parse_reference(
+            "# from another emulator\n\
+             PC=200 OP=6103 V1=03\n\
+             pc=202 op=C2FF V2=5A\n\
+             PC=204 I=300\n\
+             PC=206 M302=03 V2=5A\n\
+             PC=208\n",
+        )
+        .unwrap();
+        assert_eq!(reference[3].state[0], (Field::Memory(0x302), 3));
+
+        let (mut cpu, mut input) = machine(&program);
+        assert_eq!(
+            run_reference(&mut cpu, &reference, &mut input, 10).unwrap(),
+            None
+        );
+
+        // the reference thinks LD B wrote 4
+        let mut wrong = reference.clone();
+        wrong[3].state[0] = (Field::Memory(0x302), 4);
+        let (mut cpu, mut input) = machine(&program);
+        let divergence = run_reference(&mut cpu, &wrong, &mut input, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!((divergence.pc, divergence.opcode), (0x206, 0xF133));
+
+        assert!(parse_reference("PC=200 X=1").is_err());
+        assert!(parse_reference("V0=1").is_err());
+
+        // a stack as deep as the machine's, no deeper
+        let deep = parse_reference("PC=200 S1F=000").unwrap();
+        let (mut cpu, mut input) = machine(&program);
+        assert!(run_reference(&mut cpu, &deep, &mut input, 10).is_err());
+        cpu.stack.resize(0x20, 0);
+        assert!(run_reference(&mut cpu, &deep, &mut input, 10).is_ok());
+
+        // and memory as large as the machine's, MegaChip's goes past 4K
+        let high = parse_reference("PC=200 M12345=00").unwrap();
+        let (mut cpu, mut input) = machine(&program);
+        assert!(run_reference(&mut cpu, &high, &mut input, 10).is_err());
+        cpu.heap.resize(0x100_0000);
+        assert!(run_reference(&mut cpu, &high, &mut input, 10).is_ok());
+    }
+}
@@ -0,0 +1,35 @@
+use crate::variant::Variant;
+
+// mnemonic for an opcode in `variant`'s instruction set, in the syntax of Cowgod's
+// technical reference, anything that isn't an instruction comes out as a `DW` data word
+pub fn disassemble(variant: Variant, opcode: u16) -> String {
+    match variant.decode(opcode) {
+        Ok(instruction) => instruction.to_string(),
+        Err(_) => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let chip8 = |opcode| disassemble(Variant::Chip8, opcode);
+        assert_eq!(chip8(0x00E0), "CLS");
+        assert_eq!(chip8(0x2208), "CALL 0x208");
+        assert_eq!(chip8(0x8AB6), "SHR VA, VB");
+        assert_eq!(chip8(0xD125), "DRW V1, V2, 5");
+        assert_eq!(chip8(0xF233), "LD B, V2");
+        assert_eq!(chip8(0x00FD), "SYS 0x0FD");
+        assert_eq!(chip8(0xF130), "DW 0xF130");
+        assert_eq!(chip8(0x5121), "DW 0x5121");
+        assert_eq!(chip8(0xE1FF), "DW 0xE1FF");
+
+        // each variant's own instructions, as its CPU decodes them
+        assert_eq!(disassemble(Variant::Schip, 0x00FD), "EXIT");
+        assert_eq!(disassemble(Variant::Schip, 0xF130), "LD HF, V1");
+        assert_eq!(disassemble(Variant::Chip8e, 0x5121), "SGT V1, V2");
+        assert_eq!(disassemble(Variant::Megachip, 0x0011), "MEGAON");
+    }
+}
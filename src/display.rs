@@ -1,173 +1,341 @@
-use crate::keyboard::Keyboard;
-use minifb::{Key, Window, WindowOptions};
-use std::{convert::TryInto, sync::Arc};
-
+// the logical screen, one bit per pixel
+// rows are stored as u64s with column 0 in the most significant bit, so drawing a
+// sprite row is a shift and an XOR, and colors are only applied by the frontend
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Display {
-    window: Window,
-    buffer: Vec<u32>,
+    rows: Vec<u64>,
     width: usize,
     height: usize,
-    keyboard: Arc<Keyboard>,
+    // set for variants with a color board
+    colors: Option<Colors>,
+    // set while MegaChip mode is on, the bits above are kept but not shown
+    indexed: Option<Box<Indexed>>,
 }
 
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 
+// the VP-590 color board colors each zone of 8x4 pixels
+pub const ZONE_WIDTH: usize = 8;
+pub const ZONE_HEIGHT: usize = 4;
+
+/// CHIP-8X's colors: one of four background colors, and a foreground color from 0
+/// to 7 for each zone. The bits of a foreground color are red, blue and green.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Colors {
+    pub background: u8,
+    columns: usize,
+    zones: Vec<u8>,
+}
+
+impl Colors {
+    // red on the first background color, as the CHIP-8X interpreter starts up
+    fn new(width: usize, height: usize) -> Self {
+        let columns = width / ZONE_WIDTH;
+        Colors {
+            background: 0,
+            columns,
+            zones: vec![1; columns * (height / ZONE_HEIGHT)],
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.zones.len() / self.columns
+    }
+
+    // foreground color of the pixel at (x, y)
+    pub fn at(&self, x: usize, y: usize) -> u8 {
+        self.zones[y / ZONE_HEIGHT * self.columns + x / ZONE_WIDTH]
+    }
+
+    // zones past the edges wrap around, as sprites do
+    pub fn set_zone(&mut self, column: usize, row: usize, color: u8) {
+        let index = row % self.rows() * self.columns + column % self.columns;
+        self.zones[index] = color & 0x7;
+    }
+}
+
+pub const MEGACHIP_WIDTH: usize = 256;
+pub const MEGACHIP_HEIGHT: usize = 192;
+
+// how a sprite pixel's color combines with the color under it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Blend {
+    #[default]
+    Normal,
+    // the sprite at 25%, 50% or 75% opacity
+    Quarter,
+    Half,
+    ThreeQuarters,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    // 080n, anything past 5 draws normally
+    pub fn from_mode(mode: u8) -> Self {
+        match mode {
+            1 => Blend::Quarter,
+            2 => Blend::Half,
+            3 => Blend::ThreeQuarters,
+            4 => Blend::Add,
+            5 => Blend::Multiply,
+            _ => Blend::Normal,
+        }
+    }
+
+    // blend 0xRRGGBB colors a channel at a time
+    pub fn mix(self, under: u32, over: u32) -> u32 {
+        let opacity = match self {
+            Blend::Quarter => 1,
+            Blend::Half => 2,
+            Blend::ThreeQuarters => 3,
+            _ => 4,
+        };
+        (0..3).fold(0, |color, channel| {
+            let shift = channel * 8;
+            let (a, b) = ((under >> shift) & 0xFF, (over >> shift) & 0xFF);
+            let mixed = match self {
+                Blend::Add => (a + b).min(0xFF),
+                Blend::Multiply => a * b / 0xFF,
+                _ => (a * (4 - opacity) + b * opacity) / 4,
+            };
+            color | mixed << shift
+        })
+    }
+}
+
+/// MegaChip's 256x192 screen, a palette index per pixel. Sprites are drawn to a back
+/// buffer, blended with the colors under them, and `Display::clear` shows it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Indexed {
+    /// 0xAARRGGBB colors, index 0 is transparent
+    pub palette: Vec<u32>,
+    /// sprite size in pixels, a byte each
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    pub blend: Blend,
+    /// drawing over a pixel of this index is a collision
+    pub collision: u8,
+    /// opacity of the whole screen, for fading it in and out
+    pub alpha: u8,
+    indices: Vec<u8>,
+    back: Vec<u32>,
+    front: Vec<u32>,
+}
+
+impl Indexed {
+    fn new() -> Self {
+        let pixels = MEGACHIP_WIDTH * MEGACHIP_HEIGHT;
+        Indexed {
+            palette: vec![0; 256],
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: Blend::Normal,
+            collision: 0,
+            alpha: 0xFF,
+            indices: vec![0; pixels],
+            back: vec![0; pixels],
+            front: vec![0; pixels],
+        }
+    }
+
+    // the 0xRRGGBB colors last shown
+    pub fn front(&self) -> &[u32] {
+        &self.front
+    }
+
+    // set the pixel at (x, y) to palette index `index`, pixels off the screen are
+    // dropped. Returns true if it drew over the collision index, the empty screen
+    // never collides
+    pub fn plot(&mut self, x: usize, y: usize, index: u8) -> bool {
+        if x >= MEGACHIP_WIDTH || y >= MEGACHIP_HEIGHT {
+            return false;
+        }
+        let pixel = y * MEGACHIP_WIDTH + x;
+        let under = self.indices[pixel];
+        let collision = under != 0 && under == self.collision;
+        self.indices[pixel] = index;
+        self.back[pixel] = self
+            .blend
+            .mix(self.back[pixel], self.palette[index as usize]);
+        collision
+    }
+
+    fn show(&mut self) {
+        self.front.copy_from_slice(&self.back);
+        self.back.fill(0);
+        self.indices.fill(0);
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
+
 impl Display {
-    pub fn new(keyboard: Arc<Keyboard>) -> Self {
-        let window = Window::new(
-            "CHIP-8 Emulator",
-            DISPLAY_WIDTH,
-            DISPLAY_HEIGHT,
-            WindowOptions {
-                scale: minifb::Scale::X16,
-                ..WindowOptions::default()
-            },
-        )
-        .expect("failed to create window");
-
-        let display = Display {
-            window,
-            buffer: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
-            width: DISPLAY_WIDTH,
-            height: DISPLAY_HEIGHT,
-            keyboard,
+    pub fn new() -> Self {
+        Display::with_size(DISPLAY_WIDTH, DISPLAY_HEIGHT)
+    }
+
+    // at most 64 pixels wide, a row is a u64
+    pub fn with_size(width: usize, height: usize) -> Self {
+        assert!(width <= 64);
+        Display {
+            rows: vec![0; height],
+            width,
+            height,
+            colors: None,
+            indexed: None,
+        }
+    }
+
+    // add a color board, for CHIP-8X
+    pub fn with_colors(mut self) -> Self {
+        self.colors = Some(Colors::new(self.width, self.height));
+        self
+    }
+
+    pub fn colors(&self) -> Option<&Colors> {
+        self.colors.as_ref()
+    }
+
+    pub fn colors_mut(&mut self) -> Option<&mut Colors> {
+        self.colors.as_mut()
+    }
+
+    // turn MegaChip mode on or off, turning it on starts from a blank screen
+    pub fn set_indexed(&mut self, on: bool) {
+        self.indexed = if on {
+            Some(Box::new(Indexed::new()))
+        } else {
+            None
         };
+    }
+
+    pub fn indexed(&self) -> Option<&Indexed> {
+        self.indexed.as_deref()
+    }
+
+    pub fn indexed_mut(&mut self) -> Option<&mut Indexed> {
+        self.indexed.as_deref_mut()
+    }
+
+    // size of the picture shown, in pixels
+    pub fn resolution(&self) -> (usize, usize) {
+        match self.indexed {
+            Some(_) => (MEGACHIP_WIDTH, MEGACHIP_HEIGHT),
+            None => (self.width, self.height),
+        }
+    }
 
-        // // Test pattern - draw a simple rectangle
-        // for i in 10..20 {
-        //     for j in 10..20 {
-        //         display.buffer[i * DISPLAY_WIDTH + j] = 0xFFFFFF;
-        //     }
-        // }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
 
-        display
+    // one u64 per row, column 0 in the most significant bit
+    pub fn rows(&self) -> &[u64] {
+        &self.rows
     }
 
-    // pub fn get_buffer(&self) -> &Vec<u32> {
-    //     &self.buffer
-    // }
+    pub fn rows_mut(&mut self) -> &mut [u64] {
+        &mut self.rows
+    }
 
-    // clear the display
+    // clear the display, the colors stay as they are
+    // in MegaChip mode what was drawn since the last clear is shown first
     pub fn clear(&mut self) {
-        self.buffer.fill(0);
+        match &mut self.indexed {
+            Some(indexed) => indexed.show(),
+            None => self.rows.fill(0),
+        }
     }
 
     // draw a sprite at position (x, y) with data from memory
-    pub fn draw(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
+    // when `clip` is set, pixels past the right/bottom edge are dropped instead of wrapping
+    pub fn draw(&mut self, x: u8, y: u8, sprite: &[u8], clip: bool) -> bool {
         let mut collision = false;
 
-        for (row, sprite_byte) in sprite.iter().enumerate() {
-            for bit in 0..8 {
-                let px = (x as usize + bit) % self.width;
-                let py = (y as usize + row) % self.height;
-                let pixel = (sprite_byte >> (7 - bit)) & 1;
-
-                if pixel == 1 {
-                    let index = py * self.width + px;
-                    if self.buffer[index] == 0xFFFFFF {
-                        collision = true;
-                    }
-                    self.buffer[index] ^= 0xFFFFFF;
-                }
+        // the starting coordinate always wraps
+        let x = x as u32 % self.width as u32;
+        let y = y as usize % self.height;
+
+        for (row, &sprite_byte) in sprite.iter().enumerate() {
+            if clip && y + row >= self.height {
+                break;
             }
+
+            // the sprite row lined up with column x, bits past the right edge either
+            // fall off or come back around on the left
+            let bits = (sprite_byte as u64) << 56;
+            let bits = if clip {
+                bits >> x
+            } else {
+                bits.rotate_right(x)
+            };
+
+            let line = &mut self.rows[(y + row) % self.height];
+            collision |= *line & bits != 0;
+            *line ^= bits;
         }
 
         collision
     }
+}
 
-    // update the window with current buffer contents
-    // pub fn update(&mut self) -> bool {
-    //     if !self.window.is_open() {
-    //         return false;
-    //     }
-
-    //     self.window
-    //         .update_with_buffer(&self.buffer, self.width, self.height)
-    //         .unwrap();
-
-    //     true
-    // }
-
-    // // get the current state of a key
-    // pub fn is_key_pressed(&self, key: Key) -> bool {
-    //     self.window.is_key_down(key)
-    // }
-
-    // pub fn handle_input(&mut self) -> bool {
-    //     if !self.window.is_open() {
-    //         return false;
-    //     }
-
-    //     // Get all pressed keys
-    //     self.window.get_keys().iter().for_each(|key| match key {
-    //         Key::Key1 => self.keyboard.set_key(0x1, true),
-    //         Key::Key2 => self.keyboard.set_key(0x2, true),
-    //         Key::Key3 => self.keyboard.set_key(0x3, true),
-    //         Key::Key4 => self.keyboard.set_key(0xC, true),
-    //         Key::Q => self.keyboard.set_key(0x4, true),
-    //         Key::W => self.keyboard.set_key(0x5, true),
-    //         Key::E => self.keyboard.set_key(0x6, true),
-    //         Key::R => self.keyboard.set_key(0xD, true),
-    //         Key::A => self.keyboard.set_key(0x7, true),
-    //         Key::S => self.keyboard.set_key(0x8, true),
-    //         Key::D => self.keyboard.set_key(0x9, true),
-    //         Key::F => self.keyboard.set_key(0xE, true),
-    //         Key::Z => self.keyboard.set_key(0xA, true),
-    //         Key::X => self.keyboard.set_key(0x0, true),
-    //         Key::C => self.keyboard.set_key(0xB, true),
-    //         Key::V => self.keyboard.set_key(0xF, true),
-    //         _ => {}
-    //     });
-
-    //     // Clear keys that are no longer pressed
-    //     let pressed_keys = self.window.get_keys();
-    //     [
-    //         (Key::Key1, 0x1),
-    //         (Key::Key2, 0x2),
-    //         (Key::Key3, 0x3),
-    //         (Key::Key4, 0xC),
-    //         (Key::Q, 0x4),
-    //         (Key::W, 0x5),
-    //         (Key::E, 0x6),
-    //         (Key::R, 0xD),
-    //         (Key::A, 0x7),
-    //         (Key::S, 0x8),
-    //         (Key::D, 0x9),
-    //         (Key::F, 0xE),
-    //         (Key::Z, 0xA),
-    //         (Key::X, 0x0),
-    //         (Key::C, 0xB),
-    //         (Key::V, 0xF),
-    //     ]
-    //     .iter()
-    //     .for_each(|(key, chip8_key)| {
-    //         if !pressed_keys.contains(key) {
-    //             self.keyboard.set_key(*chip8_key, false);
-    //         }
-    //     });
-
-    //     true
-    // }
-
-    // // add this method to get the window reference
-    // pub fn window(&mut self) -> &mut Window {
-    //     &mut self.window
-    // }
-
-    // add this method to check if window is open
-    pub fn is_open(&self) -> bool {
-        self.window.is_open()
-    }
-
-    pub fn update(&mut self) -> Result<(), minifb::Error> {
-        // update keyboard state
-        for (chip8_key, pc_key) in Keyboard::KEYMAP.iter().enumerate() {
-            self.keyboard
-                .set_key(chip8_key as u8, self.window.is_key_down(*pc_key));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_wraps_and_clips() {
+        let mut display = Display::new();
+        assert!(!display.draw(60, 31, &[0xFF, 0x81], false));
+        // the row and the columns past the edges come back around
+        assert_eq!(display.rows()[31], 0xF000_0000_0000_000F);
+        assert_eq!(display.rows()[0], 0x1000_0000_0000_0008);
+
+        // drawing over lit pixels turns them off and reports a collision
+        assert!(display.draw(60, 31, &[0x80], false));
+        assert_eq!(display.rows()[31], 0xF000_0000_0000_0007);
+
+        let mut clipped = Display::new();
+        assert!(!clipped.draw(60, 31, &[0xFF, 0xFF], true));
+        assert_eq!(clipped.rows()[31], 0x0000_0000_0000_000F);
+        assert_eq!(clipped.rows()[0], 0);
+
+        // the starting coordinate wraps even when clipping
+        assert!(!clipped.draw(64 + 2, 32, &[0xC0], true));
+        assert_eq!(clipped.rows()[0], 0x3000_0000_0000_0000);
+    }
+
+    #[test]
+    fn test_indexed_blending_and_collision() {
+        let mut display = Display::new();
+        display.set_indexed(true);
+        assert_eq!(display.resolution(), (256, 192));
+        let indexed = display.indexed_mut().unwrap();
+        indexed.palette[1] = 0xFF_FF0000;
+        indexed.palette[2] = 0xFF_0000FF;
+        indexed.collision = 1;
+
+        // nothing is shown until the screen is cleared
+        assert!(!indexed.plot(10, 20, 1));
+        assert!(!indexed.plot(256, 0, 1));
+        assert_eq!(indexed.front()[20 * 256 + 10], 0);
+        indexed.blend = Blend::Half;
+        assert!(indexed.plot(10, 20, 2));
+        display.clear();
+        assert_eq!(display.indexed().unwrap().front()[20 * 256 + 10], 0x7F_007F);
 
-        self.window
-            .update_with_buffer(&self.buffer, self.width, self.height)
+        assert_eq!(Blend::Add.mix(0x80_8080, 0xA0_1000), 0xFF_9080);
+        assert_eq!(Blend::Multiply.mix(0xFF_8000, 0x80_FFFF), 0x80_8000);
+        assert_eq!(Blend::from_mode(9), Blend::Normal);
     }
 }
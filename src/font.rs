@@ -0,0 +1,289 @@
+use crate::config::invalid;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// bytes per glyph of the hex font (Fx29) and of SCHIP's big font (Fx30)
+pub const SMALL_GLYPH: usize = 5;
+pub const BIG_GLYPH: usize = 10;
+
+// the 16 hex digits
+pub const SMALL_FONT_SIZE: usize = 16 * SMALL_GLYPH;
+
+/// The hex digit glyphs an interpreter shipped with. ROMs that read the font bytes
+/// directly, or draw digits next to their own sprites, only look right with the
+/// glyphs they were written against.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FontSet {
+    // the font most modern interpreters use, from CHIP-48 by way of Octo
+    #[default]
+    Octo,
+    Vip,
+    Dream6800,
+    Eti660,
+    FishNChips,
+}
+
+#[rustfmt::skip]
+const OCTO: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+#[rustfmt::skip]
+const VIP: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+#[rustfmt::skip]
+const DREAM_6800: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+#[rustfmt::skip]
+const ETI_660: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+#[rustfmt::skip]
+const FISH_N_CHIPS: [u8; SMALL_FONT_SIZE] = [
+    0x60, 0xA0, 0xA0, 0xA0, 0xC0, // 0
+    0x40, 0xC0, 0x40, 0x40, 0xE0, // 1
+    0xC0, 0x20, 0x40, 0x80, 0xE0, // 2
+    0xC0, 0x20, 0x40, 0x20, 0xC0, // 3
+    0x20, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xC0, 0x20, 0xC0, // 5
+    0x40, 0x80, 0xC0, 0xA0, 0x40, // 6
+    0xE0, 0x20, 0x60, 0x40, 0x40, // 7
+    0x40, 0xA0, 0x40, 0xA0, 0x40, // 8
+    0x40, 0xA0, 0x60, 0x20, 0x40, // 9
+    0x40, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xC0, 0xA0, 0xC0, // B
+    0x60, 0x80, 0x80, 0x80, 0x60, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xC0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// SCHIP 1.1's 8x10 digits, it has no glyphs for A to F
+#[rustfmt::skip]
+const SCHIP_BIG: [u8; 10 * BIG_GLYPH] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+];
+
+/// The glyphs loaded into the interpreter area: the hex font, then the big font
+/// right after it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Font {
+    pub small: [u8; SMALL_FONT_SIZE],
+    pub big: Vec<u8>,
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Font::builtin(FontSet::default())
+    }
+}
+
+impl Font {
+    // a built-in set, each comes with SCHIP's big digits
+    pub fn builtin(set: FontSet) -> Self {
+        let small = match set {
+            FontSet::Octo => OCTO,
+            FontSet::Vip => VIP,
+            FontSet::Dream6800 => DREAM_6800,
+            FontSet::Eti660 => ETI_660,
+            FontSet::FishNChips => FISH_N_CHIPS,
+        };
+        Font {
+            small,
+            big: SCHIP_BIG.to_vec(),
+        }
+    }
+
+    // a font file: the 80 byte hex font followed by 10 or 16 big glyphs
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let big = data.get(SMALL_FONT_SIZE..).unwrap_or_default();
+        let big_sizes = [10 * BIG_GLYPH, 16 * BIG_GLYPH];
+        if data.len() < SMALL_FONT_SIZE || !big_sizes.contains(&big.len()) {
+            return Err(invalid(format!(
+                "expected {} bytes of hex font plus {} or {} bytes of big font, found {} bytes",
+                SMALL_FONT_SIZE,
+                big_sizes[0],
+                big_sizes[1],
+                data.len()
+            )));
+        }
+        let mut small = [0; SMALL_FONT_SIZE];
+        small.copy_from_slice(&data[..SMALL_FONT_SIZE]);
+        Ok(Font {
+            small,
+            big: big.to_vec(),
+        })
+    }
+
+    // bytes taken up in memory
+    pub fn size(&self) -> usize {
+        SMALL_FONT_SIZE + self.big.len()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        [&self.small[..], &self.big].concat()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FontConfig {
+    /// built-in glyphs to use when no file is given
+    pub set: FontSet,
+    /// where the hex font starts, the big font follows it
+    pub address: usize,
+    /// a custom font, see `Font::parse`
+    pub file: Option<PathBuf>,
+}
+
+impl FontConfig {
+    // the configured font, which has to end before `limit`
+    pub fn load(&self, limit: usize) -> io::Result<Font> {
+        let font = match &self.file {
+            Some(path) => Font::parse(&fs::read(path)?)
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?,
+            None => Font::builtin(self.set),
+        };
+        if self.address + font.size() > limit {
+            return Err(invalid(format!(
+                "a {} byte font at {:#05X} runs into the program at {:#05X}",
+                font.size(),
+                self.address,
+                limit
+            )));
+        }
+        Ok(font)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+    use crate::variant::Variant;
+
+    #[test]
+    fn test_relocated_font() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.set_variant(Variant::Schip);
+        assert_eq!(cpu.heap[0x05], 0x20);
+        cpu.load_font(Font::builtin(FontSet::Vip), 0x50);
+        // the old glyphs are gone
+        assert_eq!(cpu.heap[0x05], 0);
+
+        cpu.registers[3] = 0x1B;
+        cpu.registers[4] = 0x17;
+        cpu.heap.load(0x200, &[0xF3, 0x29, 0xF4, 0x30]);
+        cpu.tick();
+        assert_eq!(cpu.i_register, 0x50 + 0xB * 5);
+        assert_eq!(cpu.heap[cpu.i_register as usize], 0xF0);
+        assert_eq!(cpu.heap[cpu.i_register as usize + 1], 0x50);
+        cpu.tick();
+        assert_eq!(cpu.i_register, 0x50 + 80 + 7 * 10);
+    }
+
+    #[test]
+    fn test_big_digit_past_the_font() {
+        // the built-in big fonts stop at 9, SCHIP points I past them anyway
+        let mut cpu = cpu_with_program(&[0xF3, 0x30]);
+        cpu.set_variant(Variant::Schip);
+        cpu.registers[3] = 0xB;
+        cpu.tick();
+        assert_eq!(cpu.i_register, 80 + 0xB * 10);
+    }
+
+    #[test]
+    fn test_font_files() {
+        // without big digits there is nothing for Fx30 to point at
+        assert!(Font::parse(&OCTO).is_err());
+        let font = Font::parse(&[0x11; 80 + 160]).unwrap();
+        assert_eq!(font.size(), 240);
+        assert!(Font::parse(&[0; 79]).is_err());
+        assert!(Font::parse(&[0; 85]).is_err());
+        assert!(Font::parse(&[0; 80 + 120]).is_err());
+
+        let config = FontConfig {
+            address: 0x1A0,
+            ..FontConfig::default()
+        };
+        assert!(config.load(0x200).is_err());
+        assert_eq!(config.load(0x300).unwrap(), Font::default());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::display::{Colors, Display, Indexed};
+use crate::input::WindowInput;
+use crate::keymap::Keymap;
+use crate::speed::Hotkey;
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+// where frames end up and where host input comes from
+pub trait Frontend {
+    fn is_open(&self) -> bool;
+
+    // show the current frame and poll host input
+    fn present(&mut self, display: &Display) -> Result<(), Box<dyn Error>>;
+
+    // speed hotkeys pressed since the last present
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        Vec::new()
+    }
+
+    fn set_title(&mut self, _title: &str) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontendKind {
+    /// minifb window with keyboard input
+    Window,
+    /// no window and no input, for batch runs
+    Headless,
+}
+
+// 0xRRGGBB color, written as "#RRGGBB" in config files and on the command line
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(pub u32);
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim().trim_start_matches('#');
+        if hex.len() != 6 {
+            return Err(format!("invalid color {:?}, expected #RRGGBB", s));
+        }
+        u32::from_str_radix(hex, 16)
+            .map(Color)
+            .map_err(|_| format!("invalid color {:?}, expected #RRGGBB", s))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:06X}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Palette {
+    pub background: Color,
+    pub foreground: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            background: Color(0x000000),
+            foreground: Color(0xFFFFFF),
+        }
+    }
+}
+
+// the VP-590's foreground colors, the bits of a zone's color are red, blue and green
+const VP590_COLORS: [u32; 8] = [
+    0x000000, 0xFF0000, 0x0000FF, 0xFF00FF, 0x00FF00, 0xFFFF00, 0x00FFFF, 0xFFFFFF,
+];
+// and the backgrounds BGC steps through: dark blue, black, green and red
+const VP590_BACKGROUNDS: [u32; 4] = [0x000080, 0x000000, 0x008000, 0x800000];
+
+impl Palette {
+    // map the display's bits to presentable colors, one 0xRRGGBB per pixel
+    // a display with a color board brings its own colors
+    pub fn apply(&self, display: &Display, out: &mut Vec<u32>) {
+        if let Some(indexed) = display.indexed() {
+            return Self::apply_indexed(indexed, out);
+        }
+        if let Some(colors) = display.colors() {
+            return Self::apply_colors(display, colors, out);
+        }
+        out.clear();
+        for &row in display.rows() {
+            out.extend((0..display.width()).map(|x| {
+                if row << x >> 63 == 1 {
+                    self.foreground.0
+                } else {
+                    self.background.0
+                }
+            }));
+        }
+    }
+
+    // MegaChip's screen is already in color, faded by its alpha
+    fn apply_indexed(indexed: &Indexed, out: &mut Vec<u32>) {
+        let alpha = indexed.alpha as u32;
+        out.clear();
+        out.extend(indexed.front().iter().map(|&color| {
+            (0..3).fold(0, |faded, channel| {
+                let shift = channel * 8;
+                faded | (((color >> shift) & 0xFF) * alpha / 0xFF) << shift
+            })
+        }));
+    }
+
+    fn apply_colors(display: &Display, colors: &Colors, out: &mut Vec<u32>) {
+        let background = VP590_BACKGROUNDS[colors.background as usize % 4];
+        out.clear();
+        for (y, &row) in display.rows().iter().enumerate() {
+            out.extend((0..display.width()).map(|x| {
+                if row << x >> 63 == 1 {
+                    VP590_COLORS[colors.at(x, y) as usize]
+                } else {
+                    background
+                }
+            }));
+        }
+    }
+}
+
+pub struct WindowFrontend {
+    window: Window,
+    input: WindowInput,
+    keymap: Keymap,
+    palette: Palette,
+    // more keypads, like CHIP-8X's second one
+    keypads: Vec<(Keymap, WindowInput)>,
+    frame: Vec<u32>,
+}
+
+impl WindowFrontend {
+    // `size` is the largest screen the window will show, smaller ones are stretched
+    pub fn new(
+        size: (usize, usize),
+        scale: Scale,
+        palette: Palette,
+        keymap: Keymap,
+    ) -> Result<Self, minifb::Error> {
+        let mut window = Window::new(
+            "CHIP-8 Emulator",
+            size.0,
+            size.1,
+            WindowOptions {
+                scale,
+                ..WindowOptions::default()
+            },
+        )?;
+
+        // frame pacing is done by the emulation loop
+        window.limit_update_rate(None);
+
+        Ok(WindowFrontend {
+            window,
+            input: WindowInput::default(),
+            keymap,
+            palette,
+            keypads: Vec::new(),
+            frame: Vec::with_capacity(size.0 * size.1),
+        })
+    }
+
+    // input source fed from this window's keyboard
+    pub fn input(&self) -> WindowInput {
+        self.input.clone()
+    }
+
+    // input source for another keypad, played with the keys in `keymap`
+    pub fn add_keypad(&mut self, keymap: Keymap) -> WindowInput {
+        let input = WindowInput::default();
+        self.keypads.push((keymap, input.clone()));
+        input
+    }
+}
+
+// the keys of `keymap` held in this window, as a bitmask
+fn held(window: &Window, keymap: &Keymap) -> u16 {
+    let pressed = keymap.pressed(|key| window.is_key_down(key));
+    pressed
+        .iter()
+        .enumerate()
+        .fold(0, |held, (key, pressed)| held | (*pressed as u16) << key)
+}
+
+impl Frontend for WindowFrontend {
+    fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    fn present(&mut self, display: &Display) -> Result<(), Box<dyn Error>> {
+        // publish keyboard state for the input muxes
+        self.input.publish(held(&self.window, &self.keymap));
+        for (keymap, input) in &self.keypads {
+            input.publish(held(&self.window, keymap));
+        }
+
+        self.palette.apply(display, &mut self.frame);
+        let (width, height) = display.resolution();
+        self.window.update_with_buffer(&self.frame, width, height)?;
+        Ok(())
+    }
+
+    // F1 pause, F2 frame advance, Tab (held) fast-forward, F3 slow motion,
+    // PageUp/PageDown instructions per frame, keymaps can't use them (see `keymap::HOTKEYS`)
+    fn hotkeys(&mut self) -> Vec<Hotkey> {
+        let mut hotkeys = vec![Hotkey::FastForward(self.window.is_key_down(Key::Tab))];
+        for key in self.window.get_keys_pressed(KeyRepeat::Yes) {
+            let hotkey = match key {
+                Key::F1 => Hotkey::TogglePause,
+                Key::F2 => Hotkey::AdvanceFrame,
+                Key::F3 => Hotkey::ToggleSlowMotion,
+                Key::PageUp => Hotkey::MoreInstructions,
+                Key::PageDown => Hotkey::FewerInstructions,
+                _ => continue,
+            };
+            hotkeys.push(hotkey);
+        }
+        hotkeys
+    }
+
+    fn set_title(&mut self, title: &str) {
+        self.window.set_title(title);
+    }
+}
+
+pub struct HeadlessFrontend;
+
+impl Frontend for HeadlessFrontend {
+    fn is_open(&self) -> bool {
+        true
+    }
+
+    fn present(&mut self, _display: &Display) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use crate::config::invalid;
+use crate::input::{GamepadConfig, InputSource};
+use evdev::{AbsoluteAxisType, Device, Key};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// something on a gamepad that can be bound to a CHIP-8 key
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Button {
+    Key(Key),
+    // d-pad reported as a hat axis: axis and the sign that counts as pressed
+    Hat(AbsoluteAxisType, i32),
+}
+
+impl FromStr for Button {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        match name {
+            "HAT0_UP" => Ok(Button::Hat(AbsoluteAxisType::ABS_HAT0Y, -1)),
+            "HAT0_DOWN" => Ok(Button::Hat(AbsoluteAxisType::ABS_HAT0Y, 1)),
+            "HAT0_LEFT" => Ok(Button::Hat(AbsoluteAxisType::ABS_HAT0X, -1)),
+            "HAT0_RIGHT" => Ok(Button::Hat(AbsoluteAxisType::ABS_HAT0X, 1)),
+            _ => Key::from_str(name)
+                .map(Button::Key)
+                .map_err(|_| invalid(format!("unknown gamepad button {:?}", name))),
+        }
+    }
+}
+
+// evdev gamepads on Linux
+pub struct GamepadInput {
+    devices: Vec<(PathBuf, Device)>,
+    mapping: Vec<(Button, u8)>,
+}
+
+impl GamepadInput {
+    // open the configured device, or every connected device that looks like a gamepad
+    pub fn open(config: &GamepadConfig) -> io::Result<Self> {
+        let mapping = config
+            .mapping()?
+            .iter()
+            .map(|(name, key)| Ok((name.parse()?, *key)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let devices = match &config.device {
+            Some(path) => vec![(path.clone(), Device::open(path)?)],
+            None => evdev::enumerate()
+                .filter(|(_, device)| {
+                    device
+                        .supported_keys()
+                        .is_some_and(|keys| keys.contains(Key::BTN_SOUTH))
+                })
+                .collect(),
+        };
+
+        if devices.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no gamepad found, check permissions on /dev/input",
+            ));
+        }
+
+        Ok(GamepadInput { devices, mapping })
+    }
+
+    pub fn device_names(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .map(|(path, device)| {
+                format!(
+                    "{} ({})",
+                    device.name().unwrap_or("unnamed device"),
+                    path.display()
+                )
+            })
+            .collect()
+    }
+}
+
+impl InputSource for GamepadInput {
+    fn poll(&mut self) -> u16 {
+        let mut held = 0;
+        let mapping = &self.mapping;
+
+        // a device that fails to answer has been unplugged
+        self.devices.retain(|(path, device)| {
+            let keys = device.get_key_state();
+            let axes = device.get_abs_state();
+            let (keys, axes) = match (keys, axes) {
+                (Ok(keys), Ok(axes)) => (keys, axes),
+                _ => {
+                    println!("gamepad disconnected: {}", path.display());
+                    return false;
+                }
+            };
+
+            for (button, key) in mapping {
+                let pressed = match button {
+                    Button::Key(code) => keys.contains(*code),
+                    Button::Hat(axis, sign) => axes[axis.0 as usize].value.signum() == *sign,
+                };
+                if pressed {
+                    held |= 1 << key;
+                }
+            }
+            true
+        });
+
+        held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::uinput::VirtualDeviceBuilder;
+    use evdev::{AttributeSet, EventType, InputEvent};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_button_names() {
+        assert_eq!(
+            "BTN_SOUTH".parse::<Button>().unwrap(),
+            Button::Key(Key::BTN_SOUTH)
+        );
+        assert_eq!(
+            "HAT0_LEFT".parse::<Button>().unwrap(),
+            Button::Hat(AbsoluteAxisType::ABS_HAT0X, -1)
+        );
+        assert!("BTN_NOPE".parse::<Button>().is_err());
+    }
+
+    // needs write access to /dev/uinput: cargo test --features gamepad -- --ignored
+    #[test]
+    #[ignore]
+    fn test_virtual_gamepad() {
+        let mut buttons = AttributeSet::<Key>::new();
+        buttons.insert(Key::BTN_SOUTH);
+        buttons.insert(Key::BTN_DPAD_UP);
+
+        let mut pad = VirtualDeviceBuilder::new()
+            .unwrap()
+            .name("chip8 test gamepad")
+            .with_keys(&buttons)
+            .unwrap()
+            .build()
+            .unwrap();
+        let path = pad
+            .enumerate_dev_nodes_blocking()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        // give udev a moment to set up the node
+        thread::sleep(Duration::from_millis(200));
+
+        let config = GamepadConfig {
+            device: Some(path),
+            ..GamepadConfig::default()
+        };
+        let mut input = GamepadInput::open(&config).unwrap();
+        assert_eq!(input.poll(), 0);
+
+        pad.emit(&[
+            InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1),
+            InputEvent::new(EventType::KEY, Key::BTN_DPAD_UP.code(), 1),
+        ])
+        .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(input.poll(), (1 << 0x5) | (1 << 0x2));
+
+        pad.emit(&[InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 0)])
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(input.poll(), 1 << 0x2);
+    }
+}
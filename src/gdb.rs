@@ -0,0 +1,772 @@
+use crate::cpu::CPU;
+use crate::debugger::{set_poll_mode, DebugServer, Debugger, StopReason};
+use crate::machine::Machine;
+use crate::memory::{WatchKind, Watchpoint};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+
+// registers in `g` packet order: V0-VF, I, PC, SP, DT, ST
+const REGISTER_COUNT: usize = 21;
+const MAX_PACKET: usize = 0x1000;
+
+// register names and sizes as sent to gdb in the target description
+const REGISTERS: [(&str, u32, &str); REGISTER_COUNT] = [
+    ("v0", 8, "uint8"),
+    ("v1", 8, "uint8"),
+    ("v2", 8, "uint8"),
+    ("v3", 8, "uint8"),
+    ("v4", 8, "uint8"),
+    ("v5", 8, "uint8"),
+    ("v6", 8, "uint8"),
+    ("v7", 8, "uint8"),
+    ("v8", 8, "uint8"),
+    ("v9", 8, "uint8"),
+    ("va", 8, "uint8"),
+    ("vb", 8, "uint8"),
+    ("vc", 8, "uint8"),
+    ("vd", 8, "uint8"),
+    ("ve", 8, "uint8"),
+    ("vf", 8, "uint8"),
+    ("i", 32, "data_ptr"),
+    ("pc", 32, "code_ptr"),
+    ("sp", 16, "uint16"),
+    ("dt", 8, "uint8"),
+    ("st", 8, "uint8"),
+];
+
+// gdb remote serial protocol stub on localhost, one client at a time
+pub struct GdbServer {
+    debugger: Debugger,
+    // set once the client asks to kill the target
+    killed: bool,
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    input: Vec<u8>,
+    no_ack: bool,
+    last_stop: StopReason,
+    // what `monitor reset` starts over
+    machine: Machine,
+    rom: Vec<u8>,
+}
+
+impl GdbServer {
+    pub fn bind(port: u16, machine: Machine, rom: Vec<u8>) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbServer {
+            debugger: Debugger::new(),
+            killed: false,
+            listener,
+            stream: None,
+            input: Vec::new(),
+            no_ack: false,
+            last_stop: StopReason::Step,
+            machine,
+            rom,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // block until a client connects, the CPU stays halted until it says to continue
+    pub fn wait_for_client(&mut self) -> io::Result<()> {
+        self.listener.set_nonblocking(false)?;
+        let (stream, _) = self.listener.accept()?;
+        self.listener.set_nonblocking(true)?;
+        self.attach(stream)
+    }
+
+    fn attach(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        self.stream = Some(stream);
+        self.input.clear();
+        self.no_ack = false;
+        Ok(())
+    }
+
+    // the program keeps running on its own once gdb is gone
+    fn detach(&mut self, cpu: &mut CPU) {
+        self.stream = None;
+        self.debugger.breakpoints.clear();
+        cpu.heap.watchpoints.clear();
+        self.debugger.resume(cpu);
+    }
+
+    fn process(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        while let Some(&byte) = self.input.first() {
+            match byte {
+                // ctrl-c
+                0x03 => {
+                    self.input.remove(0);
+                    if !self.debugger.is_halted() {
+                        self.debugger.halt();
+                        self.last_stop = StopReason::Interrupted;
+                        self.send(&stop_reply(self.last_stop))?;
+                    }
+                }
+                b'$' => {
+                    let end = match self.input.iter().position(|&b| b == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        // wait for the rest of the packet
+                        _ => return Ok(()),
+                    };
+                    let data = self.input[1..end].to_vec();
+                    let valid = std::str::from_utf8(&self.input[end + 1..end + 3])
+                        .ok()
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        == Some(checksum(&data));
+                    self.input.drain(..end + 3);
+
+                    if !self.no_ack {
+                        self.write(if valid { b"+" } else { b"-" })?;
+                    }
+                    if !valid {
+                        continue;
+                    }
+
+                    let packet = String::from_utf8_lossy(&data).into_owned();
+                    if let Some(reply) = self.handle(&packet, cpu) {
+                        self.send(&reply)?;
+                    }
+
+                    // these take effect once their reply is out
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    } else if packet.starts_with('D') || self.killed {
+                        self.detach(cpu);
+                        return Ok(());
+                    }
+                }
+                // acks (we never retransmit) and anything between packets
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the reply to one packet, None when it comes later as a stop reply
+    fn handle(&mut self, packet: &str, cpu: &mut CPU) -> Option<String> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => stop_reply(self.last_stop),
+            "g" => (0..REGISTER_COUNT).map(|n| read_register(cpu, n)).collect(),
+            "G" => write_registers(cpu, args).unwrap_or_else(error),
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .filter(|&n| n < REGISTER_COUNT)
+                .map(|n| read_register(cpu, n))
+                .unwrap_or_else(error),
+            "P" => args
+                .split_once('=')
+                .and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    write_register(cpu, n, &from_hex(value)?)
+                })
+                .unwrap_or_else(error),
+            "m" => read_memory(cpu, args).unwrap_or_else(error),
+            "M" => write_memory(cpu, args).unwrap_or_else(error),
+            "c" | "C" | "s" | "S" => {
+                // `c addr` resumes at addr, `C sig;addr` ignores the signal
+                let addr = match command {
+                    "C" | "S" => args.split_once(';').map(|(_, addr)| addr),
+                    _ => Some(args).filter(|addr| !addr.is_empty()),
+                };
+                if let Some(addr) = addr {
+                    match parse_hex(addr).filter(|&addr| addr < cpu.heap.len()) {
+                        Some(addr) => cpu.program_counter = addr,
+                        None => return Some(error()),
+                    }
+                }
+                if command.eq_ignore_ascii_case("s") {
+                    self.debugger.step(cpu);
+                } else {
+                    self.debugger.resume(cpu);
+                }
+                return None;
+            }
+            "Z" | "z" => self
+                .breakpoint(args, command == "Z", cpu)
+                .unwrap_or_else(error),
+            "v" => {
+                if packet == "vCont?" {
+                    "vCont;c;C;s;S".to_string()
+                } else if let Some(actions) = packet.strip_prefix("vCont;") {
+                    // there is a single thread, so only the first action matters
+                    match actions.chars().next() {
+                        Some('c') | Some('C') => self.debugger.resume(cpu),
+                        Some('s') | Some('S') => self.debugger.step(cpu),
+                        _ => return Some(error()),
+                    }
+                    return None;
+                } else {
+                    String::new()
+                }
+            }
+            "q" | "Q" => match packet.strip_prefix("qRcmd,") {
+                Some(command) => from_hex(command)
+                    .map(|command| {
+                        let command = String::from_utf8_lossy(&command);
+                        monitor(&command, cpu, &self.machine, &self.rom)
+                    })
+                    .unwrap_or_else(error),
+                None => query(packet),
+            },
+            "H" | "T" | "D" => "OK".to_string(),
+            "k" => {
+                self.killed = true;
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    // Z0/Z1 breakpoints, Z2/Z3/Z4 write, read and access watchpoints
+    fn breakpoint(&mut self, args: &str, insert: bool, cpu: &mut CPU) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let len = parse_hex(fields.next()?)?;
+
+        match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.breakpoints.insert(addr);
+                } else {
+                    self.debugger.breakpoints.remove(&addr);
+                }
+            }
+            "2" | "3" | "4" => {
+                let kind = match kind {
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let watchpoint = Watchpoint {
+                    kind,
+                    addr,
+                    len,
+                    value: None,
+                };
+                if insert {
+                    if !cpu.heap.add_watchpoint(watchpoint) {
+                        return None;
+                    }
+                } else {
+                    cpu.heap.remove_watchpoint(watchpoint);
+                }
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".to_string())
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.write(packet.as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(stream) = &mut self.stream {
+            stream.set_nonblocking(false)?;
+            stream.write_all(bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl DebugServer for GdbServer {
+    fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    fn poll(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        if self.stream.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => self.attach(stream)?,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let halted = self.debugger.is_halted();
+        let stream = self.stream.as_mut().unwrap();
+        set_poll_mode(stream, halted)?;
+
+        let mut buffer = [0; MAX_PACKET];
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                self.detach(cpu);
+                return Ok(());
+            }
+            Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(())
+            }
+            Err(e) => {
+                println!("gdb connection lost: {}", e);
+                self.detach(cpu);
+                return Ok(());
+            }
+        }
+
+        self.process(cpu)
+    }
+
+    fn run(&mut self, cpu: &mut CPU, budget: u32) -> io::Result<()> {
+        if let Some(reason) = self.debugger.run(cpu, budget) {
+            self.last_stop = reason;
+            self.send(&stop_reply(reason))?;
+        }
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.killed
+    }
+}
+
+fn query(packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+        format!(
+            "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+            MAX_PACKET
+        )
+    } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        target_xml_chunk(range).unwrap_or_else(error)
+    } else {
+        match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+// `monitor` commands, for what the remote protocol has no packets for
+fn monitor(command: &str, cpu: &mut CPU, machine: &Machine, rom: &[u8]) -> String {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let output = match words.as_slice() {
+        ["writer", addr] => match parse_number(addr).filter(|&addr| addr < cpu.heap.len()) {
+            Some(addr) => match cpu.heap.last_writer(addr) {
+                Some(pc) => format!(
+                    "0x{:03X} was last written by the instruction at 0x{:03X}\n",
+                    addr, pc
+                ),
+                None => format!("0x{:03X} has not been written by the program\n", addr),
+            },
+            None => format!("invalid address {:?}\n", addr),
+        },
+        ["watch", kind, rest @ ..] => match parse_watchpoint(kind, rest) {
+            Some(watchpoint) if cpu.heap.add_watchpoint(watchpoint) => {
+                format!("watching {}\n", describe(&watchpoint))
+            }
+            _ => format!("invalid watchpoint: {}\n", command),
+        },
+        ["unwatch", addr] => {
+            let addr = parse_number(addr);
+            let count = cpu.heap.watchpoints.len();
+            cpu.heap.watchpoints.retain(|w| Some(w.addr) != addr);
+            format!(
+                "removed {} watchpoints\n",
+                count - cpu.heap.watchpoints.len()
+            )
+        }
+        ["reset"] => {
+            cpu.reset(machine, rom);
+            format!("reset, PC is at 0x{:03X}\n", cpu.program_counter)
+        }
+        ["watches"] => cpu
+            .heap
+            .watchpoints
+            .iter()
+            .map(|w| format!("{}\n", describe(w)))
+            .collect(),
+        _ => "commands:\n  \
+              writer ADDR\n  \
+              watch read|write|access|exec ADDR [LEN] [=VALUE]\n  \
+              unwatch ADDR\n  \
+              watches\n  \
+              reset\n"
+            .to_string(),
+    };
+    to_hex(output.as_bytes())
+}
+
+// `KIND ADDR [LEN] [=VALUE]`, numbers in hex
+fn parse_watchpoint(kind: &str, args: &[&str]) -> Option<Watchpoint> {
+    let kind = match kind {
+        "read" => WatchKind::Read,
+        "write" => WatchKind::Write,
+        "access" => WatchKind::Access,
+        "exec" => WatchKind::Execute,
+        _ => return None,
+    };
+    let (value, args) = match args.split_last() {
+        Some((last, rest)) if last.starts_with('=') => {
+            let value = u8::from_str_radix(last[1..].trim_start_matches("0x"), 16).ok()?;
+            (Some(value), rest)
+        }
+        _ => (None, args),
+    };
+    let (addr, len) = match args {
+        [addr] => (parse_number(addr)?, 1),
+        [addr, len] => (parse_number(addr)?, parse_number(len)?),
+        _ => return None,
+    };
+    Some(Watchpoint {
+        kind,
+        addr,
+        len,
+        value,
+    })
+}
+
+fn describe(watchpoint: &Watchpoint) -> String {
+    let mut text = format!(
+        "{:?} 0x{:03X}..0x{:03X}",
+        watchpoint.kind,
+        watchpoint.addr,
+        watchpoint.addr + watchpoint.len
+    )
+    .to_lowercase();
+    if let Some(value) = watchpoint.value {
+        text.push_str(&format!(" when 0x{:02X}", value));
+    }
+    text
+}
+
+fn target_xml() -> String {
+    let registers: String = REGISTERS
+        .iter()
+        .map(|(name, bits, kind)| {
+            format!(
+                "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+                name, bits, kind
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.chip8.core\">{}</feature></target>",
+        registers
+    )
+}
+
+// `offset,length` of the target description, `m` when more follows and `l` for the last part
+fn target_xml_chunk(range: &str) -> Option<String> {
+    let (offset, length) = range.split_once(',')?;
+    let (offset, length) = (parse_hex(offset)?, parse_hex(length)?);
+    let xml = target_xml();
+    let start = offset.min(xml.len());
+    let end = start.saturating_add(length).min(xml.len());
+    let marker = if end == xml.len() { 'l' } else { 'm' };
+    Some(format!("{}{}", marker, &xml[start..end]))
+}
+
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint(hit) => {
+            let kind = match hit.watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+                // gdb has no stop reason for these, they look like a breakpoint
+                WatchKind::Execute => return "T05".to_string(),
+            };
+            format!("T05{}:{:x};", kind, hit.addr)
+        }
+        StopReason::Interrupted => "T02".to_string(),
+        StopReason::Breakpoint(_) | StopReason::Step => "T05".to_string(),
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    REGISTERS[n].1 as usize / 8
+}
+
+// registers are little endian
+fn read_register(cpu: &CPU, n: usize) -> String {
+    let value = match n {
+        0..=15 => cpu.registers[n] as u32,
+        16 => cpu.i_register,
+        17 => cpu.program_counter as u32,
+        18 => cpu.stack_pointer as u32,
+        19 => cpu.delay_timer as u32,
+        _ => cpu.sound_timer as u32,
+    };
+    to_hex(&value.to_le_bytes()[..register_size(n)])
+}
+
+fn write_register(cpu: &mut CPU, n: usize, bytes: &[u8]) -> Option<String> {
+    if n >= REGISTER_COUNT || bytes.len() != register_size(n) {
+        return None;
+    }
+    let value = bytes
+        .iter()
+        .rev()
+        .fold(0usize, |value, &byte| value << 8 | byte as usize);
+
+    match n {
+        0..=15 => cpu.registers[n] = value as u8,
+        16 => cpu.i_register = value as u32,
+        17 if value < cpu.heap.len() => cpu.program_counter = value,
+        18 if value <= cpu.stack.len() => cpu.stack_pointer = value,
+        19 => cpu.delay_timer = value as u8,
+        20 => cpu.sound_timer = value as u8,
+        _ => return None,
+    }
+    Some("OK".to_string())
+}
+
+fn write_registers(cpu: &mut CPU, data: &str) -> Option<String> {
+    let mut bytes = from_hex(data)?;
+    let total: usize = (0..REGISTER_COUNT).map(register_size).sum();
+    if bytes.len() != total {
+        return None;
+    }
+    for n in 0..REGISTER_COUNT {
+        let rest = bytes.split_off(register_size(n));
+        write_register(cpu, n, &bytes)?;
+        bytes = rest;
+    }
+    Some("OK".to_string())
+}
+
+// `addr,length`, reads past the end of memory are cut short
+fn read_memory(cpu: &CPU, args: &str) -> Option<String> {
+    let (addr, length) = args.split_once(',')?;
+    let (addr, length) = (parse_hex(addr)?, parse_hex(length)?);
+    if addr >= cpu.heap.len() {
+        return None;
+    }
+    let end = addr.saturating_add(length).min(cpu.heap.len());
+    Some(to_hex(&cpu.heap[addr..end]))
+}
+
+// `addr,length:data`
+fn write_memory(cpu: &mut CPU, args: &str) -> Option<String> {
+    let (range, data) = args.split_once(':')?;
+    let (addr, length) = range.split_once(',')?;
+    let (addr, length) = (parse_hex(addr)?, parse_hex(length)?);
+    let data = from_hex(data)?;
+    if data.len() != length || addr.checked_add(length)? > cpu.heap.len() {
+        return None;
+    }
+    cpu.heap.load(addr, &data);
+    Some("OK".to_string())
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// hex, with or without a 0x prefix
+fn parse_number(text: &str) -> Option<usize> {
+    parse_hex(text.trim_start_matches("0x"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+    use crate::variant::Variant;
+    use std::thread;
+
+    // a minimal client, sends a packet and returns the reply once it has acked it
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+', "nack for {}", data);
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = Vec::new();
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                packet.push(byte[0]);
+                if packet.len() > 3 && packet[packet.len() - 3] == b'#' {
+                    break;
+                }
+            }
+            self.stream.write_all(b"+").unwrap();
+            let data = &packet[1..packet.len() - 3];
+            assert_eq!(
+                format!("{:02x}", checksum(data)),
+                String::from_utf8_lossy(&packet[packet.len() - 2..])
+            );
+            String::from_utf8(data.to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        // LD V0, 7; LD I, 0x300; LD B, V0; ADD V0, 1; JP 0x206
+        let program = [0x60, 0x07, 0xA3, 0x00, 0xF0, 0x33, 0x70, 0x01, 0x12, 0x06];
+        let mut cpu = cpu_with_program(&program);
+
+        let machine = Machine::for_variant(Variant::Chip8);
+        let mut server = GdbServer::bind(0, machine, program.to_vec()).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(addr).unwrap(),
+            };
+            assert!(client
+                .request("qSupported:multiprocess+;swbreak+")
+                .contains("qXfer:features:read+"));
+            assert_eq!(client.request("?"), "T05");
+            let xml = client.request("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with('l') && xml.contains("<reg name=\"pc\" bitsize=\"32\""));
+
+            // halted at the entry point
+            assert_eq!(client.request("p11"), "00020000");
+            assert_eq!(client.request("m200,4"), "6007a300");
+
+            assert_eq!(client.request("Z2,302,1"), "OK");
+            client.send("c");
+            assert_eq!(client.reply(), "T05watch:302;");
+            assert_eq!(client.request("m300,3"), "000007");
+            assert_eq!(client.request("z2,302,1"), "OK");
+            let monitor = |client: &mut Client, command: &str| {
+                let reply = client.request(&format!("qRcmd,{}", to_hex(command.as_bytes())));
+                String::from_utf8(from_hex(&reply).unwrap()).unwrap()
+            };
+            assert_eq!(
+                monitor(&mut client, "writer 302"),
+                "0x302 was last written by the instruction at 0x204\n"
+            );
+            assert_eq!(
+                monitor(&mut client, "writer 0x200"),
+                "0x200 has not been written by the program\n"
+            );
+
+            assert_eq!(client.request("Z0,208,2"), "OK");
+            client.send("c");
+            assert_eq!(client.reply(), "T05");
+            let registers = client.request("g");
+            assert_eq!(registers.len(), 28 * 2);
+            // V0 was incremented once, PC is at the breakpoint
+            assert_eq!(&registers[..2], "08");
+            assert_eq!(&registers[40..48], "08020000");
+
+            // writes go through, then single step the jump
+            assert_eq!(client.request("P0=2a"), "OK");
+            assert_eq!(client.request("M300,2:abcd"), "OK");
+            client.send("s");
+            assert_eq!(client.reply(), "T05");
+            assert_eq!(client.request("p11"), "06020000");
+            assert_eq!(client.request("p0"), "2a");
+            assert_eq!(client.request("m300,2"), "abcd");
+
+            assert_eq!(client.request("m2000,1"), "E01");
+            assert_eq!(client.request("Z5,300,1"), "");
+            assert_eq!(client.request("Z2,100,ffffffffffffffff"), "E01");
+            assert_eq!(
+                monitor(&mut client, "watch read 0xFFF 2"),
+                "invalid watchpoint: watch read 0xFFF 2\n"
+            );
+
+            // a reset starts the ROM over and keeps the watchpoints
+            assert_eq!(client.request("Z2,302,1"), "OK");
+            assert_eq!(monitor(&mut client, "reset"), "reset, PC is at 0x200\n");
+            assert_eq!(client.request("p0"), "00");
+            assert_eq!(client.request("m300,3"), "000000");
+            client.send("c");
+            assert_eq!(client.reply(), "T05watch:302;");
+            client.send("k");
+        });
+
+        server.wait_for_client().unwrap();
+        while !server.is_finished() && !client.is_finished() {
+            server.poll(&mut cpu).unwrap();
+            server.run(&mut cpu, 10).unwrap();
+        }
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_lengths_past_the_end() {
+        let mut cpu = cpu_with_program(&[0x12, 0x00]);
+        let all = "ffffffffffffffff";
+        assert_eq!(
+            read_memory(&cpu, &format!("100,{}", all)).map(|hex| hex.len()),
+            Some((cpu.heap.len() - 0x100) * 2)
+        );
+        assert_eq!(write_memory(&mut cpu, &format!("100,{}:12", all)), None);
+        assert_eq!(write_memory(&mut cpu, "fff,2:1234"), None);
+        assert!(target_xml_chunk(&format!("10,{}", all))
+            .unwrap()
+            .starts_with('l'));
+    }
+
+    #[test]
+    fn test_register_round_trip() {
+        let mut cpu = cpu_with_program(&[]);
+        let data = "000102030405060708090a0b0c0d0e0f56341200400200000100403c";
+        assert_eq!(write_registers(&mut cpu, data), Some("OK".to_string()));
+        assert_eq!(cpu.registers[0xF], 0x0F);
+        assert_eq!(cpu.i_register, 0x123456);
+        assert_eq!(cpu.program_counter, 0x240);
+        assert_eq!(
+            (cpu.stack_pointer, cpu.delay_timer, cpu.sound_timer),
+            (1, 0x40, 0x3C)
+        );
+
+        let registers: String = (0..REGISTER_COUNT)
+            .map(|n| read_register(&cpu, n))
+            .collect();
+        assert_eq!(registers, data);
+
+        // PC outside memory and an overlong stack are refused
+        assert_eq!(
+            write_register(&mut cpu, 17, &[0x00, 0x10, 0x00, 0x00]),
+            None
+        );
+        assert_eq!(write_register(&mut cpu, 18, &[17, 0]), None);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::backend::{Backend, BackendKind};
+use crate::config::{invalid, Settings};
+use crate::cpu::{KeyWait, StepOutcome, CPU};
+use crate::display::Display;
+use crate::keyboard::Keyboard;
+use crate::megachip::Sample;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// How a score is laid out in memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScoreFormat {
+    /// a decimal digit per byte, most significant first, as Fx33 stores them
+    Bcd,
+    /// a big-endian number
+    Binary,
+}
+
+impl FromStr for ScoreFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "bcd" => Ok(ScoreFormat::Bcd),
+            "binary" => Ok(ScoreFormat::Binary),
+            _ => Err(invalid(format!(
+                "unknown score format {:?}, expected bcd or binary",
+                s
+            ))),
+        }
+    }
+}
+
+/// A number the program keeps in memory. Its increase over a step is the reward.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Score {
+    pub address: usize,
+    pub len: usize,
+    pub format: ScoreFormat,
+}
+
+impl Score {
+    fn read(&self, memory: &[u8]) -> i64 {
+        let bytes = &memory[self.address..self.address + self.len];
+        match self.format {
+            ScoreFormat::Bcd => bytes
+                .iter()
+                .fold(0, |score, &digit| score * 10 + i64::from(digit)),
+            ScoreFormat::Binary => bytes
+                .iter()
+                .fold(0, |score, &byte| score << 8 | i64::from(byte)),
+        }
+    }
+}
+
+/// Everything a step can change, to go back to with `Env::restore_state`.
+#[derive(Clone)]
+pub struct State {
+    registers: [u8; 16],
+    i_register: u32,
+    delay_timer: u8,
+    sound_timer: u8,
+    program_counter: usize,
+    memory: Vec<u8>,
+    stack: Vec<u16>,
+    stack_pointer: usize,
+    display: Display,
+    key_wait: Option<KeyWait>,
+    cycles: u64,
+    frames: u64,
+    rng: StdRng,
+    timer_wait: bool,
+    sample: Option<Sample>,
+    rpl: [u8; 16],
+    keys: u16,
+    score: i64,
+}
+
+/// The headless CPU as a reinforcement learning environment. A step holds down the
+/// keys in an action mask for `frame_skip` frames, and is rewarded with how much the
+/// scores went up.
+pub struct Env {
+    settings: Settings,
+    scores: Vec<Score>,
+    // the episode ends when this address holds this value, e.g. no lives left
+    done_when: Option<(usize, u8)>,
+    pub frame_skip: u32,
+    keyboard: Arc<Keyboard>,
+    backend: Backend,
+    // None until the first reset
+    cpu: Option<CPU>,
+    score: i64,
+}
+
+impl Env {
+    pub fn new(
+        settings: Settings,
+        scores: Vec<Score>,
+        done_when: Option<(usize, u8)>,
+        frame_skip: u32,
+    ) -> io::Result<Self> {
+        let machine = settings.machine()?;
+        let addresses = scores
+            .iter()
+            .map(|score| score.address + score.len)
+            .chain(done_when.map(|(address, _)| address + 1));
+        if let Some(end) = addresses.max().filter(|&end| end > machine.memory_size) {
+            return Err(invalid(format!(
+                "{:#X} is past the end of memory at {:#X}",
+                end - 1,
+                machine.memory_size
+            )));
+        }
+        if scores.iter().any(|score| score.len == 0 || score.len > 8) {
+            return Err(invalid("scores must be from 1 to 8 bytes long"));
+        }
+        if frame_skip == 0 {
+            return Err(invalid("frame_skip must be at least 1"));
+        }
+        Ok(Env {
+            settings,
+            scores,
+            done_when,
+            frame_skip,
+            keyboard: Arc::new(Keyboard::new()),
+            backend: Backend::new(BackendKind::Interpreter)?,
+            cpu: None,
+            score: 0,
+        })
+    }
+
+    /// Start an episode of `rom`, with RND seeded by `seed`. Returns the screen.
+    pub fn reset(&mut self, rom: &[u8], seed: u64) -> io::Result<Vec<u8>> {
+        let machine = self.settings.machine()?;
+        machine.check_rom(rom.len())?;
+        if self.cpu.is_none() {
+            let font = self.settings.font.load(machine.load_address)?;
+            let mut cpu = CPU::new(self.keyboard.clone());
+            cpu.quirks = self.settings.quirks;
+            cpu.set_variant(self.settings.variant);
+            cpu.load_font(font, self.settings.font.address);
+            self.cpu = Some(cpu);
+        }
+
+        for key in 0..=0xF {
+            self.keyboard.set_key(key, false);
+        }
+        let cpu = self.cpu.as_mut().unwrap();
+        cpu.rng = StdRng::seed_from_u64(seed);
+        // episodes are independent, they don't share RPL flags
+        cpu.rpl = [0; 16];
+        cpu.reset(&machine, rom);
+        let frame = framebuffer(&cpu.display);
+        self.score = self.total_score(self.cpu.as_ref().unwrap());
+        Ok(frame)
+    }
+
+    /// Hold the keys in `action_mask`, bit n for key n, for `frame_skip` frames.
+    /// Returns the screen, the reward and whether the episode is over.
+    pub fn step(&mut self, action_mask: u16) -> io::Result<(Vec<u8>, f64, bool)> {
+        let cpu = self.cpu.as_mut().ok_or_else(not_started)?;
+        // keys are set in ascending order, as `InputMux` does
+        for key in 0..=0xF {
+            self.keyboard.set_key(key, action_mask & (1 << key) != 0);
+        }
+
+        let mut done = false;
+        for _ in 0..self.frame_skip {
+            let outcome = self.backend.run(cpu, self.settings.instructions_per_frame);
+            cpu.update_timers();
+            done = matches!(outcome, StepOutcome::Halted | StepOutcome::Exited)
+                || self
+                    .done_when
+                    .is_some_and(|(address, value)| cpu.heap[address] == value);
+            if done {
+                break;
+            }
+        }
+
+        let cpu = self.cpu.as_ref().ok_or_else(not_started)?;
+        let score = self.total_score(cpu);
+        let reward = (score - self.score) as f64;
+        self.score = score;
+        Ok((framebuffer(&cpu.display), reward, done))
+    }
+
+    pub fn clone_state(&self) -> io::Result<State> {
+        let cpu = self.cpu.as_ref().ok_or_else(not_started)?;
+        Ok(State {
+            registers: cpu.registers,
+            i_register: cpu.i_register,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+            program_counter: cpu.program_counter,
+            memory: cpu.heap.to_vec(),
+            stack: cpu.stack.clone(),
+            stack_pointer: cpu.stack_pointer,
+            display: cpu.display.clone(),
+            key_wait: cpu.key_wait,
+            cycles: cpu.cycles,
+            frames: cpu.frames,
+            rng: cpu.rng.clone(),
+            timer_wait: cpu.timer_wait,
+            sample: cpu.sample,
+            rpl: cpu.rpl,
+            keys: self.keyboard.pressed_mask(),
+            score: self.score,
+        })
+    }
+
+    pub fn restore_state(&mut self, state: &State) -> io::Result<()> {
+        let cpu = self.cpu.as_mut().ok_or_else(not_started)?;
+        if state.memory.len() != cpu.heap.len() || state.stack.len() != cpu.stack.len() {
+            return Err(invalid(
+                "the state is from a machine with another memory size or stack depth",
+            ));
+        }
+        cpu.registers = state.registers;
+        cpu.i_register = state.i_register;
+        cpu.delay_timer = state.delay_timer;
+        cpu.sound_timer = state.sound_timer;
+        cpu.program_counter = state.program_counter;
+        cpu.heap.load(0, &state.memory);
+        cpu.stack.copy_from_slice(&state.stack);
+        cpu.stack_pointer = state.stack_pointer;
+        cpu.display = state.display.clone();
+        cpu.key_wait = state.key_wait;
+        cpu.cycles = state.cycles;
+        cpu.frames = state.frames;
+        cpu.rng = state.rng.clone();
+        cpu.timer_wait = state.timer_wait;
+        cpu.sample = state.sample;
+        cpu.rpl = state.rpl;
+
+        // the presses happened before the state was taken, a key wait already saw them
+        for key in 0..=0xF {
+            self.keyboard.set_key(key, state.keys & (1 << key) != 0);
+        }
+        self.keyboard.take_events();
+        self.score = state.score;
+        Ok(())
+    }
+
+    /// Width and height of the screen `step` returns.
+    pub fn resolution(&self) -> (usize, usize) {
+        match &self.cpu {
+            Some(cpu) => cpu.display.resolution(),
+            None => self.settings.variant.display().resolution(),
+        }
+    }
+
+    fn total_score(&self, cpu: &CPU) -> i64 {
+        self.scores.iter().map(|score| score.read(&cpu.heap)).sum()
+    }
+}
+
+fn not_started() -> io::Error {
+    invalid("no ROM is loaded, call reset first")
+}
+
+// a byte per pixel, row by row, 1 where it is lit
+fn framebuffer(display: &Display) -> Vec<u8> {
+    if let Some(indexed) = display.indexed() {
+        // MegaChip's pixels are lit when they aren't black
+        return indexed
+            .front()
+            .iter()
+            .map(|&color| u8::from(color & 0xFF_FFFF != 0))
+            .collect();
+    }
+    display
+        .rows()
+        .iter()
+        .flat_map(|&row| (0..display.width()).map(move |x| (row << x >> 63) as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // stores V0 as BCD and shows it once a frame, counting it up while key 5 is held,
+    // and halts at 3
+    const ROM: [u8; 38] = [
+        0x60, 0x00, // 200: LD V0, 0
+        0x61, 0x05, // 202: LD V1, 5
+        0xA3, 0x00, // 204: LD I, 0x300
+        0xF0, 0x33, // 206: LD B, V0
+        0xE1, 0xA1, // 208: SKNP V1
+        0x70, 0x01, // 20A: ADD V0, 1
+        0x30, 0x03, // 20C: SE V0, 3
+        0x12, 0x12, // 20E: JP 0x212
+        0x12, 0x10, // 210: JP 0x210
+        0xC2, 0xFF, // 212: RND V2, 0xFF
+        0x00, 0xE0, // 214: CLS
+        0xF0, 0x29, // 216: LD F, V0
+        0xD4, 0x45, // 218: DRW V4, V4, 5
+        0x63, 0x01, // 21A: LD V3, 1
+        0xF3, 0x15, // 21C: LD DT, V3
+        0xF3, 0x07, // 21E: LD V3, DT
+        0x33, 0x00, // 220: SE V3, 0
+        0x12, 0x1E, // 222: JP 0x21E
+        0x12, 0x04, // 224: JP 0x204
+    ];
+
+    fn env(frame_skip: u32) -> Env {
+        let settings = Settings {
+            instructions_per_frame: 30,
+            ..Settings::default()
+        };
+        let score = Score {
+            address: 0x300,
+            len: 3,
+            format: ScoreFormat::Bcd,
+        };
+        Env::new(settings, vec![score], None, frame_skip).unwrap()
+    }
+
+    #[test]
+    fn test_steps_and_rewards() {
+        let mut env = env(1);
+        assert!(env.step(0).is_err());
+        let frame = env.reset(&ROM, 7).unwrap();
+        assert_eq!(frame.len(), 64 * 32);
+        assert!(frame.iter().all(|&pixel| pixel == 0));
+
+        // a pass of the loop per frame, the score is stored before it goes up
+        let (frame, reward, done) = env.step(0).unwrap();
+        assert_eq!((reward, done), (0.0, false));
+        assert_eq!(frame.iter().filter(|&&pixel| pixel == 1).count(), 14);
+        assert_eq!(env.step(1 << 5).unwrap().1, 0.0);
+        assert_eq!(env.step(1 << 5).unwrap().1, 1.0);
+
+        // the same presses from a saved state play out the same, RND included
+        let state = env.clone_state().unwrap();
+        let (frame, reward, done) = env.step(1 << 5).unwrap();
+        assert_eq!((reward, done), (1.0, true));
+        let registers = env.cpu.as_ref().unwrap().registers;
+        env.restore_state(&state).unwrap();
+        assert_eq!(env.step(1 << 5).unwrap(), (frame, reward, done));
+        assert_eq!(env.cpu.as_ref().unwrap().registers, registers);
+    }
+
+    #[test]
+    fn test_frame_skip_and_scores() {
+        let mut env = env(4);
+        env.reset(&ROM, 7).unwrap();
+        let (_, reward, done) = env.step(1 << 5).unwrap();
+        assert_eq!((reward, done), (2.0, true));
+
+        let score = |len, format| Score {
+            address: 0x10,
+            len,
+            format,
+        };
+        let memory = [0; 0x10]
+            .iter()
+            .chain(&[1, 2, 3])
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(score(3, ScoreFormat::Bcd).read(&memory), 123);
+        assert_eq!(score(2, ScoreFormat::Binary).read(&memory), 0x102);
+        assert!("packed".parse::<ScoreFormat>().is_err());
+
+        let settings = Settings::default();
+        let past_end = vec![score(0x1000, ScoreFormat::Binary)];
+        assert!(Env::new(settings.clone(), past_end, None, 1).is_err());
+        assert!(Env::new(settings.clone(), vec![], Some((0x1000, 0)), 1).is_err());
+        assert!(Env::new(settings, vec![], None, 0).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::invalid;
+use crate::keyboard::Keyboard;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+// a device that can hold down CHIP-8 keys
+pub trait InputSource {
+    // CHIP-8 keys currently held on this device, bit n for key n
+    // called once per frame
+    fn poll(&mut self) -> u16;
+}
+
+// feeds the keyboard from every attached source, a key is down while any source holds it
+pub struct InputMux {
+    keyboard: Arc<Keyboard>,
+    sources: Vec<Box<dyn InputSource>>,
+}
+
+impl InputMux {
+    pub fn new(keyboard: Arc<Keyboard>) -> Self {
+        InputMux {
+            keyboard,
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, source: Box<dyn InputSource>) {
+        self.sources.push(source);
+    }
+
+    pub fn poll(&mut self) {
+        let held = self
+            .sources
+            .iter_mut()
+            .fold(0, |held, source| held | source.poll());
+
+        // keys are set in ascending order so simultaneous presses queue deterministically
+        for key in 0..=0xF {
+            self.keyboard.set_key(key, held & (1 << key) != 0);
+        }
+    }
+}
+
+// keys held in the minifb window, published by the window frontend each frame
+#[derive(Clone, Default)]
+pub struct WindowInput {
+    keys: Arc<AtomicU16>,
+}
+
+impl WindowInput {
+    pub fn publish(&self, held: u16) {
+        self.keys.store(held, Ordering::Release);
+    }
+}
+
+impl InputSource for WindowInput {
+    fn poll(&mut self) -> u16 {
+        self.keys.load(Ordering::Acquire)
+    }
+}
+
+// one step of an input script
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScriptStep {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// replays key presses at fixed frames, for tests and reproducible runs
+// script files have one `<frame> <key> down|up` step per line, `#` starts a comment:
+//   # hold 5 for two frames starting at frame 30
+//   30 5 down
+//   32 5 up
+pub struct ScriptedInput {
+    steps: Vec<ScriptStep>,
+    next: usize,
+    frame: u64,
+    held: u16,
+}
+
+impl ScriptedInput {
+    pub fn new(mut steps: Vec<ScriptStep>) -> Self {
+        // stable, so steps on the same frame keep their order
+        steps.sort_by_key(|step| step.frame);
+        ScriptedInput {
+            steps,
+            next: 0,
+            frame: 0,
+            held: 0,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut steps = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = || {
+                invalid(format!(
+                    "line {}: expected `<frame> <key> down|up`",
+                    number + 1
+                ))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(error());
+            }
+
+            let frame = fields[0].parse().map_err(|_| error())?;
+            let key = u8::from_str_radix(fields[1], 16)
+                .ok()
+                .filter(|&key| key <= 0xF)
+                .ok_or_else(error)?;
+            let pressed = match fields[2] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(error()),
+            };
+
+            steps.push(ScriptStep {
+                frame,
+                key,
+                pressed,
+            });
+        }
+
+        Ok(Self::new(steps))
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self) -> u16 {
+        while let Some(step) = self.steps.get(self.next) {
+            if step.frame > self.frame {
+                break;
+            }
+            if step.pressed {
+                self.held |= 1 << step.key;
+            } else {
+                self.held &= !(1 << step.key);
+            }
+            self.next += 1;
+        }
+
+        self.frame += 1;
+        self.held
+    }
+}
+
+// `[gamepad]` config section
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+    pub enabled: bool,
+    /// evdev node to read, every connected gamepad is used when unset
+    pub device: Option<PathBuf>,
+    /// evdev button name (or HAT0_UP/DOWN/LEFT/RIGHT) to CHIP-8 key, "" unbinds a default
+    pub buttons: BTreeMap<String, String>,
+}
+
+impl GamepadConfig {
+    // d-pad on the keypad arrows, face buttons on the keys games commonly use for actions
+    pub const DEFAULT_BUTTONS: [(&'static str, u8); 14] = [
+        ("HAT0_UP", 0x2),
+        ("HAT0_DOWN", 0x8),
+        ("HAT0_LEFT", 0x4),
+        ("HAT0_RIGHT", 0x6),
+        ("BTN_DPAD_UP", 0x2),
+        ("BTN_DPAD_DOWN", 0x8),
+        ("BTN_DPAD_LEFT", 0x4),
+        ("BTN_DPAD_RIGHT", 0x6),
+        ("BTN_SOUTH", 0x5),
+        ("BTN_EAST", 0xA),
+        ("BTN_WEST", 0xB),
+        ("BTN_NORTH", 0xC),
+        ("BTN_SELECT", 0x0),
+        ("BTN_START", 0xF),
+    ];
+
+    // the default button mapping with the configured overrides applied
+    pub fn mapping(&self) -> io::Result<BTreeMap<String, u8>> {
+        let mut mapping: BTreeMap<String, u8> = Self::DEFAULT_BUTTONS
+            .iter()
+            .map(|(button, key)| (button.to_string(), *key))
+            .collect();
+
+        for (button, key) in &self.buttons {
+            if key.is_empty() {
+                mapping.remove(button);
+                continue;
+            }
+            let key = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|&key| key <= 0xF)
+                .ok_or_else(|| invalid(format!("invalid CHIP-8 key {:?} for {}", key, button)))?;
+            mapping.insert(button.clone(), key);
+        }
+
+        Ok(mapping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mux_combines_sources() {
+        let keyboard = Arc::new(Keyboard::new());
+        let window = WindowInput::default();
+        let mut mux = InputMux::new(keyboard.clone());
+        mux.add(Box::new(window.clone()));
+        mux.add(Box::new(ScriptedInput::new(vec![
+            ScriptStep {
+                frame: 0,
+                key: 0x5,
+                pressed: true,
+            },
+            ScriptStep {
+                frame: 2,
+                key: 0x5,
+                pressed: false,
+            },
+        ])));
+
+        window.publish(1 << 0x2);
+        mux.poll();
+        assert_eq!(keyboard.pressed_mask(), (1 << 0x2) | (1 << 0x5));
+
+        // the window letting go of a key the script still holds keeps it down
+        window.publish(1 << 0x5);
+        mux.poll();
+        assert_eq!(keyboard.pressed_mask(), 1 << 0x5);
+
+        window.publish(0);
+        mux.poll();
+        assert_eq!(keyboard.pressed_mask(), 0);
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let mut script = ScriptedInput::parse(
+            "
+            # frame key state
+            1 a down
+            1 5 down   # same frame
+            3 A up
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(script.poll(), 0);
+        assert_eq!(script.poll(), (1 << 0xA) | (1 << 0x5));
+        assert_eq!(script.poll(), (1 << 0xA) | (1 << 0x5));
+        assert_eq!(script.poll(), 1 << 0x5);
+        assert_eq!(script.poll(), 1 << 0x5);
+
+        assert!(ScriptedInput::parse("1 5 pressed").is_err());
+        assert!(ScriptedInput::parse("1 G down").is_err());
+    }
+
+    #[test]
+    fn test_gamepad_mapping_overrides_defaults() {
+        let mut config = GamepadConfig::default();
+        config
+            .buttons
+            .insert("BTN_SOUTH".to_string(), "6".to_string());
+        config
+            .buttons
+            .insert("BTN_START".to_string(), String::new());
+        config.buttons.insert("BTN_TL".to_string(), "e".to_string());
+
+        let mapping = config.mapping().unwrap();
+        assert_eq!(mapping["BTN_SOUTH"], 0x6);
+        assert_eq!(mapping["BTN_TL"], 0xE);
+        assert_eq!(mapping["HAT0_UP"], 0x2);
+        assert!(!mapping.contains_key("BTN_START"));
+
+        config
+            .buttons
+            .insert("BTN_TR".to_string(), "10".to_string());
+        assert!(config.mapping().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use crate::variant::{self, Extended};
+use std::fmt;
+use std::str::FromStr;
+
+/// A decoded CHIP-8 instruction. This is the one definition of the instruction set,
+/// shared by the CPU, the assembler, the disassembler and the debugging tools.
+/// Operands are named as in Cowgod's technical reference: x and y are registers,
+/// kk a byte, nnn an address and n a nibble.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00E0 - CLS
+    Cls,
+    /// 00EE - RET
+    Ret,
+    /// 0nnn - SYS addr
+    Sys(u16),
+    /// 1nnn - JP addr
+    Jp(u16),
+    /// 2nnn - CALL addr
+    Call(u16),
+    /// 3xkk - SE Vx, byte
+    SeByte(u8, u8),
+    /// 4xkk - SNE Vx, byte
+    SneByte(u8, u8),
+    /// 5xy0 - SE Vx, Vy
+    SeReg(u8, u8),
+    /// 6xkk - LD Vx, byte
+    LdByte(u8, u8),
+    /// 7xkk - ADD Vx, byte
+    AddByte(u8, u8),
+    /// 8xy0 - LD Vx, Vy
+    LdReg(u8, u8),
+    /// 8xy1 - OR Vx, Vy
+    Or(u8, u8),
+    /// 8xy2 - AND Vx, Vy
+    And(u8, u8),
+    /// 8xy3 - XOR Vx, Vy
+    Xor(u8, u8),
+    /// 8xy4 - ADD Vx, Vy
+    AddReg(u8, u8),
+    /// 8xy5 - SUB Vx, Vy
+    Sub(u8, u8),
+    /// 8xy6 - SHR Vx {, Vy}
+    Shr(u8, u8),
+    /// 8xy7 - SUBN Vx, Vy
+    Subn(u8, u8),
+    /// 8xyE - SHL Vx {, Vy}
+    Shl(u8, u8),
+    /// 9xy0 - SNE Vx, Vy
+    SneReg(u8, u8),
+    /// Annn - LD I, addr
+    LdI(u16),
+    /// Bnnn - JP V0, addr
+    JpV0(u16),
+    /// Cxkk - RND Vx, byte
+    Rnd(u8, u8),
+    /// Dxyn - DRW Vx, Vy, nibble
+    Drw(u8, u8, u8),
+    /// Ex9E - SKP Vx
+    Skp(u8),
+    /// ExA1 - SKNP Vx
+    Sknp(u8),
+    /// Fx07 - LD Vx, DT
+    LdVxDt(u8),
+    /// Fx0A - LD Vx, K
+    LdVxK(u8),
+    /// Fx15 - LD DT, Vx
+    LdDtVx(u8),
+    /// Fx18 - LD ST, Vx
+    LdStVx(u8),
+    /// Fx1E - ADD I, Vx
+    AddI(u8),
+    /// Fx29 - LD F, Vx
+    LdF(u8),
+    /// Fx33 - LD B, Vx
+    LdB(u8),
+    /// Fx55 - LD [I], Vx
+    LdIVx(u8),
+    /// Fx65 - LD Vx, [I]
+    LdVxI(u8),
+    /// An instruction only a variant of CHIP-8 has, see `Variant::decode`
+    Extended(Extended),
+}
+
+/// An opcode that isn't a CHIP-8 instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid opcode: {:04x}", self.opcode)
+    }
+}
+
+pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+    use self::Instruction::*;
+
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let kk = (opcode & 0x00FF) as u8;
+    let addr = opcode & 0x0FFF;
+
+    let instruction = match (opcode >> 12, n) {
+        (0x0, _) => match opcode {
+            0x00E0 => Cls,
+            0x00EE => Ret,
+            _ => Sys(addr),
+        },
+        (0x1, _) => Jp(addr),
+        (0x2, _) => Call(addr),
+        (0x3, _) => SeByte(x, kk),
+        (0x4, _) => SneByte(x, kk),
+        (0x5, 0x0) => SeReg(x, y),
+        (0x6, _) => LdByte(x, kk),
+        (0x7, _) => AddByte(x, kk),
+        (0x8, 0x0) => LdReg(x, y),
+        (0x8, 0x1) => Or(x, y),
+        (0x8, 0x2) => And(x, y),
+        (0x8, 0x3) => Xor(x, y),
+        (0x8, 0x4) => AddReg(x, y),
+        (0x8, 0x5) => Sub(x, y),
+        (0x8, 0x6) => Shr(x, y),
+        (0x8, 0x7) => Subn(x, y),
+        (0x8, 0xE) => Shl(x, y),
+        (0x9, 0x0) => SneReg(x, y),
+        (0xA, _) => LdI(addr),
+        (0xB, _) => JpV0(addr),
+        (0xC, _) => Rnd(x, kk),
+        (0xD, _) => Drw(x, y, n),
+        (0xE, _) => match kk {
+            0x9E => Skp(x),
+            0xA1 => Sknp(x),
+            _ => return Err(DecodeError { opcode }),
+        },
+        (0xF, _) => match kk {
+            0x07 => LdVxDt(x),
+            0x0A => LdVxK(x),
+            0x15 => LdDtVx(x),
+            0x18 => LdStVx(x),
+            0x1E => AddI(x),
+            0x29 => LdF(x),
+            0x33 => LdB(x),
+            0x55 => LdIVx(x),
+            0x65 => LdVxI(x),
+            _ => return Err(DecodeError { opcode }),
+        },
+        _ => return Err(DecodeError { opcode }),
+    };
+    Ok(instruction)
+}
+
+impl Instruction {
+    pub fn encode(self) -> u16 {
+        use self::Instruction::*;
+
+        let xy = |prefix: u16, x: u8, y: u8, n: u16| prefix | (x as u16) << 8 | (y as u16) << 4 | n;
+        let xkk = |prefix: u16, x: u8, kk: u8| prefix | (x as u16) << 8 | kk as u16;
+        match self {
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Sys(addr) => addr & 0x0FFF,
+            Jp(addr) => 0x1000 | addr & 0x0FFF,
+            Call(addr) => 0x2000 | addr & 0x0FFF,
+            SeByte(x, kk) => xkk(0x3000, x, kk),
+            SneByte(x, kk) => xkk(0x4000, x, kk),
+            SeReg(x, y) => xy(0x5000, x, y, 0x0),
+            LdByte(x, kk) => xkk(0x6000, x, kk),
+            AddByte(x, kk) => xkk(0x7000, x, kk),
+            LdReg(x, y) => xy(0x8000, x, y, 0x0),
+            Or(x, y) => xy(0x8000, x, y, 0x1),
+            And(x, y) => xy(0x8000, x, y, 0x2),
+            Xor(x, y) => xy(0x8000, x, y, 0x3),
+            AddReg(x, y) => xy(0x8000, x, y, 0x4),
+            Sub(x, y) => xy(0x8000, x, y, 0x5),
+            Shr(x, y) => xy(0x8000, x, y, 0x6),
+            Subn(x, y) => xy(0x8000, x, y, 0x7),
+            Shl(x, y) => xy(0x8000, x, y, 0xE),
+            SneReg(x, y) => xy(0x9000, x, y, 0x0),
+            LdI(addr) => 0xA000 | addr & 0x0FFF,
+            JpV0(addr) => 0xB000 | addr & 0x0FFF,
+            Rnd(x, kk) => xkk(0xC000, x, kk),
+            Drw(x, y, n) => xy(0xD000, x, y, n as u16 & 0xF),
+            Skp(x) => xkk(0xE000, x, 0x9E),
+            Sknp(x) => xkk(0xE000, x, 0xA1),
+            LdVxDt(x) => xkk(0xF000, x, 0x07),
+            LdVxK(x) => xkk(0xF000, x, 0x0A),
+            LdDtVx(x) => xkk(0xF000, x, 0x15),
+            LdStVx(x) => xkk(0xF000, x, 0x18),
+            AddI(x) => xkk(0xF000, x, 0x1E),
+            LdF(x) => xkk(0xF000, x, 0x29),
+            LdB(x) => xkk(0xF000, x, 0x33),
+            LdIVx(x) => xkk(0xF000, x, 0x55),
+            LdVxI(x) => xkk(0xF000, x, 0x65),
+            Extended(extended) => extended.encode(),
+        }
+    }
+}
+
+// Cowgod's syntax, which is also what the assembler accepts
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        match *self {
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Sys(addr) => write!(f, "SYS 0x{:03X}", addr),
+            Jp(addr) => write!(f, "JP 0x{:03X}", addr),
+            Call(addr) => write!(f, "CALL 0x{:03X}", addr),
+            SeByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            SneByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(addr) => write!(f, "LD I, 0x{:03X}", addr),
+            JpV0(addr) => write!(f, "JP V0, 0x{:03X}", addr),
+            Rnd(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            LdF(x) => write!(f, "LD F, V{:X}", x),
+            LdB(x) => write!(f, "LD B, V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Extended(extended) => extended.fmt(f),
+        }
+    }
+}
+
+// `V0`-`VF`
+fn register(operand: &str) -> Option<u8> {
+    let digit = operand.strip_prefix('V')?;
+    u8::from_str_radix(digit, 16)
+        .ok()
+        .filter(|_| digit.len() == 1)
+}
+
+// `0x2A`, `#2A` or `$2A` in hex, anything else in decimal
+fn number(operand: &str, max: u16) -> Option<u16> {
+    let value = match operand
+        .strip_prefix("0X")
+        .or_else(|| operand.strip_prefix('#'))
+        .or_else(|| operand.strip_prefix('$'))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+        None => operand.parse().ok()?,
+    };
+    Some(value).filter(|&value| value <= max)
+}
+
+impl FromStr for Instruction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::Instruction::*;
+
+        let s = s.trim().to_ascii_uppercase();
+        let (mnemonic, operands) = s.split_once(char::is_whitespace).unwrap_or((&s, ""));
+        let operands: Vec<&str> = operands
+            .split(',')
+            .map(str::trim)
+            .filter(|operand| !operand.is_empty())
+            .collect();
+
+        let addr = |operand: &str| number(operand, 0xFFF);
+        let byte = |operand: &str| number(operand, 0xFF).map(|value| value as u8);
+        let nibble = |operand: &str| number(operand, 0xF).map(|value| value as u8);
+
+        // the first form whose operands all parse
+        let instruction = match (mnemonic, &operands[..]) {
+            ("CLS", []) => Some(Cls),
+            ("RET", []) => Some(Ret),
+            ("EXIT", []) => Some(Extended(variant::Extended::Exit)),
+            ("SYS", [a]) => addr(a).map(Sys),
+            ("JP", ["V0", a]) => addr(a).map(JpV0),
+            ("JP", [a]) => addr(a).map(Jp),
+            ("CALL", [a]) => addr(a).map(Call),
+            ("SE", [x, y]) => register(x).and_then(|x| match register(y) {
+                Some(y) => Some(SeReg(x, y)),
+                None => byte(y).map(|kk| SeByte(x, kk)),
+            }),
+            ("SNE", [x, y]) => register(x).and_then(|x| match register(y) {
+                Some(y) => Some(SneReg(x, y)),
+                None => byte(y).map(|kk| SneByte(x, kk)),
+            }),
+            ("LD", ["I", a]) => addr(a).map(LdI),
+            ("LD", ["DT", x]) => register(x).map(LdDtVx),
+            ("LD", ["ST", x]) => register(x).map(LdStVx),
+            ("LD", ["F", x]) => register(x).map(LdF),
+            ("LD", ["HF", x]) => register(x).map(|x| Extended(variant::Extended::LdHf(x))),
+            ("LD", ["B", x]) => register(x).map(LdB),
+            ("LD", ["[I]", x]) => register(x).map(LdIVx),
+            ("LD", ["R", x]) => register(x).map(|x| Extended(variant::Extended::LdRVx(x))),
+            ("LD", [x, "DT"]) => register(x).map(LdVxDt),
+            ("LD", [x, "K"]) => register(x).map(LdVxK),
+            ("LD", [x, "[I]"]) => register(x).map(LdVxI),
+            ("LD", [x, "R"]) => register(x).map(|x| Extended(variant::Extended::LdVxR(x))),
+            ("LD", [x, y]) => register(x).and_then(|x| match register(y) {
+                Some(y) => Some(LdReg(x, y)),
+                None => byte(y).map(|kk| LdByte(x, kk)),
+            }),
+            ("ADD", ["I", x]) => register(x).map(AddI),
+            ("ADD", [x, y]) => register(x).and_then(|x| match register(y) {
+                Some(y) => Some(AddReg(x, y)),
+                None => byte(y).map(|kk| AddByte(x, kk)),
+            }),
+            ("OR", [x, y]) => register(x).zip(register(y)).map(|(x, y)| Or(x, y)),
+            ("AND", [x, y]) => register(x).zip(register(y)).map(|(x, y)| And(x, y)),
+            ("XOR", [x, y]) => register(x).zip(register(y)).map(|(x, y)| Xor(x, y)),
+            ("SUB", [x, y]) => register(x).zip(register(y)).map(|(x, y)| Sub(x, y)),
+            ("SUBN", [x, y]) => register(x).zip(register(y)).map(|(x, y)| Subn(x, y)),
+            ("SHR", [x]) => register(x).map(|x| Shr(x, x)),
+            ("SHR", [x, y]) => register(x).zip(register(y)).map(|(x, y)| Shr(x, y)),
+            ("SHL", [x]) => register(x).map(|x| Shl(x, x)),
+            ("SHL", [x, y]) => register(x).zip(register(y)).map(|(x, y)| Shl(x, y)),
+            ("RND", [x, kk]) => register(x).zip(byte(kk)).map(|(x, kk)| Rnd(x, kk)),
+            ("DRW", [x, y, n]) => register(x)
+                .zip(register(y))
+                .zip(nibble(n))
+                .map(|((x, y), n)| Drw(x, y, n)),
+            ("SKP", [x]) => register(x).map(Skp),
+            ("SKNP", [x]) => register(x).map(Sknp),
+            _ => None,
+        };
+        instruction.ok_or_else(|| format!("invalid instruction {:?}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x00E0), Ok(Instruction::Cls));
+        assert_eq!(decode(0x5120), Ok(Instruction::SeReg(1, 2)));
+        assert_eq!(decode(0x5FF0), Ok(Instruction::SeReg(0xF, 0xF)));
+        assert_eq!(decode(0x9AB0), Ok(Instruction::SneReg(0xA, 0xB)));
+        assert_eq!(decode(0xD125), Ok(Instruction::Drw(1, 2, 5)));
+
+        // 5xy0 and 9xy0 need a zero low nibble
+        for opcode in [0x5121, 0x512F, 0x9AB1, 0x9FFF, 0x8008, 0xE1FF, 0xF0FF] {
+            assert_eq!(decode(opcode), Err(DecodeError { opcode }));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // every valid opcode encodes back to itself and reassembles from its disassembly
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = decode(opcode) {
+                assert_eq!(instruction.encode(), opcode);
+                assert_eq!(instruction.to_string().parse(), Ok(instruction));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("ld v1, 3".parse(), Ok(Instruction::LdByte(1, 3)));
+        assert_eq!("ADD I, VA".parse(), Ok(Instruction::AddI(0xA)));
+        assert_eq!("SHR V2".parse(), Ok(Instruction::Shr(2, 2)));
+        assert_eq!("JP V0, #300".parse(), Ok(Instruction::JpV0(0x300)));
+        assert_eq!(
+            "LD R, V7".parse(),
+            Ok(Instruction::Extended(Extended::LdRVx(7)))
+        );
+        assert_eq!(
+            "LD HF, VA".parse(),
+            Ok(Instruction::Extended(Extended::LdHf(0xA)))
+        );
+        assert_eq!("EXIT".parse(), Ok(Instruction::Extended(Extended::Exit)));
+        assert!("LD V1, 256".parse::<Instruction>().is_err());
+        assert!("DRW V1, V2".parse::<Instruction>().is_err());
+        assert!("MOV V1, V2".parse::<Instruction>().is_err());
+    }
+}
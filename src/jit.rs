@@ -0,0 +1,621 @@
+use crate::config::invalid;
+use crate::cpu::{Quirks, CPU};
+use crate::instruction::Instruction;
+use crate::variant::{self, Variant};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, FuncRef, InstBuilder, MemFlags, Type, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::io;
+use std::mem::{self, offset_of};
+
+// times a block has to start before it gets compiled
+const HOT: u32 = 8;
+// instructions in a block at most
+const MAX_BLOCK: usize = 64;
+// invalidated blocks whose code is still allocated, before the whole module is thrown away
+const MAX_STALE: usize = 1024;
+
+// a compiled block takes the CPU and returns the instructions it ran << 32 | the next PC
+type BlockFn = unsafe extern "C" fn(*mut CPU) -> u64;
+
+// what the JIT knows about the code starting at an address
+#[derive(Clone, Copy)]
+enum Entry {
+    // not compiled yet, and how many times a block started here
+    Cold(u32),
+    Compiled(Block),
+    // starts with an invalid opcode
+    Uncompilable,
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    code: BlockFn,
+    // one past the last byte the block was compiled from
+    end: usize,
+    len: u32,
+}
+
+// does the block end after this instruction
+fn ends_block(instruction: Instruction) -> bool {
+    use crate::instruction::Instruction::*;
+    if let Extended(variant::Extended::LdRVx(_) | variant::Extended::LdVxR(_)) = instruction {
+        return false;
+    }
+    matches!(
+        instruction,
+        Sys(_)
+            | Jp(_)
+            | Call(_)
+            | Ret
+            | JpV0(_)
+            | SeByte(..)
+            | SneByte(..)
+            | SeReg(..)
+            | SneReg(..)
+            | Skp(_)
+            | Sknp(_)
+            | Drw(..)
+            | LdVxK(_)
+            | Extended(_)
+    )
+}
+
+// compiled code calls back into the interpreter for instructions that touch memory,
+// the display, the keyboard or the stack, returns nonzero when code was overwritten
+extern "C" fn execute(cpu: *mut CPU, addr: u32, opcode: u32) -> u32 {
+    let cpu = unsafe { &mut *cpu };
+    cpu.heap.fetch(addr as usize);
+    if let Ok(instruction) = cpu.heap.variant().decode(opcode as u16) {
+        cpu.execute(instruction);
+    }
+    cpu.heap.has_code_writes() as u32
+}
+
+/// Compiles hot basic blocks of CHIP-8 code to native code with Cranelift and runs
+/// them in place of the interpreter. Register arithmetic, I, the timers, the RPL
+/// flags, jumps and register skips are compiled, everything else calls back into
+/// `CPU::execute`. Writes to code a block was compiled from throw the block away.
+pub struct Jit {
+    module: JITModule,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    helper: FuncId,
+    // by address
+    entries: Vec<Entry>,
+    // the quirks and instruction set blocks were compiled for
+    quirks: Quirks,
+    variant: Variant,
+    stale: usize,
+}
+
+impl Jit {
+    pub fn new() -> io::Result<Self> {
+        let (module, helper) = Self::module()?;
+        Ok(Jit {
+            context: module.make_context(),
+            module,
+            builder_context: FunctionBuilderContext::new(),
+            helper,
+            entries: Vec::new(),
+            quirks: Quirks::default(),
+            variant: Variant::Chip8,
+            stale: 0,
+        })
+    }
+
+    fn module() -> io::Result<(JITModule, FuncId)> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(invalid)?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| invalid(format!("the JIT doesn't support this host: {}", e)))?
+            .finish(settings::Flags::new(flags))
+            .map_err(invalid)?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("chip8_execute", execute as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let mut signature = module.make_signature();
+        signature
+            .params
+            .push(AbiParam::new(module.target_config().pointer_type()));
+        signature.params.push(AbiParam::new(types::I32));
+        signature.params.push(AbiParam::new(types::I32));
+        signature.returns.push(AbiParam::new(types::I32));
+        let helper = module
+            .declare_function("chip8_execute", Linkage::Import, &signature)
+            .map_err(invalid)?;
+        Ok((module, helper))
+    }
+
+    /// run exactly `budget` instructions
+    pub fn run(&mut self, cpu: &mut CPU, budget: u32) {
+        let mut remaining = budget;
+        while remaining > 0 {
+            remaining -= self.step(cpu, remaining);
+        }
+    }
+
+    /// run the block at the PC if it is compiled and fits in `budget`, or interpret
+    /// one instruction, returns how many instructions ran
+    pub fn step(&mut self, cpu: &mut CPU, budget: u32) -> u32 {
+        self.sync(cpu);
+
+        // tracing and watchpoints need to see every instruction
+        if cpu.tracer.is_some() || !cpu.heap.watchpoints.is_empty() {
+            cpu.tick();
+            return 1;
+        }
+
+        let pc = cpu.program_counter;
+        match self.entries.get_mut(pc) {
+            Some(Entry::Compiled(block)) if block.len <= budget => {
+                let result = unsafe { (block.code)(cpu as *mut CPU) };
+                let count = (result >> 32) as u32;
+                cpu.program_counter = (result & 0xFFFF_FFFF) as usize;
+                cpu.cycles += count as u64;
+                return count;
+            }
+            Some(Entry::Cold(heat)) if *heat + 1 >= HOT => {
+                self.entries[pc] = match self.compile(cpu, pc) {
+                    Some(block) => Entry::Compiled(block),
+                    None => Entry::Uncompilable,
+                };
+            }
+            Some(Entry::Cold(heat)) => *heat += 1,
+            _ => (),
+        }
+
+        cpu.tick();
+        1
+    }
+
+    // drop blocks that no longer match the CPU's code, quirks or variant
+    fn sync(&mut self, cpu: &mut CPU) {
+        // blocks are only invalidated through the decode cache, so it has to be on
+        let attached = cpu.heap.tracks_code_writes();
+        if !attached {
+            cpu.heap.set_decode_cache(true);
+            cpu.heap.track_code_writes(true);
+        }
+        if !attached
+            || cpu.quirks != self.quirks
+            || cpu.heap.variant() != self.variant
+            || self.entries.len() != cpu.heap.code_len()
+        {
+            self.quirks = cpu.quirks;
+            self.variant = cpu.heap.variant();
+            self.flush();
+            self.entries = vec![Entry::Cold(0); cpu.heap.code_len()];
+        }
+
+        for addr in cpu.heap.take_code_writes() {
+            // blocks are at most MAX_BLOCK instructions long, so only those starting
+            // shortly before `addr` can cover it
+            for start in addr.saturating_sub(MAX_BLOCK * 2)..=addr {
+                let end = match self.entries[start] {
+                    Entry::Compiled(block) => block.end,
+                    Entry::Uncompilable => start + 2,
+                    Entry::Cold(_) => continue,
+                };
+                if end > addr {
+                    if let Entry::Compiled(_) = self.entries[start] {
+                        self.stale += 1;
+                    }
+                    self.entries[start] = Entry::Cold(0);
+                }
+            }
+        }
+        if self.stale > MAX_STALE {
+            self.flush();
+        }
+    }
+
+    // throw away every block and the memory their code lives in
+    fn flush(&mut self) {
+        self.entries.fill(Entry::Cold(0));
+        self.stale = 0;
+        if let Ok((module, helper)) = Self::module() {
+            let old = mem::replace(&mut self.module, module);
+            // no block from the old module is reachable any more
+            unsafe { old.free_memory() };
+            self.helper = helper;
+        }
+    }
+
+    fn compile(&mut self, cpu: &mut CPU, start: usize) -> Option<Block> {
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        while instructions.len() < MAX_BLOCK && addr + 1 < cpu.heap.code_len() {
+            // decoding through the cache is what lets writes find this block again
+            let instruction = match cpu.heap.decode(addr) {
+                Ok(instruction) => instruction,
+                Err(_) => break,
+            };
+            instructions.push((addr, instruction));
+            addr += 2;
+            if ends_block(instruction) {
+                break;
+            }
+        }
+        if instructions.is_empty() {
+            return None;
+        }
+
+        self.module.clear_context(&mut self.context);
+        let pointer = self.module.target_config().pointer_type();
+        let signature = &mut self.context.func.signature;
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I64));
+        let helper = self
+            .module
+            .declare_func_in_func(self.helper, &mut self.context.func);
+
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let mut emitter = Emitter {
+            cpu: builder.block_params(entry)[0],
+            builder,
+            pointer,
+            helper,
+            quirks: self.quirks,
+        };
+        for (count, &(addr, instruction)) in instructions.iter().enumerate() {
+            emitter.emit(addr, instruction, count as u32 + 1);
+        }
+        if !ends_block(instructions.last().unwrap().1) {
+            emitter.exit(instructions.len() as u32, addr);
+        }
+        emitter.builder.finalize();
+
+        let id = self
+            .module
+            .declare_anonymous_function(&self.context.func.signature)
+            .ok()?;
+        self.module.define_function(id, &mut self.context).ok()?;
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        Some(Block {
+            code: unsafe { mem::transmute::<*const u8, BlockFn>(code) },
+            end: addr,
+            len: instructions.len() as u32,
+        })
+    }
+}
+
+// builds the native code for one block
+struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    cpu: Value,
+    pointer: Type,
+    helper: FuncRef,
+    quirks: Quirks,
+}
+
+impl<'a> Emitter<'a> {
+    fn load(&mut self, ty: Type, offset: usize) -> Value {
+        self.builder
+            .ins()
+            .load(ty, MemFlags::trusted(), self.cpu, offset as i32)
+    }
+
+    fn store(&mut self, value: Value, offset: usize) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.cpu, offset as i32);
+    }
+
+    fn v(&mut self, x: u8) -> Value {
+        self.load(types::I8, offset_of!(CPU, registers) + x as usize)
+    }
+
+    fn set_v(&mut self, x: u8, value: Value) {
+        self.store(value, offset_of!(CPU, registers) + x as usize);
+    }
+
+    fn byte(&mut self, value: u8) -> Value {
+        self.builder.ins().iconst(types::I8, value as i64)
+    }
+
+    // the block's return value
+    fn packed(&mut self, count: u32, pc: usize) -> Value {
+        let value = (count as i64) << 32 | pc as i64;
+        self.builder.ins().iconst(types::I64, value)
+    }
+
+    fn exit(&mut self, count: u32, pc: usize) {
+        let result = self.packed(count, pc);
+        self.builder.ins().return_(&[result]);
+    }
+
+    // skip the next instruction if `condition` is nonzero
+    fn skip_if(&mut self, condition: Value, count: u32, addr: usize) {
+        let skip = self.packed(count, addr + 4);
+        let next = self.packed(count, addr + 2);
+        let result = self.builder.ins().select(condition, skip, next);
+        self.builder.ins().return_(&[result]);
+    }
+
+    // run the instruction in the interpreter
+    fn call_interpreter(&mut self, addr: usize, opcode: u16) -> Value {
+        let next = self.builder.ins().iconst(self.pointer, addr as i64 + 2);
+        self.store(next, offset_of!(CPU, program_counter));
+        let addr = self.builder.ins().iconst(types::I32, addr as i64);
+        let opcode = self.builder.ins().iconst(types::I32, opcode as i64);
+        let call = self
+            .builder
+            .ins()
+            .call(self.helper, &[self.cpu, addr, opcode]);
+        self.builder.inst_results(call)[0]
+    }
+
+    // `count` is how many instructions of the block have run once this one has
+    fn emit(&mut self, addr: usize, instruction: Instruction, count: u32) {
+        use crate::instruction::Instruction::*;
+
+        // the same loads and stores, in the same order, as the interpreter's handlers
+        match instruction {
+            LdByte(x, kk) => {
+                let value = self.byte(kk);
+                self.set_v(x, value);
+            }
+            AddByte(x, kk) => {
+                let value = self.v(x);
+                let kk = self.byte(kk);
+                let sum = self.builder.ins().iadd(value, kk);
+                self.set_v(x, sum);
+            }
+            LdReg(x, y) => {
+                let value = self.v(y);
+                self.set_v(x, value);
+            }
+            Or(x, y) | And(x, y) | Xor(x, y) => {
+                let (a, b) = (self.v(x), self.v(y));
+                let value = match instruction {
+                    Or(..) => self.builder.ins().bor(a, b),
+                    And(..) => self.builder.ins().band(a, b),
+                    _ => self.builder.ins().bxor(a, b),
+                };
+                self.set_v(x, value);
+                if self.quirks.vf_reset {
+                    let zero = self.byte(0);
+                    self.set_v(0xF, zero);
+                }
+            }
+            AddReg(x, y) => {
+                let a = self.v(x);
+                let b = self.v(y);
+                let a = self.builder.ins().uextend(types::I16, a);
+                let b = self.builder.ins().uextend(types::I16, b);
+                let sum = self.builder.ins().iadd(a, b);
+                let carry = self
+                    .builder
+                    .ins()
+                    .icmp_imm(IntCC::UnsignedGreaterThan, sum, 255);
+                self.set_v(0xF, carry);
+                let sum = self.builder.ins().ireduce(types::I8, sum);
+                self.set_v(x, sum);
+            }
+            Sub(x, y) | Subn(x, y) => {
+                let (mut a, mut b) = (self.v(x), self.v(y));
+                if let Subn(..) = instruction {
+                    mem::swap(&mut a, &mut b);
+                }
+                let no_borrow = self.builder.ins().icmp(IntCC::UnsignedGreaterThan, a, b);
+                self.set_v(0xF, no_borrow);
+                let difference = self.builder.ins().isub(a, b);
+                self.set_v(x, difference);
+            }
+            Shr(x, y) | Shl(x, y) => {
+                if !self.quirks.shift_in_place {
+                    let value = self.v(y);
+                    self.set_v(x, value);
+                }
+                let value = self.v(x);
+                let flag = match instruction {
+                    Shr(..) => self.builder.ins().band_imm(value, 1),
+                    _ => self.builder.ins().ushr_imm(value, 7),
+                };
+                self.set_v(0xF, flag);
+                let value = self.v(x);
+                let shifted = match instruction {
+                    Shr(..) => self.builder.ins().ushr_imm(value, 1),
+                    _ => self.builder.ins().ishl_imm(value, 1),
+                };
+                self.set_v(x, shifted);
+            }
+            LdI(addr) => {
+                let value = self.builder.ins().iconst(types::I32, addr as i64);
+                self.store(value, offset_of!(CPU, i_register));
+            }
+            AddI(x) => {
+                let i = self.load(types::I32, offset_of!(CPU, i_register));
+                let value = self.v(x);
+                let value = self.builder.ins().uextend(types::I32, value);
+                let sum = self.builder.ins().iadd(i, value);
+                self.store(sum, offset_of!(CPU, i_register));
+            }
+            Extended(variant::Extended::LdRVx(x)) => {
+                for n in 0..=x as usize {
+                    let value = self.v(n as u8);
+                    self.store(value, offset_of!(CPU, rpl) + n);
+                }
+            }
+            Extended(variant::Extended::LdVxR(x)) => {
+                for n in 0..=x {
+                    let value = self.load(types::I8, offset_of!(CPU, rpl) + n as usize);
+                    self.set_v(n, value);
+                }
+            }
+            LdVxDt(x) => {
+                let value = self.load(types::I8, offset_of!(CPU, delay_timer));
+                self.set_v(x, value);
+            }
+            LdDtVx(x) => {
+                let value = self.v(x);
+                self.store(value, offset_of!(CPU, delay_timer));
+            }
+            LdStVx(x) => {
+                let value = self.v(x);
+                self.store(value, offset_of!(CPU, sound_timer));
+            }
+            Jp(target) => self.exit(count, target as usize),
+            SeByte(x, kk) | SneByte(x, kk) => {
+                let value = self.v(x);
+                let kk = self.byte(kk);
+                let condition = match instruction {
+                    SeByte(..) => IntCC::Equal,
+                    _ => IntCC::NotEqual,
+                };
+                let skip = self.builder.ins().icmp(condition, value, kk);
+                self.skip_if(skip, count, addr);
+            }
+            SeReg(x, y) | SneReg(x, y) => {
+                let (a, b) = (self.v(x), self.v(y));
+                let condition = match instruction {
+                    SeReg(..) => IntCC::Equal,
+                    _ => IntCC::NotEqual,
+                };
+                let skip = self.builder.ins().icmp(condition, a, b);
+                self.skip_if(skip, count, addr);
+            }
+            // ends the block, wherever the interpreter left the PC is where we go. 0nnn
+            // machine code and the variants' instructions can move it too
+            Sys(_) | Call(_) | Ret | JpV0(_) | Skp(_) | Sknp(_) | Drw(..) | LdVxK(_)
+            | Extended(_) => {
+                self.call_interpreter(addr, instruction.encode());
+                let pc = self.load(self.pointer, offset_of!(CPU, program_counter));
+                let pc = if self.pointer == types::I64 {
+                    pc
+                } else {
+                    self.builder.ins().uextend(types::I64, pc)
+                };
+                let result = self.builder.ins().bor_imm(pc, (count as i64) << 32);
+                self.builder.ins().return_(&[result]);
+            }
+            // stop early if the instruction overwrote code, it may have been this block
+            Cls | Rnd(..) | LdF(_) | LdB(_) | LdIVx(_) | LdVxI(_) => {
+                let modified = self.call_interpreter(addr, instruction.encode());
+                let exit = self.builder.create_block();
+                let next = self.builder.create_block();
+                self.builder.ins().brif(modified, exit, &[], next, &[]);
+                self.builder.seal_block(exit);
+                self.builder.seal_block(next);
+
+                self.builder.switch_to_block(exit);
+                self.exit(count, addr + 2);
+                self.builder.switch_to_block(next);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+
+    #[test]
+    fn test_matches_interpreter() {
+        // a loop over most of the compiled instructions, with ADD, SUB and SUBN carrying
+        // and borrowing on some passes
+        let program = [
+            0x70, 0x11, // 200: ADD V0, 0x11
+            0x67, 0x3F, // LD V7, 0x3F
+            0x87, 0x02, // AND V7, V0
+            0x81, 0x00, // LD V1, V0
+            0x81, 0x04, // ADD V1, V0
+            0x68, 0xC0, // LD V8, 0xC0
+            0x88, 0x14, // ADD V8, V1
+            0x62, 0x80, // LD V2, 0x80
+            0x82, 0x15, // SUB V2, V1
+            0x63, 0x40, // LD V3, 0x40
+            0x83, 0x17, // SUBN V3, V1
+            0x84, 0x16, // SHR V4, V1
+            0x85, 0x2E, // SHL V5, V2
+            0x86, 0x13, // XOR V6, V1
+            0xF8, 0x75, // LD R, V8
+            0xF3, 0x85, // LD V3, R
+            0xA3, 0x00, // LD I, 0x300
+            0xF6, 0x1E, // ADD I, V6
+            0xF1, 0x33, // LD B, V1
+            0xF0, 0x55, // LD [I], V0
+            0x30, 0x00, // SE V0, 0
+            0x12, 0x00, // JP 0x200
+            0x12, 0x2C, // 22C: JP 0x22C
+        ];
+        for quirk in [true, false] {
+            let mut interpreted = cpu_with_program(&program);
+            let mut compiled = cpu_with_program(&program);
+            // for the RPL flags
+            interpreted.set_variant(Variant::Schip);
+            compiled.set_variant(Variant::Schip);
+            interpreted.quirks.shift_in_place = quirk;
+            interpreted.quirks.vf_reset = quirk;
+            compiled.quirks = interpreted.quirks;
+
+            let mut jit = Jit::new().unwrap();
+            for _ in 0..100 {
+                for _ in 0..200 {
+                    interpreted.tick();
+                }
+                jit.run(&mut compiled, 200);
+                assert_eq!(compiled.registers, interpreted.registers);
+                assert_eq!(compiled.i_register, interpreted.i_register);
+                assert_eq!(compiled.rpl, interpreted.rpl);
+                assert_eq!(compiled.program_counter, interpreted.program_counter);
+                assert_eq!(compiled.cycles, interpreted.cycles);
+                assert_eq!(&compiled.heap[..], &interpreted.heap[..]);
+            }
+            assert!(jit
+                .entries
+                .iter()
+                .any(|entry| matches!(entry, Entry::Compiled(_))));
+        }
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // counts V0 up to 20 in a hot loop, then rewrites the loop's ADD V0, 1 into ADD V0, 2
+        let program = [
+            0x70, 0x01, // 200: ADD V0, 1
+            0x30, 0x14, // SE V0, 20
+            0x12, 0x00, // JP 0x200
+            0xA2, 0x01, // LD I, 0x201
+            0x60, 0x02, // LD V0, 2
+            0xF0, 0x55, // LD [I], V0
+            0x71, 0x01, // ADD V1, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        let mut interpreted = cpu_with_program(&program);
+        let mut compiled = cpu_with_program(&program);
+        let mut jit = Jit::new().unwrap();
+        for _ in 0..500 {
+            interpreted.tick();
+        }
+        jit.run(&mut compiled, 500);
+        assert_eq!(compiled.heap[0x201], 0x02);
+        assert_eq!(compiled.registers, interpreted.registers);
+        assert_eq!(compiled.program_counter, interpreted.program_counter);
+
+        // make a hot block, then overwrite it
+        let mut cpu = cpu_with_program(&[0x70, 0x01, 0x12, 0x00]);
+        jit.run(&mut cpu, 100);
+        assert!(matches!(jit.entries[0x200], Entry::Compiled(_)));
+        cpu.heap.fetch(0x300);
+        cpu.heap.write(0x201, 0x02);
+        jit.run(&mut cpu, 2);
+        assert!(matches!(jit.entries[0x200], Entry::Cold(_)));
+        let before = cpu.registers[0];
+        jit.run(&mut cpu, 100);
+        assert_eq!(cpu.registers[0], before.wrapping_add(100));
+    }
+}
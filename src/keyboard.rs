@@ -1,70 +1,128 @@
-use minifb::Key;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// oldest events are dropped once this many are waiting to be read
+const MAX_EVENTS: usize = 64;
+
+// a CHIP-8 key going down or coming back up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub pressed: bool,
+    pub time: Instant,
+}
 
 // represents the current state of all keys
 pub struct Keyboard {
-    pub keys: Arc<Mutex<HashMap<u8, bool>>>,
+    // bit n is set while key n is held, readable without locking
+    pressed: AtomicU16,
+    // presses and releases in the order they happened
+    events: Mutex<VecDeque<KeyEvent>>,
 }
 
-impl Keyboard {
-    // CHIP-8 to PC keyboard mapping
-    pub const KEYMAP: [Key; 16] = [
-        Key::X,    // 0
-        Key::Key1, // 1
-        Key::Key2, // 2
-        Key::Key3, // 3
-        Key::Q,    // 4
-        Key::W,    // 5
-        Key::E,    // 6
-        Key::A,    // 7
-        Key::S,    // 8
-        Key::D,    // 9
-        Key::Z,    // A
-        Key::C,    // B
-        Key::Key4, // C
-        Key::R,    // D
-        Key::F,    // E
-        Key::V,    // F
-    ];
+impl Default for Keyboard {
+    fn default() -> Self {
+        Keyboard::new()
+    }
+}
 
+impl Keyboard {
     pub fn new() -> Self {
-        let mut keys = HashMap::new();
-        // initialize all keys (0-F) as not pressed
-        for key in 0..=0xF {
-            keys.insert(key, false);
-        }
-
         Keyboard {
-            keys: Arc::new(Mutex::new(keys)),
+            pressed: AtomicU16::new(0),
+            events: Mutex::new(VecDeque::with_capacity(MAX_EVENTS)),
         }
     }
 
-    // set key state (pressed/released)
+    // set key state (pressed/released), queueing an event if it changed
     pub fn set_key(&self, key: u8, pressed: bool) {
-        if let Ok(mut keys) = self.keys.lock() {
-            keys.insert(key, pressed);
+        let key = key & 0xF;
+        // most calls repeat the current state, skip the lock for those
+        if self.is_key_pressed(key) == pressed {
+            return;
+        }
+
+        // update the mask under the queue lock so events stay in mask order
+        let mut events = match self.events.lock() {
+            Ok(events) => events,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let bit = 1 << key;
+        let previous = if pressed {
+            self.pressed.fetch_or(bit, Ordering::AcqRel)
+        } else {
+            self.pressed.fetch_and(!bit, Ordering::AcqRel)
+        };
+        if (previous & bit != 0) == pressed {
+            return;
+        }
+
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
         }
+        events.push_back(KeyEvent {
+            key,
+            pressed,
+            time: Instant::now(),
+        });
     }
 
     // check if a specific key is pressed
     pub fn is_key_pressed(&self, key: u8) -> bool {
-        if let Ok(keys) = self.keys.lock() {
-            *keys.get(&key).unwrap_or(&false)
-        } else {
-            false
+        self.pressed_mask() & (1 << (key & 0xF)) != 0
+    }
+
+    // all keys at once, bit n for key n
+    pub fn pressed_mask(&self) -> u16 {
+        self.pressed.load(Ordering::Acquire)
+    }
+
+    // remove and return every queued event, oldest first
+    pub fn take_events(&self) -> Vec<KeyEvent> {
+        match self.events.lock() {
+            Ok(mut events) => events.drain(..).collect(),
+            Err(poisoned) => poisoned.into_inner().drain(..).collect(),
         }
     }
+}
 
-    // wait for any key press and return its value
-    pub fn wait_for_key_press(&self) -> Option<u8> {
-        if let Ok(keys) = self.keys.lock() {
-            for (key, pressed) in keys.iter() {
-                if *pressed {
-                    return Some(*key);
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_and_events_follow_changes() {
+        let keyboard = Keyboard::new();
+        keyboard.set_key(0x5, true);
+        keyboard.set_key(0x5, true);
+        keyboard.set_key(0xA, true);
+        keyboard.set_key(0x5, false);
+
+        assert_eq!(keyboard.pressed_mask(), 1 << 0xA);
+        assert!(keyboard.is_key_pressed(0xA));
+        assert!(!keyboard.is_key_pressed(0x5));
+
+        let events: Vec<_> = keyboard
+            .take_events()
+            .iter()
+            .map(|e| (e.key, e.pressed))
+            .collect();
+        assert_eq!(events, vec![(0x5, true), (0xA, true), (0x5, false)]);
+        assert!(keyboard.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_event_queue_is_bounded() {
+        let keyboard = Keyboard::new();
+        for i in 0..MAX_EVENTS {
+            keyboard.set_key(0x1, i % 2 == 0);
         }
-        None
+        keyboard.set_key(0x2, true);
+
+        let events = keyboard.take_events();
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events.last().map(|e| e.key), Some(0x2));
     }
 }
@@ -0,0 +1,305 @@
+use minifb::Key;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// which host keys press each CHIP-8 key, indexed by CHIP-8 key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    keys: [Vec<Key>; 16],
+}
+
+// CHIP-8 keypad, left to right and top to bottom
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+const KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+// keys the window keeps for speed control, they can't press CHIP-8 keys too
+pub const HOTKEYS: [Key; 6] = [
+    Key::Tab,
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::PageUp,
+    Key::PageDown,
+];
+
+impl Keymap {
+    pub const PRESETS: [&'static str; 4] = ["qwerty", "azerty", "dvorak", "numpad"];
+
+    // build a keymap from host keys laid out in the same order as `KEYPAD`
+    fn from_grid(grid: [Key; 16]) -> Self {
+        let mut keymap = Keymap::unbound();
+        for (chip8_key, host_key) in KEYPAD.iter().zip(grid.iter()) {
+            keymap.keys[*chip8_key as usize].push(*host_key);
+        }
+        keymap
+    }
+
+    pub fn unbound() -> Self {
+        Keymap {
+            keys: Default::default(),
+        }
+    }
+
+    // the left-hand 4x4 block of a QWERTY keyboard
+    #[rustfmt::skip]
+    pub fn qwerty() -> Self {
+        Self::from_grid([
+            Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+            Key::Q, Key::W, Key::E, Key::R,
+            Key::A, Key::S, Key::D, Key::F,
+            Key::Z, Key::X, Key::C, Key::V,
+        ])
+    }
+
+    // same physical keys as `qwerty`, named as on an AZERTY keyboard
+    #[rustfmt::skip]
+    pub fn azerty() -> Self {
+        Self::from_grid([
+            Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+            Key::A, Key::Z, Key::E, Key::R,
+            Key::Q, Key::S, Key::D, Key::F,
+            Key::W, Key::X, Key::C, Key::V,
+        ])
+    }
+
+    // same physical keys as `qwerty`, named as on a Dvorak keyboard
+    #[rustfmt::skip]
+    pub fn dvorak() -> Self {
+        Self::from_grid([
+            Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+            Key::Apostrophe, Key::Comma, Key::Period, Key::P,
+            Key::A, Key::O, Key::E, Key::U,
+            Key::Semicolon, Key::Q, Key::J, Key::K,
+        ])
+    }
+
+    // keypad digits on the matching numpad positions (so 8 is up and 2 is down, as
+    // in most games), with the arrow keys doubling up for 2/4/6/8
+    #[rustfmt::skip]
+    pub fn numpad() -> Self {
+        let mut keymap = Self::from_grid([
+            Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadAsterisk,
+            Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPadMinus,
+            Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPadPlus,
+            Key::NumPad0, Key::NumPadDot, Key::NumPadSlash, Key::NumPadEnter,
+        ]);
+        keymap.keys[0x2].push(Key::Up);
+        keymap.keys[0x4].push(Key::Left);
+        keymap.keys[0x6].push(Key::Right);
+        keymap.keys[0x8].push(Key::Down);
+        keymap
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "qwerty" => Some(Self::qwerty()),
+            "azerty" => Some(Self::azerty()),
+            "dvorak" => Some(Self::dvorak()),
+            "numpad" => Some(Self::numpad()),
+            _ => None,
+        }
+    }
+
+    // replace the host keys bound to a CHIP-8 key, an empty list unbinds it
+    pub fn bind(&mut self, chip8_key: u8, host_keys: Vec<Key>) {
+        self.keys[chip8_key as usize] = host_keys;
+    }
+
+    pub fn host_keys(&self, chip8_key: u8) -> &[Key] {
+        &self.keys[chip8_key as usize]
+    }
+
+    // state of every CHIP-8 key given a way to ask whether a host key is down
+    pub fn pressed<F: Fn(Key) -> bool>(&self, is_down: F) -> [bool; 16] {
+        let mut pressed = [false; 16];
+        for chip8_key in 0..=0xF {
+            pressed[chip8_key as usize] = self.host_keys(chip8_key).iter().any(|key| is_down(*key));
+        }
+        pressed
+    }
+}
+
+// `[keymap]` config section: a preset plus per-key overrides, e.g.
+//   layout = "azerty"
+//   5 = ["Up", "W"]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct KeymapConfig {
+    pub layout: String,
+    #[serde(flatten)]
+    pub bindings: BTreeMap<String, HostKeys>,
+}
+
+impl Default for KeymapConfig {
+    fn default() -> Self {
+        KeymapConfig {
+            layout: "qwerty".to_string(),
+            bindings: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum HostKeys {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeymapConfig {
+    pub fn build(&self) -> Result<Keymap, String> {
+        let mut keymap = Keymap::preset(&self.layout).ok_or_else(|| {
+            format!(
+                "unknown keymap layout {:?}, expected one of {}",
+                self.layout,
+                Keymap::PRESETS.join(", ")
+            )
+        })?;
+
+        for (chip8_key, host_keys) in &self.bindings {
+            let chip8_key = u8::from_str_radix(chip8_key, 16)
+                .ok()
+                .filter(|&k| k <= 0xF)
+                .ok_or_else(|| format!("invalid CHIP-8 key {:?}", chip8_key))?;
+
+            let names = match host_keys {
+                HostKeys::One(name) => vec![name.as_str()],
+                HostKeys::Many(names) => names.iter().map(String::as_str).collect(),
+            };
+            let host_keys = names
+                .into_iter()
+                .map(|name| parse_key(name).ok_or_else(|| format!("unknown host key {:?}", name)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(key) = host_keys.iter().find(|key| HOTKEYS.contains(key)) {
+                return Err(format!(
+                    "{:?} is a speed hotkey and can't be bound to a CHIP-8 key",
+                    key
+                ));
+            }
+
+            keymap.bind(chip8_key, host_keys);
+        }
+
+        Ok(keymap)
+    }
+}
+
+// every host key that can be named in a keymap
+#[rustfmt::skip]
+const HOST_KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7,
+    Key::Key8, Key::Key9, Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H,
+    Key::I, Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S,
+    Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z, Key::F1, Key::F2, Key::F3,
+    Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::F13, Key::F14, Key::F15, Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon,
+    Key::Slash, Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home,
+    Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock, Key::LeftShift, Key::RightShift,
+    Key::LeftCtrl, Key::RightCtrl, Key::NumPad0, Key::NumPad1, Key::NumPad2,
+    Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8,
+    Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk,
+    Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter, Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+// look up a host key by its minifb name ("W", "Key1", "NumPad8"), ignoring case
+// a bare digit is accepted as shorthand for the number row key
+pub fn parse_key(name: &str) -> Option<Key> {
+    let name = name.trim();
+    let name = if name.len() == 1 && name.as_bytes()[0].is_ascii_digit() {
+        format!("Key{}", name)
+    } else {
+        name.to_string()
+    };
+
+    HOST_KEYS
+        .iter()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(&name))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_cover_every_key() {
+        for name in Keymap::PRESETS.iter() {
+            let keymap = Keymap::preset(name).unwrap();
+            for chip8_key in 0..=0xF {
+                assert!(
+                    !keymap.host_keys(chip8_key).is_empty(),
+                    "{} {:X}",
+                    name,
+                    chip8_key
+                );
+            }
+        }
+        assert_eq!(Keymap::qwerty().host_keys(0x0), &[Key::X]);
+        assert_eq!(Keymap::azerty().host_keys(0x4), &[Key::A]);
+        assert_eq!(Keymap::numpad().host_keys(0x2), &[Key::NumPad8, Key::Up]);
+    }
+
+    #[test]
+    fn test_config_overrides_preset() {
+        let mut bindings = BTreeMap::new();
+        bindings.insert(
+            "5".to_string(),
+            HostKeys::Many(vec!["Space".to_string(), "NumPad5".to_string()]),
+        );
+        bindings.insert("a".to_string(), HostKeys::One("Backquote".to_string()));
+        bindings.insert("F".to_string(), HostKeys::Many(Vec::new()));
+
+        let keymap = KeymapConfig {
+            layout: "dvorak".to_string(),
+            bindings,
+        }
+        .build()
+        .unwrap();
+
+        assert_eq!(keymap.host_keys(0x5), &[Key::Space, Key::NumPad5]);
+        assert_eq!(keymap.host_keys(0xA), &[Key::Backquote]);
+        assert!(keymap.host_keys(0xF).is_empty());
+        assert_eq!(keymap.host_keys(0x6), &[Key::Period]);
+
+        let pressed = keymap.pressed(|key| key == Key::NumPad5 || key == Key::Period);
+        assert!(pressed[0x5] && pressed[0x6] && !pressed[0x4]);
+    }
+
+    #[test]
+    fn test_hotkeys_cant_be_bound() {
+        for name in ["Tab", "F2", "PageDown"] {
+            let mut bindings = BTreeMap::new();
+            bindings.insert("a".to_string(), HostKeys::One(name.to_string()));
+            let error = KeymapConfig {
+                layout: "qwerty".to_string(),
+                bindings,
+            }
+            .build()
+            .unwrap_err();
+            assert!(error.contains("speed hotkey"), "{}", error);
+        }
+        for name in Keymap::PRESETS.iter() {
+            let keymap = Keymap::preset(name).unwrap();
+            assert!((0..=0xF).all(|key| !keymap
+                .host_keys(key)
+                .iter()
+                .any(|key| HOTKEYS.contains(key))));
+        }
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("w"), Some(Key::W));
+        assert_eq!(parse_key("1"), Some(Key::Key1));
+        assert_eq!(parse_key("numpad8"), Some(Key::NumPad8));
+        assert_eq!(parse_key("Hyper"), None);
+    }
+}
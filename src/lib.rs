@@ -0,0 +1,75 @@
+//! The emulator. The `chip8-cpu-emulator` binary is a command line on top of it,
+//! and with `--features python` the same library is a Python module.
+
+pub mod audio;
+pub mod backend;
+pub mod bench;
+pub mod cdp1802;
+pub mod cdp1861;
+pub mod cli;
+pub mod config;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod diff_run;
+pub mod disasm;
+pub mod display;
+pub mod font;
+pub mod frontend;
+#[cfg(all(feature = "gamepad", target_os = "linux"))]
+pub mod gamepad;
+pub mod gdb;
+pub mod gym;
+pub mod input;
+pub mod instruction;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod keyboard;
+pub mod keymap;
+pub mod machine;
+pub mod megachip;
+pub mod memory;
+#[cfg(feature = "python")]
+mod python;
+pub mod recompile;
+// written by `recompile`, build.rs picks which one (CHIP8_RECOMPILED or the fixture)
+#[cfg(feature = "recompiled")]
+pub mod recompiled {
+    include!(env!("CHIP8_RECOMPILED_MODULE"));
+}
+pub mod rom_loader;
+pub mod rpl;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod speed;
+pub mod timing;
+pub mod trace;
+pub mod variant;
+pub mod vip;
+
+extern crate clap;
+// pyo3's macros name `::core`, which the 2015 edition looks for at the crate root
+#[cfg(feature = "python")]
+extern crate core;
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(feature = "jit")]
+extern crate cranelift_native;
+#[cfg(all(feature = "gamepad", target_os = "linux"))]
+extern crate evdev;
+extern crate minifb;
+#[cfg(feature = "python")]
+extern crate pyo3;
+extern crate rand;
+#[cfg(feature = "scripting")]
+extern crate rhai;
+extern crate serde;
+extern crate serde_json;
+extern crate sha1_smol;
+extern crate toml;
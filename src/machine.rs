@@ -0,0 +1,147 @@
+use crate::config::invalid;
+use crate::memory::MEMORY_SIZE;
+use crate::variant::Variant;
+use serde::Deserialize;
+use std::io;
+
+// 16 return addresses is what most interpreters had room for
+pub const DEFAULT_STACK_DEPTH: usize = 16;
+
+// far deeper than any interpreter went, SP is 16 bits in traces and over gdb
+pub const MAX_STACK_DEPTH: usize = 0xFFFF;
+
+// MegaChip's 24-bit I can't reach further
+const MAX_MEMORY_SIZE: usize = 1 << 24;
+
+/// The memory map a ROM runs in: the interpreter's area below the load address, the
+/// program, and whatever the interpreter keeps at the top of memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Machine {
+    pub load_address: usize,
+    pub memory_size: usize,
+    /// return addresses CALL can push
+    pub stack_depth: usize,
+    /// bytes at the top of memory the interpreter keeps for itself, a ROM can't
+    /// be loaded over them
+    pub reserved: usize,
+}
+
+impl Machine {
+    pub fn for_variant(variant: Variant) -> Self {
+        Machine {
+            load_address: variant.load_address(),
+            memory_size: variant.memory_size(),
+            stack_depth: DEFAULT_STACK_DEPTH,
+            reserved: 0,
+        }
+    }
+
+    // the most a ROM can take up, from the load address to the reserved area
+    pub fn rom_capacity(&self) -> usize {
+        self.memory_size - self.reserved - self.load_address
+    }
+
+    pub fn check_rom(&self, len: usize) -> io::Result<()> {
+        if len > self.rom_capacity() {
+            return Err(invalid(format!(
+                "the ROM is {} bytes, only {} fit between {:#05X} and {:#05X}",
+                len,
+                self.rom_capacity(),
+                self.load_address,
+                self.memory_size - self.reserved
+            )));
+        }
+        Ok(())
+    }
+
+    // where `rom` starts running, the variant's entry point moved with the load address
+    pub fn entry(&self, variant: Variant, rom: &[u8]) -> usize {
+        self.load_address + variant.entry(rom) - variant.load_address()
+    }
+}
+
+/// `[machine]` in the config file. Anything left out is the variant's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub load_address: Option<usize>,
+    pub memory_size: Option<usize>,
+    pub stack_depth: Option<usize>,
+    pub reserved: Option<usize>,
+}
+
+impl MachineConfig {
+    pub fn resolve(&self, variant: Variant) -> io::Result<Machine> {
+        let defaults = Machine::for_variant(variant);
+        let machine = Machine {
+            load_address: self.load_address.unwrap_or(defaults.load_address),
+            memory_size: self.memory_size.unwrap_or(defaults.memory_size),
+            stack_depth: self.stack_depth.unwrap_or(defaults.stack_depth),
+            reserved: self.reserved.unwrap_or(defaults.reserved),
+        };
+
+        // instructions address 4K, smaller memories would need mirroring
+        if !(MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&machine.memory_size) {
+            return Err(invalid(format!(
+                "memory_size must be from {} to {} bytes",
+                MEMORY_SIZE, MAX_MEMORY_SIZE
+            )));
+        }
+        if !(1..=MAX_STACK_DEPTH).contains(&machine.stack_depth) {
+            return Err(invalid(format!(
+                "stack_depth must be from 1 to {}",
+                MAX_STACK_DEPTH
+            )));
+        }
+        if machine.load_address + machine.reserved >= machine.memory_size {
+            return Err(invalid(format!(
+                "a program loaded at {:#05X} has no room below the {} reserved bytes",
+                machine.load_address, machine.reserved
+            )));
+        }
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_machine_profile() {
+        let vip = Machine::for_variant(Variant::Chip8);
+        // a ROM can fill memory to the last byte
+        assert!(vip.check_rom(0x1000 - 0x200).is_ok());
+        assert!(vip.check_rom(0x1000 - 0x200 + 1).is_err());
+
+        let eti660 = MachineConfig {
+            load_address: Some(0x600),
+            reserved: Some(0x100),
+            stack_depth: Some(12),
+            ..MachineConfig::default()
+        }
+        .resolve(Variant::Hires)
+        .unwrap();
+        assert_eq!(eti660.rom_capacity(), 0x900);
+        assert_eq!(eti660.entry(Variant::Hires, &[0x12, 0x60]), 0x6C0);
+        assert_eq!(eti660.entry(Variant::Hires, &[0x00, 0xE0]), 0x600);
+
+        let config = |memory_size, stack_depth| MachineConfig {
+            memory_size: Some(memory_size),
+            stack_depth: Some(stack_depth),
+            ..MachineConfig::default()
+        };
+        assert!(config(0x800, 16).resolve(Variant::Chip8).is_err());
+        assert!(config(0x10000, MAX_STACK_DEPTH + 1)
+            .resolve(Variant::Chip8)
+            .is_err());
+        assert!(config(0x10000, 0).resolve(Variant::Chip8).is_err());
+        assert_eq!(
+            config(0x10000, 12)
+                .resolve(Variant::Chip8)
+                .unwrap()
+                .rom_capacity(),
+            0xFE00
+        );
+    }
+}
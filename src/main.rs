@@ -1,66 +1,674 @@
-mod cpu;
-mod display;
-mod keyboard;
-mod rom_loader;
-
-extern crate minifb;
+extern crate chip8;
+extern crate clap;
 extern crate rand;
-use cpu::CPU;
 
-use crate::keyboard::Keyboard;
-use crate::rom_loader::RomLoader;
-use std::path::Path;
+use chip8::audio::Beeper;
+use chip8::backend::{Backend, BackendKind};
+use chip8::cli::{apply_quirk, BenchArgs, Cli, Command, DiffRunArgs, FlagsArgs};
+use chip8::config::{Config, Settings, DEFAULT_CONFIG_FILE};
+use chip8::cpu::{Quirks, StepOutcome, CPU};
+use chip8::dap::DapServer;
+use chip8::debugger::DebugServer;
+use chip8::frontend::{Frontend, FrontendKind, HeadlessFrontend, WindowFrontend};
+#[cfg(all(feature = "gamepad", target_os = "linux"))]
+use chip8::gamepad;
+use chip8::gdb::GdbServer;
+use chip8::input::{InputMux, ScriptedInput};
+use chip8::keyboard::Keyboard;
+use chip8::keymap::Keymap;
+#[cfg(feature = "recompiled")]
+use chip8::recompiled;
+use chip8::rom_loader::RomLoader;
+use chip8::rpl::{FlagStore, Flags};
+#[cfg(feature = "scripting")]
+use chip8::script;
+use chip8::speed::{FrameLimiter, Hotkey, SpeedControl};
+use chip8::timing::{Timing, VipTiming};
+use chip8::trace::{TraceFilter, Tracer};
+use chip8::variant::Variant;
+use chip8::vip::Vip;
+use chip8::{bench, config, diff_run, megachip, recompile, trace};
+use clap::Parser;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 fn main() {
-    let keyboard = Arc::new(Keyboard::new());
-
-    let mut cpu = CPU::new(keyboard);
+    let cli = Cli::parse();
 
-    // Load ROM file
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <ROM file>", args[0]);
-        return;
+    let compared = match &cli.command {
+        Some(Command::TraceDiff { a, b, context }) => Some(trace::diff(a, b, *context)),
+        Some(Command::DiffRun(args)) => Some(diff_run(args)),
+        Some(Command::Bench(args)) => Some(bench(args).map(|_| true)),
+        Some(Command::Flags(args)) => Some(flags(args).map(|_| true)),
+        Some(Command::Recompile {
+            rom,
+            output,
+            config,
+        }) => Some(recompile(rom, output, config).map(|_| true)),
+        None => None,
+    };
+    match compared {
+        Some(Ok(true)) => return,
+        Some(Ok(false)) => process::exit(1),
+        Some(Err(e)) => {
+            println!("error: {}", e);
+            process::exit(2);
+        }
+        None => (),
     }
+    // clap requires a ROM when there is no subcommand
+    let rom_path = cli.rom.clone().unwrap();
 
-    let rom_path = &args[1];
     // load ROM file (will handle both binary and text assembly)
-    let rom_data = match RomLoader::load(Path::new(rom_path)) {
-        Ok(data) => data,
+    let rom = match RomLoader::load(&rom_path) {
+        Ok(rom) => rom,
         Err(e) => {
             println!("error loading ROM: {}", e);
             return;
         }
     };
 
+    let rom_data = rom.data;
     println!("loaded ROM: {} bytes", rom_data.len());
 
+    let settings = load_settings(&cli.config, &rom_path, &rom_data)
+        .and_then(|mut settings| cli.apply(&mut settings).map(|_| settings));
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("error loading config: {}", e);
+            return;
+        }
+    };
+
     // ensure ROM isn't too large for memory
-    if rom_data.len() > 0xFFF - 0x200 {
-        println!("ROM is too large to fit in memory!");
+    let machine = match settings.machine().and_then(|machine| {
+        machine.check_rom(rom_data.len())?;
+        Ok(machine)
+    }) {
+        Ok(machine) => machine,
+        Err(e) => {
+            println!("error loading ROM: {}", e);
+            return;
+        }
+    };
+    let load_address = machine.load_address;
+
+    let keyboard = Arc::new(Keyboard::new());
+
+    let mut cpu = CPU::new(keyboard.clone());
+    cpu.quirks = settings.quirks;
+    cpu.set_variant(settings.variant);
+    cpu.set_machine(&machine);
+    match settings.font.load(load_address) {
+        Ok(font) => cpu.load_font(font, settings.font.address),
+        Err(e) => {
+            println!("error loading font: {}", e);
+            return;
+        }
+    }
+
+    // load ROM data into memory where the machine's interpreter expects it
+    cpu.heap.load(load_address, &rom_data);
+    cpu.program_counter = machine.entry(settings.variant, &rom_data);
+
+    println!("ROM loaded into memory at {:#05X}", load_address);
+
+    // flags the ROM saved on an earlier run
+    let flag_store = FlagStore::new(&settings.save_dir, &rom_data);
+    match flag_store.load() {
+        Ok(flags) => cpu.rpl = flags,
+        Err(e) => {
+            println!("error loading RPL flags: {}", e);
+            return;
+        }
+    }
+    let mut saved_flags = cpu.rpl;
+
+    // a VIP runs its own interpreter on the ROM instead
+    let mut vip = match &cli.vip_interpreter {
+        Some(path) => match boot_vip(path, &cli.vip_monitor, keyboard.clone(), &rom_data) {
+            Ok(vip) => Some(vip),
+            Err(e) => {
+                println!("error starting the VIP: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if let Some(path) = &cli.trace {
+        let filter = TraceFilter {
+            addresses: cli.trace_addr.clone(),
+            classes: cli.trace_class.clone(),
+            frames: cli.trace_frames.clone(),
+        };
+        match Tracer::create(path, cli.trace_format, filter, settings.variant) {
+            Ok(tracer) => cpu.start_trace(tracer),
+            Err(e) => {
+                println!("error creating trace file: {}", e);
+                return;
+            }
+        }
+    }
+
+    let mut input = InputMux::new(keyboard);
+    let mut keypad2 = InputMux::new(cpu.keypad2.clone());
+
+    // settings were validated when they were resolved
+    let screen_size = settings.variant.screen_size();
+    let mut frontend: Box<dyn Frontend> = match settings.frontend {
+        FrontendKind::Window => match WindowFrontend::new(
+            screen_size,
+            settings.window_scale_for(screen_size.0).unwrap(),
+            settings.palette,
+            settings.keymap().unwrap(),
+        ) {
+            Ok(mut window) => {
+                input.add(Box::new(window.input()));
+                // CHIP-8X's second keypad is on the numeric keypad
+                if settings.variant == Variant::Chip8x {
+                    keypad2.add(Box::new(window.add_keypad(Keymap::numpad())));
+                }
+                Box::new(window)
+            }
+            Err(e) => {
+                println!("failed to create window: {}", e);
+                return;
+            }
+        },
+        FrontendKind::Headless => Box::new(HeadlessFrontend),
+    };
+
+    if settings.gamepad.enabled {
+        #[cfg(all(feature = "gamepad", target_os = "linux"))]
+        match gamepad::GamepadInput::open(&settings.gamepad) {
+            Ok(gamepad) => {
+                for name in gamepad.device_names() {
+                    println!("using gamepad: {}", name);
+                }
+                input.add(Box::new(gamepad));
+            }
+            Err(e) => println!("failed to open gamepad: {}", e),
+        }
+
+        #[cfg(not(all(feature = "gamepad", target_os = "linux")))]
+        println!("gamepad support needs the `gamepad` feature on Linux, ignoring it");
+    }
+
+    if let Some(path) = &cli.input_script {
+        match ScriptedInput::load(path) {
+            Ok(script) => input.add(Box::new(script)),
+            Err(e) => {
+                println!("error loading input script: {}", e);
+                return;
+            }
+        }
+    }
+
+    // scripts drive the CPU, they can't share it with a debugger or the VIP
+    #[cfg(feature = "scripting")]
+    let mut script = match &cli.script {
+        Some(_) if cli.gdb.is_some() || cli.dap.is_some() || vip.is_some() => {
+            println!("a script can't run under a debugger or on the VIP");
+            return;
+        }
+        Some(path) => match script::Script::load(path, &mut cpu, settings.palette) {
+            Ok((script, keys)) => {
+                input.add(Box::new(keys));
+                Some(script)
+            }
+            Err(e) => {
+                println!("error loading script: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "scripting"))]
+    if cli.script.is_some() {
+        println!("scripts need the `scripting` feature");
         return;
     }
+    #[cfg(feature = "scripting")]
+    let mut script_failed = false;
 
-    // load ROM data into memory starting at 0x200
-    for (i, &byte) in rom_data.iter().enumerate() {
-        cpu.heap[0x200 + i] = byte;
+    let mut beeper = match Beeper::new(&settings.audio) {
+        Ok(beeper) => beeper,
+        Err(e) => {
+            println!("failed to open audio output: {}", e);
+            return;
+        }
+    };
+
+    let mut speed = SpeedControl::new(
+        settings.instructions_per_frame,
+        settings.fast_forward,
+        settings.slow_motion,
+    );
+
+    // headless runs go as fast as the host allows, `Cli::apply` refuses speed flags for them
+    let throttled = settings.frontend == FrontendKind::Window;
+    if cli.paused {
+        speed.handle(Hotkey::TogglePause);
+    }
+    if let Some(frames) = cli.frame_advance {
+        speed.advance_frames(frames);
     }
+    if cli.slow_motion.is_some() {
+        speed.handle(Hotkey::ToggleSlowMotion);
+    }
+    // the CPU stays halted until the debugger says to continue
+    let debug_server: io::Result<Option<Box<dyn DebugServer>>> = if let Some(port) = cli.gdb {
+        GdbServer::bind(port, machine, rom_data.clone()).and_then(|mut server| {
+            println!("waiting for gdb on {}", server.local_addr()?);
+            server.wait_for_client()?;
+            Ok(Some(Box::new(server) as Box<dyn DebugServer>))
+        })
+    } else if let Some(port) = cli.dap {
+        DapServer::bind(port, machine, rom.source_map).and_then(|mut server| {
+            println!("waiting for a DAP client on {}", server.local_addr()?);
+            server.wait_for_client()?;
+            Ok(Some(Box::new(server) as Box<dyn DebugServer>))
+        })
+    } else {
+        Ok(None)
+    };
+    let mut debug_server = match debug_server {
+        Ok(server) => server,
+        Err(e) => {
+            println!("failed to start debug server: {}", e);
+            return;
+        }
+    };
 
-    println!("ROM loaded into memory at 0x200");
+    // the debug servers step the interpreter themselves
+    let mut backend = match Backend::new(cli.backend) {
+        Ok(backend) => backend,
+        Err(e) => {
+            println!("error: {}", e);
+            return;
+        }
+    };
 
-    // main emulation loop
-    while cpu.display.is_open() {
-        // run one cpu cycle
-        cpu.tick();
+    #[cfg(feature = "recompiled")]
+    if cli.backend == BackendKind::Recompiled && rom_data[..] != recompiled::ROM[..] {
+        println!(
+            "{} isn't the ROM that was recompiled, it will run in the interpreter",
+            rom_path.display()
+        );
+    }
+
+    // VIP timing needs the cost of every instruction, so it always interprets
+    if settings.timing == Timing::Vip && backend.is_jit() {
+        println!("VIP timing runs in the interpreter, ignoring --backend");
+    }
+    let mut vip_timing = VipTiming::new();
+
+    let mut limiter = FrameLimiter::new();
+    let mut title = String::new();
+
+    // main emulation loop, one iteration per host frame
+    let mut frames = 0;
+    'running: while frontend.is_open() {
+        for hotkey in frontend.hotkeys() {
+            speed.handle(hotkey);
+        }
+        if speed.title() != title {
+            title = speed.title();
+            frontend.set_title(&title);
+        }
+
+        if let Some(server) = &mut debug_server {
+            if let Err(e) = server.poll(&mut cpu) {
+                println!("debug server failed: {}", e);
+                break;
+            }
+            if server.is_finished() {
+                break;
+            }
+        }
+
+        // one 60 Hz CHIP-8 frame each
+        for _ in 0..speed.frames_to_run() {
+            if cli.frames.is_some_and(|limit| frames >= limit) {
+                break 'running;
+            }
+            // time stands still while the debugger has the CPU halted
+            if debug_server
+                .as_ref()
+                .is_some_and(|server| server.debugger().is_halted())
+            {
+                break;
+            }
+
+            input.poll();
+            keypad2.poll();
+            let outcome = match &mut debug_server {
+                Some(server) => {
+                    if let Err(e) = server.run(&mut cpu, speed.instructions_per_frame) {
+                        println!("debug server failed: {}", e);
+                        break 'running;
+                    }
+                    StepOutcome::Running
+                }
+                #[cfg(feature = "scripting")]
+                None if script.is_some() => {
+                    let script = script.as_mut().unwrap();
+                    match script.run_frame(&mut cpu, &mut backend, speed.instructions_per_frame) {
+                        Ok(outcome) => outcome,
+                        Err(e) => {
+                            println!("{}", e);
+                            script_failed = true;
+                            break 'running;
+                        }
+                    }
+                }
+                None => match (&mut vip, settings.timing) {
+                    (Some(vip), _) => {
+                        vip.run_frame();
+                        StepOutcome::Running
+                    }
+                    // the window only needs a frame's worth of instructions until the
+                    // ROM starts spinning, the rest would burn a core for nothing
+                    (None, Timing::Fixed) if throttled => {
+                        backend.run_until_idle(&mut cpu, speed.instructions_per_frame)
+                    }
+                    (None, Timing::Fixed) => backend.run(&mut cpu, speed.instructions_per_frame),
+                    (None, Timing::Vip) => {
+                        vip_timing.run_frame(&mut cpu);
+                        cpu.outcome()
+                    }
+                },
+            };
+            // nothing happens after that in a batch run
+            if !throttled {
+                let reason = match outcome {
+                    StepOutcome::Halted => Some("halted"),
+                    StepOutcome::Exited => Some("exited"),
+                    _ => None,
+                };
+                if let Some(reason) = reason {
+                    println!(
+                        "program {} at {:#05X} after {} frames",
+                        reason, cpu.program_counter, frames
+                    );
+                    break 'running;
+                }
+            }
+            cpu.update_timers();
+            frames += 1;
+
+            #[cfg(feature = "scripting")]
+            if let Some(script) = &mut script {
+                if let Err(e) = script.end_frame(&mut cpu) {
+                    println!("{}", e);
+                    script_failed = true;
+                    break 'running;
+                }
+                if script.is_finished() {
+                    break 'running;
+                }
+            }
+
+            let sound = vip.as_ref().map_or(cpu.sound_timer > 0, Vip::sound);
+            let audio = match megachip::sound_frame(&mut cpu) {
+                Some(pcm) => beeper.play(&pcm),
+                None => beeper.frame(sound),
+            };
+            if let Err(e) = audio {
+                println!("failed to write audio: {}", e);
+                break 'running;
+            }
+        }
+
+        // saved as soon as they change, as the HP48 kept them
+        save_flags(&flag_store, &cpu.rpl, &mut saved_flags);
 
         // update display
-        if let Err(e) = cpu.display.update() {
+        let display = vip.as_ref().map_or(&cpu.display, |vip| &vip.display);
+        if let Err(e) = frontend.present(display) {
             println!("failed to update display: {}", e);
             break;
         }
 
-        // sleep for 1/60th of a second
-        // std::thread::sleep(std::time::Duration::from_secs_f32(0.4));
+        if throttled {
+            limiter.wait(speed.frame_duration());
+        }
+    }
+
+    // a ROM that saves its flags and exits in the same frame breaks out before the save above
+    save_flags(&flag_store, &cpu.rpl, &mut saved_flags);
+    if let Some(tracer) = cpu.stop_trace() {
+        if let Err(e) = tracer.finish() {
+            println!("failed to write trace: {}", e);
+        }
+    }
+    #[cfg(feature = "scripting")]
+    if script_failed {
+        process::exit(1);
+    }
+}
+
+// write the RPL flags if they changed since they were last saved
+fn save_flags(flag_store: &FlagStore, flags: &Flags, saved: &mut Flags) {
+    if flags != saved {
+        if let Err(e) = flag_store.save(flags) {
+            println!("failed to save RPL flags: {}", e);
+        }
+        *saved = *flags;
+    }
+}
+
+// a VIP with `interpreter` and optionally `monitor` loaded, and the ROM at 0x200
+fn boot_vip(
+    interpreter: &Path,
+    monitor: &Option<PathBuf>,
+    keyboard: Arc<Keyboard>,
+    rom: &[u8],
+) -> io::Result<Vip> {
+    let monitor = match monitor {
+        Some(path) => Some(fs::read(path)?),
+        None => None,
+    };
+    let mut vip = Vip::new(keyboard, &fs::read(interpreter)?, monitor)?;
+    vip.load(0x200, rom)?;
+    println!("running on a COSMAC VIP with {}", interpreter.display());
+    Ok(vip)
+}
+
+// settings for a ROM from the config file, before command line flags are applied
+fn load_settings(
+    config: &Option<PathBuf>,
+    rom_path: &Path,
+    rom_data: &[u8],
+) -> io::Result<Settings> {
+    // an explicit --config must exist, the default one is optional
+    let config = match config {
+        Some(path) => Config::load(path),
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            Config::load(Path::new(DEFAULT_CONFIG_FILE))
+        }
+        None => Ok(Config::default()),
+    };
+    config.and_then(|config| config.settings_for(rom_path, rom_data))
+}
+
+// the `diff-run` subcommand, returns whether the runs agreed
+fn diff_run(args: &DiffRunArgs) -> io::Result<bool> {
+    let rom = RomLoader::load(&args.rom)?;
+    let mut settings = load_settings(&args.config, &args.rom, &rom.data)?;
+    for quirk in &args.quirks {
+        apply_quirk(&mut settings.quirks, quirk)?;
+    }
+    let machine = settings.machine()?;
+    machine.check_rom(rom.data.len())?;
+    let load_address = machine.load_address;
+    let font = settings.font.load(load_address)?;
+    let ipf = args
+        .instructions_per_frame
+        .unwrap_or(settings.instructions_per_frame)
+        .max(1);
+
+    // both runs start from the same ROM, RND seed and input script
+    let machine = |quirks: &[String]| -> io::Result<(CPU, InputMux)> {
+        let keyboard = Arc::new(Keyboard::new());
+        let mut cpu = CPU::new(keyboard.clone());
+        cpu.quirks = settings.quirks;
+        for quirk in quirks {
+            apply_quirk(&mut cpu.quirks, quirk)?;
+        }
+        // a side that runs machine code needs the top of memory left free
+        let side = Settings {
+            quirks: cpu.quirks,
+            ..settings.clone()
+        };
+        side.machine()?.check_rom(rom.data.len())?;
+        cpu.rng = StdRng::seed_from_u64(args.seed);
+        cpu.set_variant(settings.variant);
+        cpu.set_machine(&machine);
+        cpu.load_font(font.clone(), settings.font.address);
+        cpu.heap.load(load_address, &rom.data);
+        cpu.program_counter = machine.entry(settings.variant, &rom.data);
+
+        let mut input = InputMux::new(keyboard);
+        if let Some(path) = &args.input_script {
+            input.add(Box::new(ScriptedInput::load(path)?));
+        }
+        Ok((cpu, input))
+    };
+
+    let (mut a, mut input_a) = machine(&args.quirks_a)?;
+    let divergence = match &args.reference {
+        Some(path) => {
+            let steps = diff_run::parse_reference(&fs::read_to_string(path)?)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            println!("a: {}", describe_quirks(a.quirks));
+            println!("b: {}", path.display());
+            diff_run::run_reference(&mut a, &steps, &mut input_a, ipf)?
+        }
+        None => {
+            let (mut b, input_b) = machine(&args.quirks_b)?;
+            println!("a: {}", describe_quirks(a.quirks));
+            println!("b: {}", describe_quirks(b.quirks));
+            let mut inputs = [input_a, input_b];
+            let mut backends = [Backend::new(args.backend_a)?, Backend::new(args.backend_b)?];
+            diff_run::run_pair(&mut a, &mut b, &mut backends, &mut inputs, args.frames, ipf)
+        }
+    };
+
+    match divergence {
+        Some(divergence) => {
+            print!("{}", divergence);
+            Ok(false)
+        }
+        None => {
+            println!("no divergence in {} instructions", a.cycles);
+            Ok(true)
+        }
+    }
+}
+
+// the `bench` subcommand
+fn bench(args: &BenchArgs) -> io::Result<()> {
+    let rom = RomLoader::load(&args.rom)?;
+    let settings = load_settings(&args.config, &args.rom, &rom.data)?;
+    let machine = settings.machine()?;
+    machine.check_rom(rom.data.len())?;
+    let load_address = machine.load_address;
+    let ipf = args
+        .instructions_per_frame
+        .unwrap_or(settings.instructions_per_frame)
+        .max(1);
+    let duration = Duration::try_from_secs_f64(args.seconds)
+        .map_err(|_| config::invalid("--seconds must be a positive number"))?;
+
+    if args.no_cache && args.backend == BackendKind::Jit {
+        return Err(config::invalid(
+            "the JIT needs the decode cache, drop --no-cache",
+        ));
+    }
+    let mut backend = Backend::new(args.backend)?;
+
+    let mut cpu = CPU::new(Arc::new(Keyboard::new()));
+    cpu.quirks = settings.quirks;
+    cpu.heap.set_decode_cache(!args.no_cache);
+    cpu.set_variant(settings.variant);
+    cpu.set_machine(&machine);
+    cpu.load_font(settings.font.load(load_address)?, settings.font.address);
+    cpu.heap.load(load_address, &rom.data);
+    cpu.program_counter = machine.entry(settings.variant, &rom.data);
+
+    let result = bench::run(&mut cpu, &mut backend, ipf, duration);
+    println!("{}", result);
+    Ok(())
+}
+
+// the `flags` subcommand
+fn flags(args: &FlagsArgs) -> io::Result<()> {
+    let rom = RomLoader::load(&args.rom)?;
+    let settings = load_settings(&args.config, &args.rom, &rom.data)?;
+    let store = FlagStore::new(&settings.save_dir, &rom.data);
+    let flags = store.load()?;
+
+    let bytes: Vec<String> = flags.iter().map(|flag| format!("{:02X}", flag)).collect();
+    println!("{}: {}", store.path().display(), bytes.join(" "));
+    if let Some(path) = &args.export {
+        fs::write(path, flags)?;
+        println!("exported to {}", path.display());
+    }
+    if args.reset {
+        store.reset()?;
+        println!("reset");
+    }
+    Ok(())
+}
+
+// the `recompile` subcommand
+fn recompile(
+    rom_path: &Path,
+    output: &Option<PathBuf>,
+    config: &Option<PathBuf>,
+) -> io::Result<()> {
+    let rom = RomLoader::load(rom_path)?;
+    let settings = load_settings(config, rom_path, &rom.data)?;
+    let machine = settings.machine()?;
+    machine.check_rom(rom.data.len())?;
+    let name = rom_path.file_name().map_or_else(
+        || rom_path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let module = recompile::generate(&rom.data, &name, &machine);
+    match output {
+        Some(path) => fs::write(path, module),
+        None => {
+            print!("{}", module);
+            Ok(())
+        }
+    }
+}
+
+// the quirks that are switched on, for telling runs apart
+fn describe_quirks(quirks: Quirks) -> String {
+    let enabled: Vec<&str> = Quirks::NAMES
+        .iter()
+        .copied()
+        .filter(|&name| {
+            let mut probe = quirks;
+            // a quirk is on if turning it on changes nothing
+            probe.set(name, true);
+            probe == quirks
+        })
+        .collect();
+    if enabled.is_empty() {
+        "no quirks".to_string()
+    } else {
+        format!("quirks {}", enabled.join(", "))
     }
 }
@@ -0,0 +1,174 @@
+use crate::audio::{SAMPLES_PER_FRAME, SAMPLE_RATE};
+use crate::cpu::CPU;
+
+// a sound starts with its sample rate in 2 bytes and its length in 3, big endian,
+// then a reserved byte. Samples are unsigned 8-bit mono
+const SOUND_HEADER: usize = 6;
+
+// a digitized sound being played
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    start: usize,
+    length: usize,
+    rate: u32,
+    looping: bool,
+    // samples played so far, in the sound's own rate
+    position: f64,
+}
+
+/// draw a sprite of the set width and height from I at (Vx, Vy), a palette index
+/// per byte. Index 0 is transparent, VF is set if any pixel drew over the collision
+/// index
+pub fn draw(cpu: &mut CPU, x: u8, y: u8) {
+    let (left, top) = (
+        cpu.registers[x as usize] as usize,
+        cpu.registers[y as usize] as usize,
+    );
+    let (width, height) = match cpu.display.indexed() {
+        Some(indexed) => (indexed.sprite_width, indexed.sprite_height),
+        None => return,
+    };
+    let start = cpu.i_register as usize;
+    let len = cpu.heap.len();
+    let sprite: Vec<u8> = (0..width * height)
+        .map(|offset| cpu.heap.read((start + offset) % len))
+        .collect();
+
+    let indexed = cpu.display.indexed_mut().unwrap();
+    let mut collision = false;
+    for (offset, &index) in sprite.iter().enumerate() {
+        if index != 0 {
+            collision |= indexed.plot(left + offset % width, top + offset / width, index);
+        }
+    }
+    cpu.registers[0xF] = collision as u8;
+}
+
+// `count` colors from I into the palette, starting at index 1
+pub fn load_palette(cpu: &mut CPU, count: u8) {
+    let start = cpu.i_register as usize;
+    let colors: Vec<u32> = (0..count as usize)
+        .map(|n| {
+            (0..4).fold(0, |color, byte| {
+                color << 8 | cpu.heap.read((start + n * 4 + byte) % cpu.heap.len()) as u32
+            })
+        })
+        .collect();
+    if let Some(indexed) = cpu.display.indexed_mut() {
+        indexed.palette[1..=colors.len()].copy_from_slice(&colors);
+    }
+}
+
+// start playing the sound at I
+pub fn play(cpu: &mut CPU, looping: bool) {
+    let start = cpu.i_register as usize;
+    let header: Vec<u8> = (0..SOUND_HEADER)
+        .map(|offset| cpu.heap.read((start + offset) % cpu.heap.len()))
+        .collect();
+    let rate = (header[0] as u32) << 8 | header[1] as u32;
+    let length = (header[2] as usize) << 16 | (header[3] as usize) << 8 | header[4] as usize;
+    cpu.sample = if rate == 0 || length == 0 {
+        None
+    } else {
+        Some(Sample {
+            start: start + SOUND_HEADER,
+            length,
+            rate,
+            looping,
+            position: 0.0,
+        })
+    };
+}
+
+/// A frame of the playing sound at the output's sample rate, unsigned 8-bit as it
+/// is stored. None when nothing is playing.
+pub fn sound_frame(cpu: &mut CPU) -> Option<Vec<u8>> {
+    let sample = cpu.sample.as_mut()?;
+    let step = sample.rate as f64 / SAMPLE_RATE as f64;
+    let mut frame = Vec::with_capacity(SAMPLES_PER_FRAME);
+    for _ in 0..SAMPLES_PER_FRAME {
+        let mut offset = sample.position as usize;
+        if offset >= sample.length {
+            if !sample.looping {
+                break;
+            }
+            sample.position -= sample.length as f64;
+            offset = sample.position as usize;
+        }
+        frame.push(cpu.heap[(sample.start + offset) % cpu.heap.len()]);
+        sample.position += step;
+    }
+    // a sound that ran out ends in silence
+    if frame.len() < SAMPLES_PER_FRAME {
+        cpu.sample = None;
+        frame.resize(SAMPLES_PER_FRAME, 0x80);
+    }
+    Some(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+    use crate::variant::Variant;
+
+    #[test]
+    fn test_megachip_sprites() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.set_variant(Variant::Megachip);
+        cpu.heap
+            .load(0x10000, &[0xFF, 0xFF, 0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00]);
+        cpu.heap.load(0x20000, &[1, 0, 2, 2]);
+        cpu.registers[1] = 10;
+        cpu.heap.load(
+            0x200,
+            &[
+                0x00, 0x11, // MEGAON
+                0x01, 0x01, 0x00, 0x00, // LDHI I, 0x010000
+                0x02, 0x02, // LDPAL 2
+                0x03, 0x02, // SPRW 2
+                0x04, 0x02, // SPRH 2
+                0x01, 0x02, 0x00, 0x00, // LDHI I, 0x020000
+                0xD1, 0x10, // DRW V1, V1, 0
+                0x09, 0x02, // CCOL 2
+                0xD1, 0x10, // DRW V1, V1, 0
+                0x00, 0xE0, // CLS
+            ],
+        );
+        for _ in 0..8 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.i_register, 0x20000);
+        assert_eq!(cpu.registers[0xF], 0);
+        cpu.tick();
+        assert_eq!(cpu.registers[0xF], 1);
+        cpu.tick();
+
+        let front = cpu.display.indexed().unwrap().front();
+        assert_eq!(front[10 * 256 + 10], 0xFF0000);
+        // index 0 is transparent
+        assert_eq!(front[10 * 256 + 11], 0);
+        assert_eq!(&front[11 * 256 + 10..11 * 256 + 12], &[0x00FF00, 0x00FF00]);
+    }
+
+    #[test]
+    fn test_megachip_sound() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.set_variant(Variant::Megachip);
+        // 3 samples at half the output rate
+        let [high, low] = (SAMPLE_RATE as u16 / 2).to_be_bytes();
+        cpu.heap.load(0x1000, &[high, low, 0, 0, 3, 0, 10, 20, 30]);
+        cpu.i_register = 0x1000;
+
+        play(&mut cpu, false);
+        let frame = sound_frame(&mut cpu).unwrap();
+        assert_eq!(&frame[..7], &[10, 10, 20, 20, 30, 30, 0x80]);
+        assert_eq!(frame.len(), SAMPLES_PER_FRAME);
+        assert_eq!(sound_frame(&mut cpu), None);
+
+        play(&mut cpu, true);
+        let frame = sound_frame(&mut cpu).unwrap();
+        assert_eq!(&frame[5..8], &[30, 10, 10]);
+        assert!(cpu.sample.is_some());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::instruction::{DecodeError, Instruction};
+use crate::variant::Variant;
+use std::ops::{Deref, Range};
+
+pub const MEMORY_SIZE: usize = 4096;
+
+// who wrote each byte and the decoded instructions are only kept this far. Jumps
+// can't go past it, so there is no code higher up, only MegaChip's data
+const CODE_SIZE: usize = 0x10000;
+
+// how an instruction touched memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // reads and writes
+    Access,
+    Execute,
+}
+
+impl WatchKind {
+    pub fn covers(self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::Access => access != Access::Execute,
+            WatchKind::Execute => access == Access::Execute,
+        }
+    }
+}
+
+// a watched range of addresses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub addr: usize,
+    pub len: usize,
+    // only fire when this value is read, written or executed
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    fn fires(&self, access: Access, addr: usize, value: u8) -> bool {
+        self.kind.covers(access)
+            && addr >= self.addr
+            && addr - self.addr < self.len
+            && self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+// a watchpoint that fired, and the access that set it off
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub access: Access,
+    pub addr: usize,
+    pub value: u8,
+    // address of the instruction that made the access
+    pub pc: usize,
+}
+
+// the memory bus, every access an instruction makes goes through here
+// derefs to the raw bytes for debuggers and loaders, those reads don't fire watchpoints
+pub struct Memory {
+    bytes: Vec<u8>,
+    // address of the instruction that last wrote each byte, None when the program never has
+    writers: Vec<Option<u32>>,
+    pub watchpoints: Vec<Watchpoint>,
+    // the watchpoints that fired since the last `take_hits`, in the order they did
+    hits: Vec<WatchHit>,
+    // address of the instruction being executed, accesses are attributed to it
+    executing: usize,
+    // writes that changed a byte, kept while tracing
+    journal: Option<Vec<(usize, u8)>>,
+    // instructions already decoded, by address, dropped when either of their bytes changes
+    decoded: Vec<Option<Result<Instruction, DecodeError>>>,
+    decode_cache: bool,
+    // the instruction set the program is decoded as
+    variant: Variant,
+    // addresses where cached instructions were overwritten, kept for the JIT
+    code_writes: Option<Vec<usize>>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory::with_size(MEMORY_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Self {
+        let code = size.min(CODE_SIZE);
+        Memory {
+            bytes: vec![0; size],
+            writers: vec![None; code],
+            watchpoints: Vec::new(),
+            hits: Vec::new(),
+            executing: 0,
+            journal: None,
+            decoded: vec![None; code],
+            decode_cache: true,
+            variant: Variant::Chip8,
+            code_writes: None,
+        }
+    }
+
+    // copy `data` in at `addr` without firing watchpoints, for loaders and debuggers
+    pub fn load(&mut self, addr: usize, data: &[u8]) {
+        self.bytes[addr..addr + data.len()].copy_from_slice(data);
+        let cached = self.code_range(addr, addr + data.len());
+        self.writers[addr.min(cached.end)..cached.end].fill(None);
+        if let Some(code_writes) = &mut self.code_writes {
+            for (offset, decoded) in self.decoded[cached.clone()].iter().enumerate() {
+                if decoded.is_some() {
+                    code_writes.push(cached.start + offset);
+                }
+            }
+        }
+        self.decoded[cached].fill(None);
+    }
+
+    // zero every byte as at power on, watchpoints stay set
+    pub fn clear(&mut self) {
+        let size = self.bytes.len();
+        self.load(0, &vec![0; size]);
+        self.hits.clear();
+    }
+
+    // the cached instructions that overlap the bytes from `start` to `end`
+    fn code_range(&self, start: usize, end: usize) -> Range<usize> {
+        let end = end.min(self.decoded.len());
+        start.saturating_sub(1).min(end)..end
+    }
+
+    // grow or shrink memory, keeping what fits
+    pub fn resize(&mut self, size: usize) {
+        let code = size.min(CODE_SIZE);
+        self.bytes.resize(size, 0);
+        self.writers.resize(code, None);
+        self.decoded.resize(code, None);
+    }
+
+    // fetch the opcode at `addr`, accesses until the next fetch belong to it
+    pub fn fetch(&mut self, addr: usize) -> u16 {
+        self.executing = addr;
+        (self.bytes[addr] as u16) << 8 | self.bytes[addr + 1] as u16
+    }
+
+    // the instruction at `addr`, decoded once and then served from the cache
+    pub fn decode(&mut self, addr: usize) -> Result<Instruction, DecodeError> {
+        let opcode = (self.bytes[addr] as u16) << 8 | self.bytes[addr + 1] as u16;
+        if !self.decode_cache || addr >= self.decoded.len() {
+            return self.variant.decode(opcode);
+        }
+        let variant = self.variant;
+        *self.decoded[addr].get_or_insert_with(|| variant.decode(opcode))
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    // decoded instructions may mean something else now
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        self.decoded.fill(None);
+    }
+
+    // the cache only pays off for code that runs more than once, so benchmarks can turn it off
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        self.decoded.fill(None);
+    }
+
+    pub fn read(&mut self, addr: usize) -> u8 {
+        let value = self.bytes[addr];
+        self.watch(Access::Read, addr, value);
+        value
+    }
+
+    pub fn write(&mut self, addr: usize, value: u8) {
+        if let Some(journal) = &mut self.journal {
+            if self.bytes[addr] != value {
+                journal.push((addr, value));
+            }
+        }
+        self.bytes[addr] = value;
+        if let Some(writer) = self.writers.get_mut(addr) {
+            *writer = Some(self.executing as u32);
+        }
+        // the instructions starting on this byte and the one before it
+        let cached = self.code_range(addr, addr + 1);
+        if let Some(code_writes) = &mut self.code_writes {
+            if self.decoded[cached.clone()].iter().any(Option::is_some) {
+                code_writes.push(addr);
+            }
+        }
+        self.decoded[cached].fill(None);
+        self.watch(Access::Write, addr, value);
+    }
+
+    // the execute watchpoint at `addr`, checked before the instruction there runs
+    pub fn execute_hit(&self, addr: usize) -> Option<WatchHit> {
+        let value = *self.bytes.get(addr)?;
+        self.watchpoints
+            .iter()
+            .find(|w| w.fires(Access::Execute, addr, value))
+            .map(|&watchpoint| WatchHit {
+                watchpoint,
+                access: Access::Execute,
+                addr,
+                value,
+                pc: addr,
+            })
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    // start or stop journaling the writes that change memory
+    pub fn record_writes(&mut self, enabled: bool) {
+        self.journal = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // the journaled writes since the last call, in the order they happened
+    pub fn take_writes(&mut self) -> Vec<(usize, u8)> {
+        self.journal.as_mut().map_or_else(Vec::new, std::mem::take)
+    }
+
+    // address of the instruction that last wrote `addr`
+    pub fn last_writer(&self, addr: usize) -> Option<u32> {
+        self.writers.get(addr).copied().flatten()
+    }
+
+    // add `watchpoint` unless it's empty or runs past the end of memory
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let fits = watchpoint.len > 0
+            && watchpoint
+                .addr
+                .checked_add(watchpoint.len)
+                .is_some_and(|end| end <= self.bytes.len());
+        if fits {
+            self.watchpoints.push(watchpoint);
+        }
+        fits
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    fn watch(&mut self, access: Access, addr: usize, value: u8) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        if let Some(&watchpoint) = self
+            .watchpoints
+            .iter()
+            .find(|w| w.fires(access, addr, value))
+        {
+            self.hits.push(WatchHit {
+                watchpoint,
+                access,
+                addr,
+                value,
+                pc: self.executing,
+            });
+        }
+    }
+}
+
+// the JIT compiles code straight from the decode cache, so the writes that drop
+// cached instructions are what invalidate its blocks
+#[cfg(feature = "jit")]
+impl Memory {
+    // start or stop keeping the addresses where writes hit decoded instructions
+    pub fn track_code_writes(&mut self, enabled: bool) {
+        self.code_writes = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // how far instructions are cached, blocks can only be compiled below it
+    pub fn code_len(&self) -> usize {
+        self.decoded.len()
+    }
+
+    pub fn tracks_code_writes(&self) -> bool {
+        self.code_writes.is_some()
+    }
+
+    pub fn has_code_writes(&self) -> bool {
+        self.code_writes
+            .as_ref()
+            .is_some_and(|writes| !writes.is_empty())
+    }
+
+    // the addresses of code overwritten since the last call
+    pub fn take_code_writes(&mut self) -> Vec<usize> {
+        self.code_writes
+            .as_mut()
+            .map_or_else(Vec::new, std::mem::take)
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchpoints_and_conditions() {
+        let mut memory = Memory::new();
+        memory.watchpoints.push(Watchpoint {
+            kind: WatchKind::Write,
+            addr: 0x300,
+            len: 3,
+            value: Some(7),
+        });
+        memory.watchpoints.push(Watchpoint {
+            kind: WatchKind::Access,
+            addr: 0x400,
+            len: 1,
+            value: None,
+        });
+
+        memory.fetch(0x200);
+        memory.write(0x301, 6);
+        memory.read(0x301);
+        memory.write(0x303, 7);
+        assert_eq!(memory.take_hits(), vec![]);
+
+        memory.fetch(0x204);
+        memory.write(0x302, 7);
+        memory.write(0x300, 7);
+        let hits: Vec<_> = memory
+            .take_hits()
+            .iter()
+            .map(|hit| (hit.access, hit.addr, hit.value, hit.pc))
+            .collect();
+        assert_eq!(
+            hits,
+            vec![
+                (Access::Write, 0x302, 7, 0x204),
+                (Access::Write, 0x300, 7, 0x204)
+            ]
+        );
+
+        memory.read(0x400);
+        assert_eq!(memory.take_hits()[0].access, Access::Read);
+        assert_eq!(memory.execute_hit(0x400), None);
+
+        memory.watchpoints.push(Watchpoint {
+            kind: WatchKind::Execute,
+            addr: 0x206,
+            len: 2,
+            value: None,
+        });
+        assert_eq!(memory.execute_hit(0x206).map(|hit| hit.pc), Some(0x206));
+
+        // empty ranges and ones past the end are refused, not left to overflow
+        let watchpoint = |addr, len| Watchpoint {
+            kind: WatchKind::Access,
+            addr,
+            len,
+            value: None,
+        };
+        assert!(!memory.add_watchpoint(watchpoint(0x100, usize::MAX)));
+        assert!(!memory.add_watchpoint(watchpoint(0x100, 0)));
+        assert!(!memory.add_watchpoint(watchpoint(0xFFF, 2)));
+        assert!(memory.add_watchpoint(watchpoint(0xFFF, 1)));
+        assert!(!watchpoint(usize::MAX, 1).fires(Access::Read, 0x10, 0));
+        memory.read(0xFFF);
+        assert_eq!(memory.take_hits()[0].addr, 0xFFF);
+    }
+
+    #[test]
+    fn test_last_writer() {
+        let mut memory = Memory::new();
+        memory.load(0x200, &[0x12, 0x00]);
+        assert_eq!(memory.last_writer(0x200), None);
+
+        memory.fetch(0x20A);
+        memory.write(0x200, 0x13);
+        assert_eq!(memory.last_writer(0x200), Some(0x20A));
+        assert_eq!(memory[0x200], 0x13);
+
+        // loading over it forgets the writer
+        memory.load(0x200, &[0x12]);
+        assert_eq!(memory.last_writer(0x200), None);
+    }
+
+    #[test]
+    fn test_decode_cache_invalidation() {
+        let mut memory = Memory::new();
+        memory.load(0x200, &[0x61, 0x03, 0x71, 0x01]);
+        assert_eq!(memory.decode(0x200), Ok(Instruction::LdByte(1, 3)));
+        assert_eq!(memory.decode(0x202), Ok(Instruction::AddByte(1, 1)));
+
+        // writes to either byte of a cached instruction drop it
+        memory.write(0x201, 0x07);
+        assert_eq!(memory.decode(0x200), Ok(Instruction::LdByte(1, 7)));
+        memory.write(0x202, 0x72);
+        assert_eq!(memory.decode(0x202), Ok(Instruction::AddByte(2, 1)));
+
+        // and so does loading over it, as restoring a saved state does
+        memory.load(0x201, &[0x05]);
+        assert_eq!(memory.decode(0x200), Ok(Instruction::LdByte(1, 5)));
+    }
+
+    #[test]
+    fn test_large_memory() {
+        // past the first 64K bytes are neither cached nor attributed to a writer
+        let mut memory = Memory::with_size(0x20000);
+        memory.load(0xFFFF, &[0x61, 0x03, 0x71, 0x01]);
+        assert_eq!(memory.decode(0xFFFF), Ok(Instruction::LdByte(1, 3)));
+        assert_eq!(memory.decode(0x10001), Ok(Instruction::AddByte(1, 1)));
+
+        memory.write(0x10000, 0x07);
+        assert_eq!(memory.decode(0xFFFF), Ok(Instruction::LdByte(1, 7)));
+        assert_eq!(memory.last_writer(0x10000), None);
+
+        memory.resize(MEMORY_SIZE);
+        assert_eq!(memory.len(), MEMORY_SIZE);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::cli::apply_quirk;
+use crate::config::Settings;
+use crate::gym::{Env, Score, State};
+use crate::rom_loader::RomLoader;
+use crate::variant::Variant;
+use clap::ValueEnum;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::io;
+use std::path::PathBuf;
+
+fn value_error(e: io::Error) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// A CHIP-8 game as a reinforcement learning environment.
+///
+/// `rewards` is a list of `(address, length, format)` scores, format being "bcd" for a
+/// digit per byte as Fx33 stores them or "binary" for a big-endian number. A step is
+/// rewarded with how much they went up. `done_when` is an `(address, value)` that ends
+/// the episode, an episode also ends when the program halts or exits.
+#[pyclass(name = "Env", module = "chip8", unsendable)]
+struct PyEnv {
+    env: Env,
+}
+
+#[pymethods]
+impl PyEnv {
+    #[new]
+    #[pyo3(signature = (
+        variant = "chip8",
+        quirks = Vec::new(),
+        instructions_per_frame = 10,
+        frame_skip = 4,
+        rewards = Vec::new(),
+        done_when = None,
+    ))]
+    fn new(
+        variant: &str,
+        quirks: Vec<String>,
+        instructions_per_frame: u32,
+        frame_skip: u32,
+        rewards: Vec<(usize, usize, String)>,
+        done_when: Option<(usize, u8)>,
+    ) -> PyResult<Self> {
+        let mut settings = Settings {
+            variant: Variant::from_str(variant, true).map_err(PyValueError::new_err)?,
+            instructions_per_frame,
+            ..Settings::default()
+        };
+        for quirk in &quirks {
+            apply_quirk(&mut settings.quirks, quirk).map_err(value_error)?;
+        }
+        let scores = rewards
+            .into_iter()
+            .map(|(address, len, format)| {
+                Ok(Score {
+                    address,
+                    len,
+                    format: format.parse()?,
+                })
+            })
+            .collect::<io::Result<_>>()
+            .map_err(value_error)?;
+        let env = Env::new(settings, scores, done_when, frame_skip).map_err(value_error)?;
+        Ok(PyEnv { env })
+    }
+
+    /// Start an episode of `rom`, bytes or the path of a ROM or assembly listing, with
+    /// RND seeded by `seed`. Returns the screen, a byte per pixel row by row, 1 where it
+    /// is lit.
+    fn reset<'py>(
+        &mut self,
+        py: Python<'py>,
+        rom: &Bound<'py, PyAny>,
+        seed: u64,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let rom = match rom.cast::<PyBytes>() {
+            Ok(bytes) => bytes.as_bytes().to_vec(),
+            Err(_) => {
+                let path: PathBuf = rom.extract()?;
+                RomLoader::load(&path).map_err(value_error)?.data
+            }
+        };
+        let frame = self.env.reset(&rom, seed).map_err(value_error)?;
+        Ok(PyBytes::new(py, &frame))
+    }
+
+    /// Hold the keys in `action_mask`, bit n for key n, for `frame_skip` frames.
+    /// Returns `(framebuffer, reward, done)`.
+    fn step<'py>(
+        &mut self,
+        py: Python<'py>,
+        action_mask: u16,
+    ) -> PyResult<(Bound<'py, PyBytes>, f64, bool)> {
+        let (frame, reward, done) = self.env.step(action_mask).map_err(value_error)?;
+        Ok((PyBytes::new(py, &frame), reward, done))
+    }
+
+    fn clone_state(&self) -> PyResult<PyState> {
+        let state = self.env.clone_state().map_err(value_error)?;
+        Ok(PyState { state })
+    }
+
+    fn restore_state(&mut self, state: PyRef<'_, PyState>) -> PyResult<()> {
+        self.env.restore_state(&state.state).map_err(value_error)
+    }
+
+    /// `(width, height)` of the framebuffer.
+    #[getter]
+    fn resolution(&self) -> (usize, usize) {
+        self.env.resolution()
+    }
+
+    #[getter]
+    fn frame_skip(&self) -> u32 {
+        self.env.frame_skip
+    }
+
+    #[setter]
+    fn set_frame_skip(&mut self, frame_skip: u32) -> PyResult<()> {
+        if frame_skip == 0 {
+            return Err(PyValueError::new_err("frame_skip must be at least 1"));
+        }
+        self.env.frame_skip = frame_skip;
+        Ok(())
+    }
+}
+
+/// A saved `Env`, from `clone_state`.
+#[pyclass(name = "State", module = "chip8", frozen)]
+struct PyState {
+    state: State,
+}
+
+#[pymodule]
+fn chip8(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyEnv>()?;
+    module.add_class::<PyState>()?;
+    Ok(())
+}
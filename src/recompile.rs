@@ -0,0 +1,341 @@
+use crate::instruction::{decode, Instruction};
+use crate::machine::Machine;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// every instruction that can run by following jumps, calls and both sides of each skip
+// from the start of the ROM, loaded where `machine` loads it. Bnnn, RET and invalid
+// opcodes end a path, where a computed jump goes is only known at runtime
+pub fn reachable(rom: &[u8], machine: &Machine) -> BTreeMap<usize, Instruction> {
+    let start = machine.load_address;
+    let end = start + rom.len();
+    let mut code = BTreeMap::new();
+    let mut pending = vec![start];
+
+    while let Some(addr) = pending.pop() {
+        if addr < start || addr + 1 >= end || code.contains_key(&addr) {
+            continue;
+        }
+        let offset = addr - start;
+        let opcode = (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+        let instruction = match decode(opcode) {
+            Ok(instruction) => instruction,
+            Err(_) => continue,
+        };
+        code.insert(addr, instruction);
+
+        match instruction {
+            Instruction::Jp(target) => pending.push(target as usize),
+            Instruction::Call(target) => pending.extend([addr + 2, target as usize]),
+            Instruction::Ret | Instruction::JpV0(_) => (),
+            Instruction::SeByte(..)
+            | Instruction::SneByte(..)
+            | Instruction::SeReg(..)
+            | Instruction::SneReg(..)
+            | Instruction::Skp(_)
+            | Instruction::Sknp(_) => pending.extend([addr + 2, addr + 4]),
+            _ => pending.push(addr + 2),
+        }
+    }
+    code
+}
+
+// the Rust statements for one instruction, or None where it runs through `CPU::execute`
+fn translate(addr: usize, instruction: Instruction) -> Option<String> {
+    use crate::instruction::Instruction::*;
+
+    let next = addr + 2;
+    let skip = |condition: String| {
+        format!(
+            "cpu.program_counter = if {} {{ 0x{:03X} }} else {{ 0x{:03X} }};",
+            condition,
+            addr + 4,
+            next
+        )
+    };
+    let logic = |x: u8, y: u8, op: &str| {
+        format!(
+            "cpu.registers[0x{x:X}] {op}= cpu.registers[0x{y:X}];\n\
+             if cpu.quirks.vf_reset {{\n    cpu.registers[0xF] = 0;\n}}",
+            x = x,
+            y = y,
+            op = op
+        )
+    };
+    let shift = |x: u8, y: u8, flag: &str, op: &str| {
+        let mut code = String::new();
+        if x != y {
+            code = format!(
+                "if !cpu.quirks.shift_in_place {{\n    cpu.registers[0x{x:X}] = cpu.registers[0x{y:X}];\n}}\n",
+                x = x,
+                y = y
+            );
+        }
+        code.push_str(&format!(
+            "cpu.registers[0xF] = cpu.registers[0x{x:X}] {flag};\n\
+             cpu.registers[0x{x:X}] {op}= 1;",
+            x = x,
+            flag = flag,
+            op = op
+        ));
+        code
+    };
+
+    let code = match instruction {
+        Jp(target) => return Some(format!("cpu.program_counter = 0x{:03X};", target)),
+        SeByte(x, kk) => return Some(skip(format!("cpu.registers[0x{:X}] == 0x{:02X}", x, kk))),
+        SneByte(x, kk) => return Some(skip(format!("cpu.registers[0x{:X}] != 0x{:02X}", x, kk))),
+        SeReg(x, y) => {
+            return Some(skip(format!(
+                "cpu.registers[0x{:X}] == cpu.registers[0x{:X}]",
+                x, y
+            )))
+        }
+        SneReg(x, y) => {
+            return Some(skip(format!(
+                "cpu.registers[0x{:X}] != cpu.registers[0x{:X}]",
+                x, y
+            )))
+        }
+        LdByte(x, kk) => format!("cpu.registers[0x{:X}] = 0x{:02X};", x, kk),
+        AddByte(x, kk) => format!(
+            "cpu.registers[0x{x:X}] = cpu.registers[0x{x:X}].wrapping_add(0x{kk:02X});",
+            x = x,
+            kk = kk
+        ),
+        LdReg(x, y) if x == y => String::new(),
+        LdReg(x, y) => format!("cpu.registers[0x{:X}] = cpu.registers[0x{:X}];", x, y),
+        Or(x, y) => logic(x, y, "|"),
+        And(x, y) => logic(x, y, "&"),
+        Xor(x, y) => logic(x, y, "^"),
+        AddReg(x, y) => format!(
+            "let (sum, carry) = cpu.registers[0x{x:X}].overflowing_add(cpu.registers[0x{y:X}]);\n\
+             cpu.registers[0xF] = carry as u8;\n\
+             cpu.registers[0x{x:X}] = sum;",
+            x = x,
+            y = y
+        ),
+        Sub(x, y) | Subn(x, y) => {
+            let (a, b) = match instruction {
+                Sub(..) => (x, y),
+                _ => (y, x),
+            };
+            format!(
+                "let (a, b) = (cpu.registers[0x{a:X}], cpu.registers[0x{b:X}]);\n\
+                 cpu.registers[0xF] = (a > b) as u8;\n\
+                 cpu.registers[0x{x:X}] = a.wrapping_sub(b);",
+                a = a,
+                b = b,
+                x = x
+            )
+        }
+        Shr(x, y) => shift(x, y, "& 1", ">>"),
+        Shl(x, y) => shift(x, y, ">> 7", "<<"),
+        LdI(target) => format!("cpu.i_register = 0x{:03X};", target),
+        AddI(x) => format!(
+            "cpu.i_register = cpu.i_register.wrapping_add(cpu.registers[0x{:X}] as u32);",
+            x
+        ),
+        LdVxDt(x) => format!("cpu.registers[0x{:X}] = cpu.delay_timer;", x),
+        LdDtVx(x) => format!("cpu.delay_timer = cpu.registers[0x{:X}];", x),
+        LdStVx(x) => format!("cpu.sound_timer = cpu.registers[0x{:X}];", x),
+        _ => return None,
+    };
+    Some(
+        format!("cpu.program_counter = 0x{:03X};\n{}", next, code)
+            .trim_end()
+            .to_string(),
+    )
+}
+
+// a Rust module that runs `rom` recompiled, for building in with `--features recompiled`
+pub fn generate(rom: &[u8], name: &str, machine: &Machine) -> String {
+    let code = reachable(rom, machine);
+    let mut out = String::new();
+
+    writeln!(
+        out,
+        "// Recompiled from {} by `recompile`, regenerate it rather than editing it.",
+        name
+    )
+    .unwrap();
+    out.push_str(
+        "// Build with `--features recompiled` and run with `--backend recompiled`.\n\
+         \n\
+         use crate::cpu::CPU;\n\
+         #[allow(unused_imports)]\n\
+         use crate::instruction::Instruction;\n\
+         \n",
+    );
+
+    writeln!(out, "pub const ROM: [u8; {}] = [", rom.len()).unwrap();
+    for row in rom.chunks(16) {
+        let bytes: Vec<String> = row.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        writeln!(out, "    {},", bytes.join(", ")).unwrap();
+    }
+    out.push_str("];\n\n");
+
+    out.push_str(
+        "// is the code at `addr` still what was recompiled\n\
+         fn original(cpu: &CPU, addr: usize, opcode: u16) -> bool {\n\
+         \x20   cpu.heap[addr] == (opcode >> 8) as u8 && cpu.heap[addr + 1] == opcode as u8\n\
+         }\n\
+         \n\
+         /// Run the instruction at the PC. Code that was recompiled and hasn't been\n\
+         /// overwritten runs natively, everything else, such as the targets of computed\n\
+         /// jumps, runs in the interpreter.\n\
+         pub fn step(cpu: &mut CPU) {\n\
+         \x20   if cpu.tracer.is_some() {\n\
+         \x20       cpu.tick();\n\
+         \x20       return;\n\
+         \x20   }\n\
+         \x20   match cpu.program_counter {\n",
+    );
+    for (&addr, &instruction) in &code {
+        let opcode = instruction.encode();
+        writeln!(
+            out,
+            "        // {}\n        0x{:03X} if original(cpu, 0x{:03X}, 0x{:04X}) => {{",
+            instruction, addr, addr, opcode
+        )
+        .unwrap();
+        let body = translate(addr, instruction).unwrap_or_else(|| {
+            format!(
+                "cpu.heap.fetch(0x{:03X});\ncpu.program_counter = 0x{:03X};\ncpu.execute(Instruction::{:?});",
+                addr,
+                addr + 2,
+                instruction
+            )
+        });
+        for line in body.lines() {
+            writeln!(out, "            {}", line).unwrap();
+        }
+        out.push_str("        }\n");
+    }
+    out.push_str(
+        "        _ => {\n\
+         \x20           cpu.tick();\n\
+         \x20           return;\n\
+         \x20       }\n\
+         \x20   }\n\
+         \x20   cpu.cycles += 1;\n\
+         }\n",
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variant::Variant;
+
+    fn chip8() -> Machine {
+        Machine::for_variant(Variant::Chip8)
+    }
+
+    #[test]
+    fn test_reachable() {
+        let rom = [
+            0x22, 0x0A, // 200: CALL 0x20A
+            0x30, 0x01, // 202: SE V0, 1
+            0x12, 0x08, // 204: JP 0x208
+            0xB2, 0x00, // 206: JP V0, 0x200
+            0x12, 0x08, // 208: JP 0x208
+            0x60, 0x01, // 20A: LD V0, 1
+            0x00, 0xEE, // 20C: RET
+            0xF0, 0x90, // 20E: sprite data, never reached
+        ];
+        let code = reachable(&rom, &chip8());
+        assert_eq!(
+            code.keys().copied().collect::<Vec<_>>(),
+            vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A, 0x20C]
+        );
+        assert_eq!(code[&0x206], Instruction::JpV0(0x200));
+    }
+
+    #[test]
+    fn test_generate() {
+        // LD V1, 3; SHR V1; DRW V1, V1, 1; JP 0x206
+        let module = generate(
+            &[0x61, 0x03, 0x81, 0x16, 0xD1, 0x11, 0x12, 0x06],
+            "test.ch8",
+            &chip8(),
+        );
+        assert!(module.contains("pub const ROM: [u8; 8] = [\n    0x61, 0x03,"));
+        assert!(module.contains(
+            "        // LD V1, 0x03\n        0x200 if original(cpu, 0x200, 0x6103) => {\n            \
+             cpu.program_counter = 0x202;\n            cpu.registers[0x1] = 0x03;\n        }\n"
+        ));
+        assert!(module.contains("cpu.registers[0xF] = cpu.registers[0x1] & 1;"));
+        assert!(module.contains("cpu.execute(Instruction::Drw(1, 1, 1));"));
+        assert!(module.contains(
+            "0x206 if original(cpu, 0x206, 0x1206) => {\n            cpu.program_counter = 0x206;"
+        ));
+    }
+
+    #[test]
+    fn test_load_address() {
+        // ETI-660 ROMs load at 0x600: LD V0, 1; JP 0x602
+        let eti660 = Machine {
+            load_address: 0x600,
+            ..chip8()
+        };
+        let rom = [0x60, 0x01, 0x16, 0x02];
+        let code = reachable(&rom, &eti660);
+        assert_eq!(code.keys().copied().collect::<Vec<_>>(), vec![0x600, 0x602]);
+        let module = generate(&rom, "eti660.ch8", &eti660);
+        assert!(module.contains("0x600 if original(cpu, 0x600, 0x6001) => {"));
+        assert!(module.contains("0x602 if original(cpu, 0x602, 0x1602) => {"));
+    }
+
+    // the ROM src/recompiled_fixture.rs was generated from
+    const FIXTURE: [u8; 30] = [
+        0x60, 0x05, // 200: LD V0, 5
+        0x61, 0xFE, // 202: LD V1, 0xFE
+        0x81, 0x04, // 204: ADD V1, V0
+        0x82, 0x15, // 206: SUB V2, V1
+        0x83, 0x26, // 208: SHR V3, V2
+        0xA3, 0x00, // 20A: LD I, 0x300
+        0xF1, 0x33, // 20C: LD B, V1
+        0x22, 0x1A, // 20E: CALL 0x21A
+        0x70, 0xFF, // 210: ADD V0, 0xFF
+        0x30, 0x00, // 212: SE V0, 0
+        0x12, 0x04, // 214: JP 0x204
+        0x12, 0x16, // 216: JP 0x216
+        0x00, 0x00, // 218: never reached
+        0xD3, 0x05, // 21A: DRW V3, V0, 5
+        0x00, 0xEE, // 21C: RET
+    ];
+
+    #[test]
+    fn test_fixture_is_generated() {
+        assert_eq!(
+            generate(&FIXTURE, "fixture.ch8", &chip8()),
+            include_str!("recompiled_fixture.rs")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "recompiled")]
+    fn test_recompiled_matches_interpreter() {
+        use crate::cpu::cpu_with_program;
+        use crate::recompiled;
+
+        assert_eq!(recompiled::ROM, FIXTURE);
+        let mut interpreted = cpu_with_program(&FIXTURE);
+        let mut recompiled = cpu_with_program(&FIXTURE);
+        while interpreted.program_counter != 0x216 {
+            interpreted.tick();
+            recompiled::step(&mut recompiled);
+            assert_eq!(recompiled.program_counter, interpreted.program_counter);
+            assert_eq!(recompiled.registers, interpreted.registers);
+            assert_eq!(recompiled.i_register, interpreted.i_register);
+            assert_eq!(recompiled.stack_pointer, interpreted.stack_pointer);
+            assert_eq!(recompiled.heap[..], interpreted.heap[..]);
+            assert_eq!(recompiled.display, interpreted.display);
+            assert_eq!(recompiled.cycles, interpreted.cycles);
+        }
+        // five times round the loop, jumping back after all but the last
+        assert_eq!(recompiled.cycles, 2 + 5 * 10 + 4);
+    }
+}
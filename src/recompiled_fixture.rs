@@ -0,0 +1,112 @@
+// Recompiled from fixture.ch8 by `recompile`, regenerate it rather than editing it.
+// Build with `--features recompiled` and run with `--backend recompiled`.
+
+use crate::cpu::CPU;
+#[allow(unused_imports)]
+use crate::instruction::Instruction;
+
+pub const ROM: [u8; 30] = [
+    0x60, 0x05, 0x61, 0xFE, 0x81, 0x04, 0x82, 0x15, 0x83, 0x26, 0xA3, 0x00, 0xF1, 0x33, 0x22, 0x1A,
+    0x70, 0xFF, 0x30, 0x00, 0x12, 0x04, 0x12, 0x16, 0x00, 0x00, 0xD3, 0x05, 0x00, 0xEE,
+];
+
+// is the code at `addr` still what was recompiled
+fn original(cpu: &CPU, addr: usize, opcode: u16) -> bool {
+    cpu.heap[addr] == (opcode >> 8) as u8 && cpu.heap[addr + 1] == opcode as u8
+}
+
+/// Run the instruction at the PC. Code that was recompiled and hasn't been
+/// overwritten runs natively, everything else, such as the targets of computed
+/// jumps, runs in the interpreter.
+pub fn step(cpu: &mut CPU) {
+    if cpu.tracer.is_some() {
+        cpu.tick();
+        return;
+    }
+    match cpu.program_counter {
+        // LD V0, 0x05
+        0x200 if original(cpu, 0x200, 0x6005) => {
+            cpu.program_counter = 0x202;
+            cpu.registers[0x0] = 0x05;
+        }
+        // LD V1, 0xFE
+        0x202 if original(cpu, 0x202, 0x61FE) => {
+            cpu.program_counter = 0x204;
+            cpu.registers[0x1] = 0xFE;
+        }
+        // ADD V1, V0
+        0x204 if original(cpu, 0x204, 0x8104) => {
+            cpu.program_counter = 0x206;
+            let (sum, carry) = cpu.registers[0x1].overflowing_add(cpu.registers[0x0]);
+            cpu.registers[0xF] = carry as u8;
+            cpu.registers[0x1] = sum;
+        }
+        // SUB V2, V1
+        0x206 if original(cpu, 0x206, 0x8215) => {
+            cpu.program_counter = 0x208;
+            let (a, b) = (cpu.registers[0x2], cpu.registers[0x1]);
+            cpu.registers[0xF] = (a > b) as u8;
+            cpu.registers[0x2] = a.wrapping_sub(b);
+        }
+        // SHR V3, V2
+        0x208 if original(cpu, 0x208, 0x8326) => {
+            cpu.program_counter = 0x20A;
+            if !cpu.quirks.shift_in_place {
+                cpu.registers[0x3] = cpu.registers[0x2];
+            }
+            cpu.registers[0xF] = cpu.registers[0x3] & 1;
+            cpu.registers[0x3] >>= 1;
+        }
+        // LD I, 0x300
+        0x20A if original(cpu, 0x20A, 0xA300) => {
+            cpu.program_counter = 0x20C;
+            cpu.i_register = 0x300;
+        }
+        // LD B, V1
+        0x20C if original(cpu, 0x20C, 0xF133) => {
+            cpu.heap.fetch(0x20C);
+            cpu.program_counter = 0x20E;
+            cpu.execute(Instruction::LdB(1));
+        }
+        // CALL 0x21A
+        0x20E if original(cpu, 0x20E, 0x221A) => {
+            cpu.heap.fetch(0x20E);
+            cpu.program_counter = 0x210;
+            cpu.execute(Instruction::Call(538));
+        }
+        // ADD V0, 0xFF
+        0x210 if original(cpu, 0x210, 0x70FF) => {
+            cpu.program_counter = 0x212;
+            cpu.registers[0x0] = cpu.registers[0x0].wrapping_add(0xFF);
+        }
+        // SE V0, 0x00
+        0x212 if original(cpu, 0x212, 0x3000) => {
+            cpu.program_counter = if cpu.registers[0x0] == 0x00 { 0x216 } else { 0x214 };
+        }
+        // JP 0x204
+        0x214 if original(cpu, 0x214, 0x1204) => {
+            cpu.program_counter = 0x204;
+        }
+        // JP 0x216
+        0x216 if original(cpu, 0x216, 0x1216) => {
+            cpu.program_counter = 0x216;
+        }
+        // DRW V3, V0, 5
+        0x21A if original(cpu, 0x21A, 0xD305) => {
+            cpu.heap.fetch(0x21A);
+            cpu.program_counter = 0x21C;
+            cpu.execute(Instruction::Drw(3, 0, 5));
+        }
+        // RET
+        0x21C if original(cpu, 0x21C, 0x00EE) => {
+            cpu.heap.fetch(0x21C);
+            cpu.program_counter = 0x21E;
+            cpu.execute(Instruction::Ret);
+        }
+        _ => {
+            cpu.tick();
+            return;
+        }
+    }
+    cpu.cycles += 1;
+}
@@ -1,32 +1,87 @@
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
+use crate::instruction::Instruction;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str;
+
+// a loaded program, with a source map when it was assembled from text
+pub struct Rom {
+    pub data: Vec<u8>,
+    pub source_map: Option<SourceMap>,
+}
+
+// which assembly source line each part of a ROM came from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    pub file: PathBuf,
+    // ROM offset where each line's output starts, to its 1-based line number
+    pub lines: BTreeMap<usize, usize>,
+    // ROM length, offsets past it have no line
+    pub len: usize,
+}
+
+impl SourceMap {
+    // the line the byte at `offset` was assembled from
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        if offset >= self.len {
+            return None;
+        }
+        self.lines
+            .range(..=offset)
+            .next_back()
+            .map(|(_, &line)| line)
+    }
+
+    // the first offset assembled from `line`, moving down to the next line with code
+    // returns the offset and the line it actually belongs to
+    pub fn offset_of(&self, line: usize) -> Option<(usize, usize)> {
+        self.lines
+            .iter()
+            .filter(|(_, &l)| l >= line)
+            .min_by_key(|(&offset, &l)| (l, offset))
+            .map(|(&offset, &l)| (offset, l))
+    }
+
+    pub fn is_for(&self, path: &Path) -> bool {
+        fs::canonicalize(path).is_ok_and(|path| path == self.file)
+    }
+}
 
 pub struct RomLoader;
 
 impl RomLoader {
     // load a ROM file (either binary or text assembly)
-    pub fn load(path: &Path) -> io::Result<Vec<u8>> {
-        let mut file = File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        // check if file is text-based assembly
-        if contents.contains(';') || contents.trim().starts_with("00E0") {
-            Ok(Self::parse_assembly(&contents))
-        } else {
-            // handle binary ROM
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer)?;
-            Ok(buffer)
+    pub fn load(path: &Path) -> io::Result<Rom> {
+        let buffer = fs::read(path)?;
+
+        // check if file is text-based assembly, binary ROMs are rarely valid UTF-8
+        match str::from_utf8(&buffer) {
+            Ok(contents) if contents.contains(';') || contents.trim().starts_with("00E0") => {
+                let (data, lines) = Self::parse_assembly(contents);
+                let source_map = SourceMap {
+                    file: fs::canonicalize(path)?,
+                    lines,
+                    len: data.len(),
+                };
+                Ok(Rom {
+                    data,
+                    source_map: Some(source_map),
+                })
+            }
+            _ => Ok(Rom {
+                data: buffer,
+                source_map: None,
+            }),
         }
     }
 
-    // parse text-based assembly into binary
-    fn parse_assembly(contents: &str) -> Vec<u8> {
+    // parse text-based assembly into binary, and the line each output offset came from
+    fn parse_assembly(contents: &str) -> (Vec<u8>, BTreeMap<usize, usize>) {
         let mut binary = Vec::new();
+        let mut lines = BTreeMap::new();
 
-        for line in contents.lines() {
+        for (number, line) in contents.lines().enumerate() {
             // skip empty lines and comments
             let line = line.trim();
             if line.is_empty() || line.starts_with(';') {
@@ -39,7 +94,7 @@ impl RomLoader {
                 .next()
                 .unwrap()
                 .split(':')
-                .last()
+                .next_back()
                 .unwrap()
                 .trim();
 
@@ -48,14 +103,23 @@ impl RomLoader {
                 continue;
             }
 
-            // parse the entire opcode as a single 16-bit value
+            // a hex opcode, a mnemonic such as `LD V1, 0x03`, or a longer run of
+            // hex digits as raw data such as sprite rows
+            let offset = binary.len();
             if let Some((high, low)) = Self::opcode_to_bytes(opcode_str) {
                 binary.push(high);
                 binary.push(low);
+            } else if let Ok(instruction) = opcode_str.parse::<Instruction>() {
+                binary.extend_from_slice(&instruction.encode().to_be_bytes());
+            } else if let Some(data) = Self::data_to_bytes(opcode_str) {
+                binary.extend(data);
+            }
+            if binary.len() > offset {
+                lines.insert(offset, number + 1);
             }
         }
 
-        binary
+        (binary, lines)
     }
 
     // helper function to convert a single opcode to bytes
@@ -80,6 +144,23 @@ impl RomLoader {
             None
         }
     }
+
+    // helper function to convert a run of hex digit pairs to bytes
+    fn data_to_bytes(data_str: &str) -> Option<Vec<u8>> {
+        let data = data_str
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+
+        if data.is_empty() || data.len() % 2 != 0 || !data.is_ascii() {
+            return None;
+        }
+
+        (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&data[i..i + 2], 16).ok())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -97,13 +178,47 @@ mod tests {
             F0808080F0          ; Sprite for 'C'
         ";
 
-        let binary = RomLoader::parse_assembly(input);
+        let (binary, _) = RomLoader::parse_assembly(input);
         assert_eq!(
             binary,
             vec![0x00, 0xE0, 0xA2, 0x00, 0x61, 0x00, 0xF0, 0x80, 0x80, 0x80, 0xF0]
         );
     }
 
+    #[test]
+    fn test_parse_mnemonics() {
+        let input = "
+            start: CLS
+            LD V1, 0x03         ; mnemonics and hex opcodes mix
+            7101
+            drw v1, v2, 5
+        ";
+
+        let (binary, _) = RomLoader::parse_assembly(input);
+        assert_eq!(binary, vec![0x00, 0xE0, 0x61, 0x03, 0x71, 0x01, 0xD1, 0x25]);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let input = "; header\n00E0\n\nloop: 1202 ; forever\nF0808080F0\n";
+        let (binary, lines) = RomLoader::parse_assembly(input);
+        let map = SourceMap {
+            file: PathBuf::new(),
+            lines,
+            len: binary.len(),
+        };
+
+        assert_eq!(map.line_at(0), Some(2));
+        assert_eq!(map.line_at(2), Some(4));
+        assert_eq!(map.line_at(8), Some(5));
+        assert_eq!(map.line_at(9), None);
+
+        // breakpoints on blank lines and comments move to the next instruction
+        assert_eq!(map.offset_of(1), Some((0, 2)));
+        assert_eq!(map.offset_of(3), Some((2, 4)));
+        assert_eq!(map.offset_of(6), None);
+    }
+
     #[test]
     fn test_opcode_to_bytes() {
         assert_eq!(RomLoader::opcode_to_bytes("00E0"), Some((0x00, 0xE0)));
@@ -0,0 +1,98 @@
+use crate::config::{invalid, rom_hash};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Flags = [u8; 16];
+
+/// The RPL user flags a ROM saved with `Fx75`, kept in `<sha1 of the ROM>.rpl` in the
+/// save directory. On the HP48 they outlived the program, which is how SCHIP games
+/// keep high scores. Keying by hash lets a renamed or moved ROM find its flags.
+pub struct FlagStore {
+    path: PathBuf,
+}
+
+impl FlagStore {
+    pub fn new(dir: &Path, rom: &[u8]) -> Self {
+        FlagStore {
+            path: dir.join(format!("{}.rpl", rom_hash(rom))),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // the saved flags, all zero if the ROM never saved any
+    pub fn load(&self) -> io::Result<Flags> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Flags::default()),
+            Err(e) => return Err(e),
+        };
+        let mut flags = Flags::default();
+        if data.len() > flags.len() {
+            return Err(invalid(format!(
+                "{}: expected at most {} bytes of flags, found {}",
+                self.path.display(),
+                flags.len(),
+                data.len()
+            )));
+        }
+        flags[..data.len()].copy_from_slice(&data);
+        Ok(flags)
+    }
+
+    pub fn save(&self, flags: &Flags) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, flags)
+    }
+
+    // forget the saved flags, the ROM starts from zeros again
+    pub fn reset(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_flags_round_trip() {
+        let dir = env::temp_dir().join(format!("chip8-rpl-{}", process::id()));
+        let store = FlagStore::new(&dir, &[0x12, 0x00]);
+        assert!(store
+            .path()
+            .ends_with(format!("{}.rpl", rom_hash(&[0x12, 0x00]))));
+        assert_eq!(store.load().unwrap(), Flags::default());
+
+        let mut flags = Flags::default();
+        flags[..3].copy_from_slice(&[9, 0, 42]);
+        store.save(&flags).unwrap();
+        assert_eq!(store.load().unwrap(), flags);
+        // another ROM has its own
+        assert_eq!(
+            FlagStore::new(&dir, &[0x13]).load().unwrap(),
+            Flags::default()
+        );
+
+        // files from interpreters with 8 flags still load
+        fs::write(store.path(), [1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(store.load().unwrap()[..9], [1, 2, 3, 4, 5, 6, 7, 8, 0]);
+        fs::write(store.path(), [0; 17]).unwrap();
+        assert!(store.load().is_err());
+
+        store.reset().unwrap();
+        store.reset().unwrap();
+        assert_eq!(store.load().unwrap(), Flags::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+use crate::backend::Backend;
+use crate::config::invalid;
+use crate::cpu::{StepOutcome, CPU};
+use crate::frontend::Palette;
+use crate::input::InputSource;
+use crate::keyboard::Keyboard;
+use crate::memory::{WatchKind, Watchpoint};
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST, INT};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// functions scripts registered to be called back
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    exec: BTreeMap<usize, Vec<FnPtr>>,
+    write: BTreeMap<usize, Vec<FnPtr>>,
+    key_wait: Vec<FnPtr>,
+}
+
+/// A Rhai script driving the emulator. Its top level runs once at startup and
+/// registers hooks with `on_frame`, `on_exec`, `on_write` and `on_key_wait`; they
+/// can read and write the machine, press keys, take screenshots and assert.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    hooks: Rc<RefCell<Hooks>>,
+    // the CPU is swapped in here while script code runs, for the functions it calls
+    cpu: Rc<RefCell<CPU>>,
+    quit: Rc<Cell<bool>>,
+    // whether the last check found the program waiting for a key
+    waiting: bool,
+}
+
+// CHIP-8 keys the script holds down, polled with the other input devices
+pub struct ScriptKeys {
+    held: Rc<Cell<u16>>,
+}
+
+impl InputSource for ScriptKeys {
+    fn poll(&mut self) -> u16 {
+        self.held.get()
+    }
+}
+
+impl Script {
+    pub fn load(path: &Path, cpu: &mut CPU, palette: Palette) -> io::Result<(Script, ScriptKeys)> {
+        let source = fs::read_to_string(path)?;
+        let located = |e: &dyn ToString| invalid(format!("{}: {}", path.display(), e.to_string()));
+
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let slot = Rc::new(RefCell::new(CPU::new(Arc::new(Keyboard::new()))));
+        let held = Rc::new(Cell::new(0));
+        let quit = Rc::new(Cell::new(false));
+
+        let mut engine = Engine::new();
+        register_machine(&mut engine, &slot);
+        register_input(&mut engine, &held, palette, &slot);
+        register_hooks(&mut engine, &hooks);
+        let stop = quit.clone();
+        engine.register_fn("quit", move || stop.set(true));
+        engine.register_fn(
+            "assert",
+            |condition: bool, message: &str| -> ScriptResult<()> {
+                match condition {
+                    true => Ok(()),
+                    false => Err(format!("assertion failed: {}", message).into()),
+                }
+            },
+        );
+        engine.register_fn(
+            "assert_eq",
+            |actual: INT, expected: INT, message: &str| -> ScriptResult<()> {
+                match actual == expected {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "assertion failed: {}: expected {}, found {}",
+                        message, expected, actual
+                    )
+                    .into()),
+                }
+            },
+        );
+
+        let ast = engine.compile(&source).map_err(|e| located(&e))?;
+        let mut script = Script {
+            engine,
+            ast,
+            hooks,
+            cpu: slot,
+            quit,
+            waiting: false,
+        };
+        script
+            .with_cpu(cpu, |script| script.engine.run_ast(&script.ast))
+            .map_err(|e| located(&e))?;
+        Ok((script, ScriptKeys { held }))
+    }
+
+    /// Run a frame's worth of instructions. With address or write hooks the
+    /// backend goes an instruction at a time so they fire where they should.
+    pub fn run_frame(
+        &mut self,
+        cpu: &mut CPU,
+        backend: &mut Backend,
+        count: u32,
+    ) -> io::Result<StepOutcome> {
+        let stepping = {
+            let hooks = self.hooks.borrow();
+            !hooks.exec.is_empty() || !hooks.write.is_empty()
+        };
+        if !stepping {
+            let outcome = backend.run(cpu, count);
+            self.check_key_wait(cpu)?;
+            return Ok(outcome);
+        }
+
+        for _ in 0..count {
+            let pc = cpu.program_counter;
+            let exec = self.hooks.borrow().exec.get(&pc).cloned();
+            for hook in exec.unwrap_or_default() {
+                self.call(cpu, &hook, (pc as INT,))?;
+            }
+            backend.step(cpu, 1);
+            for hit in cpu.heap.take_hits() {
+                let write = self.hooks.borrow().write.get(&hit.addr).cloned();
+                for hook in write.unwrap_or_default() {
+                    self.call(cpu, &hook, (hit.addr as INT, hit.value as INT))?;
+                }
+            }
+            self.check_key_wait(cpu)?;
+            if self.quit.get() {
+                break;
+            }
+        }
+        Ok(cpu.outcome())
+    }
+
+    // call the frame hooks, after the timers counted down for it
+    pub fn end_frame(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        let hooks = self.hooks.borrow().frame.clone();
+        for hook in hooks {
+            self.call(cpu, &hook, (cpu.frames as INT,))?;
+        }
+        Ok(())
+    }
+
+    // whether the script called `quit`
+    pub fn is_finished(&self) -> bool {
+        self.quit.get()
+    }
+
+    // call the key wait hooks when the program starts waiting for a key
+    fn check_key_wait(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        let waiting = cpu.key_wait.is_some();
+        if waiting && !self.waiting {
+            let hooks = self.hooks.borrow().key_wait.clone();
+            for hook in hooks {
+                self.call(cpu, &hook, ())?;
+            }
+        }
+        self.waiting = waiting;
+        Ok(())
+    }
+
+    fn call(&mut self, cpu: &mut CPU, hook: &FnPtr, args: impl FuncArgs) -> io::Result<()> {
+        self.with_cpu(cpu, |script| {
+            hook.call::<Dynamic>(&script.engine, &script.ast, args)
+                .map(|_| ())
+        })
+        .map_err(|e| invalid(format!("script error: {}", e)))
+    }
+
+    // run script code with `cpu` where the script's functions find it, and the
+    // write hooks watched on its memory afterwards
+    fn with_cpu<T>(&mut self, cpu: &mut CPU, run: impl FnOnce(&Self) -> T) -> T {
+        mem::swap(cpu, &mut self.cpu.borrow_mut());
+        let result = run(self);
+        mem::swap(cpu, &mut self.cpu.borrow_mut());
+
+        cpu.heap.watchpoints = self
+            .hooks
+            .borrow()
+            .write
+            .keys()
+            .map(|&addr| Watchpoint {
+                kind: WatchKind::Write,
+                addr,
+                len: 1,
+                value: None,
+            })
+            .collect();
+        result
+    }
+}
+
+fn error<T>(message: String) -> ScriptResult<T> {
+    Err(message.into())
+}
+
+// a register number, a key or a byte from a script
+fn nibble(n: INT) -> ScriptResult<usize> {
+    match n {
+        0..=0xF => Ok(n as usize),
+        _ => error(format!("{} isn't a register or key, expected 0 to 15", n)),
+    }
+}
+
+fn byte(value: INT) -> ScriptResult<u8> {
+    match value {
+        0..=0xFF => Ok(value as u8),
+        _ => error(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+// reg, set_reg, get_i, set_i, pc, set_pc, dt, set_dt, st, set_st, peek, poke,
+// frame and cycles
+fn register_machine(engine: &mut Engine, slot: &Rc<RefCell<CPU>>) {
+    let cpu = slot.clone();
+    engine.register_fn("reg", move |n: INT| -> ScriptResult<INT> {
+        Ok(cpu.borrow().registers[nibble(n)?] as INT)
+    });
+    let cpu = slot.clone();
+    engine.register_fn("set_reg", move |n: INT, value: INT| -> ScriptResult<()> {
+        cpu.borrow_mut().registers[nibble(n)?] = byte(value)?;
+        Ok(())
+    });
+    let cpu = slot.clone();
+    engine.register_fn("get_i", move || cpu.borrow().i_register as INT);
+    let cpu = slot.clone();
+    engine.register_fn("set_i", move |value: INT| {
+        cpu.borrow_mut().i_register = value as u32
+    });
+    let cpu = slot.clone();
+    engine.register_fn("pc", move || cpu.borrow().program_counter as INT);
+    let cpu = slot.clone();
+    engine.register_fn("set_pc", move |addr: INT| -> ScriptResult<()> {
+        let mut cpu = cpu.borrow_mut();
+        match usize::try_from(addr) {
+            Ok(addr) if addr + 1 < cpu.heap.len() => {
+                cpu.program_counter = addr;
+                Ok(())
+            }
+            _ => error(format!("{:#X} is outside memory", addr)),
+        }
+    });
+    let cpu = slot.clone();
+    engine.register_fn("dt", move || cpu.borrow().delay_timer as INT);
+    let cpu = slot.clone();
+    engine.register_fn("set_dt", move |value: INT| -> ScriptResult<()> {
+        cpu.borrow_mut().delay_timer = byte(value)?;
+        Ok(())
+    });
+    let cpu = slot.clone();
+    engine.register_fn("st", move || cpu.borrow().sound_timer as INT);
+    let cpu = slot.clone();
+    engine.register_fn("set_st", move |value: INT| -> ScriptResult<()> {
+        cpu.borrow_mut().sound_timer = byte(value)?;
+        Ok(())
+    });
+    // memory goes around the bus, so the script doesn't set off its own write hooks
+    let cpu = slot.clone();
+    engine.register_fn("peek", move |addr: INT| -> ScriptResult<INT> {
+        let cpu = cpu.borrow();
+        match usize::try_from(addr)
+            .ok()
+            .and_then(|addr| cpu.heap.get(addr))
+        {
+            Some(&value) => Ok(value as INT),
+            None => error(format!("{:#X} is outside memory", addr)),
+        }
+    });
+    let cpu = slot.clone();
+    engine.register_fn("poke", move |addr: INT, value: INT| -> ScriptResult<()> {
+        let mut cpu = cpu.borrow_mut();
+        match usize::try_from(addr) {
+            Ok(addr) if addr < cpu.heap.len() => {
+                cpu.heap.load(addr, &[byte(value)?]);
+                Ok(())
+            }
+            _ => error(format!("{:#X} is outside memory", addr)),
+        }
+    });
+    let cpu = slot.clone();
+    engine.register_fn("frame", move || cpu.borrow().frames as INT);
+    let cpu = slot.clone();
+    engine.register_fn("cycles", move || cpu.borrow().cycles as INT);
+}
+
+// press, release and screenshot
+fn register_input(
+    engine: &mut Engine,
+    held: &Rc<Cell<u16>>,
+    palette: Palette,
+    slot: &Rc<RefCell<CPU>>,
+) {
+    // held from the next frame on, like the other input devices
+    let keys = held.clone();
+    engine.register_fn("press", move |key: INT| -> ScriptResult<()> {
+        keys.set(keys.get() | 1 << nibble(key)?);
+        Ok(())
+    });
+    let keys = held.clone();
+    engine.register_fn("release", move |key: INT| -> ScriptResult<()> {
+        keys.set(keys.get() & !(1 << nibble(key)?));
+        Ok(())
+    });
+    let cpu = slot.clone();
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        screenshot(&cpu.borrow(), palette, Path::new(path))
+            .or_else(|e| error(format!("can't write {}: {}", path, e)))
+    });
+}
+
+// on_frame, on_exec, on_write and on_key_wait
+fn register_hooks(engine: &mut Engine, hooks: &Rc<RefCell<Hooks>>) {
+    let registered = hooks.clone();
+    engine.register_fn("on_frame", move |hook: FnPtr| {
+        registered.borrow_mut().frame.push(hook)
+    });
+    let registered = hooks.clone();
+    engine.register_fn("on_exec", move |addr: INT, hook: FnPtr| {
+        let mut hooks = registered.borrow_mut();
+        hooks.exec.entry(addr as usize).or_default().push(hook);
+    });
+    let registered = hooks.clone();
+    engine.register_fn("on_write", move |addr: INT, hook: FnPtr| {
+        let mut hooks = registered.borrow_mut();
+        hooks.write.entry(addr as usize).or_default().push(hook);
+    });
+    let registered = hooks.clone();
+    engine.register_fn("on_key_wait", move |hook: FnPtr| {
+        registered.borrow_mut().key_wait.push(hook)
+    });
+}
+
+// the screen as a binary PPM, in the palette's colors
+fn screenshot(cpu: &CPU, palette: Palette, path: &Path) -> io::Result<()> {
+    let (width, height) = cpu.display.resolution();
+    let mut pixels = Vec::new();
+    palette.apply(&cpu.display, &mut pixels);
+
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    for color in pixels {
+        out.write_all(&color.to_be_bytes()[1..])?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendKind;
+    use crate::cpu::cpu_with_program;
+    use crate::input::InputMux;
+    use std::env;
+    use std::process;
+
+    fn load(source: &str, cpu: &mut CPU) -> io::Result<(Script, ScriptKeys)> {
+        let path = env::temp_dir().join(format!("chip8-script-{}.rhai", process::id()));
+        fs::write(&path, source).unwrap();
+        let loaded = Script::load(&path, cpu, Palette::default());
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut cpu = cpu_with_program(&[
+            0x60, 0x05, // 200: LD V0, 5
+            0xA3, 0x00, // 202: LD I, 0x300
+            0xF0, 0x55, // 204: LD [I], V0
+            0xF1, 0x0A, // 206: LD V1, K
+            0x12, 0x08, // 208: JP 0x208
+        ]);
+        let (mut script, keys) = load(
+            "
+            poke(0x300, 1);
+            on_exec(0x204, |pc| set_reg(0, reg(0) + 1));
+            on_write(0x300, |addr, value| {
+                assert_eq(value, 6, \"the hooked register\");
+                set_reg(2, peek(0x300));
+            });
+            on_key_wait(|| press(0xB));
+            on_frame(|frame| if frame == 3 { quit(); });
+            ",
+            &mut cpu,
+        )
+        .unwrap();
+        assert_eq!(cpu.heap[0x300], 1);
+        assert_eq!(cpu.heap.watchpoints.len(), 1);
+
+        let mut input = InputMux::new(cpu.keyboard.clone());
+        input.add(Box::new(keys));
+        let mut backend = Backend::new(BackendKind::Interpreter).unwrap();
+        while !script.is_finished() {
+            input.poll();
+            script.run_frame(&mut cpu, &mut backend, 10).unwrap();
+            cpu.update_timers();
+            script.end_frame(&mut cpu).unwrap();
+        }
+        assert_eq!(cpu.registers[2], 6);
+        assert_eq!(cpu.frames, 3);
+        assert!(cpu.keyboard.is_key_pressed(0xB));
+
+        let (mut failing, _) =
+            load("on_frame(|frame| assert(false, \"nope\"));", &mut cpu).unwrap();
+        let error = failing.end_frame(&mut cpu).unwrap_err();
+        assert!(error.to_string().contains("assertion failed: nope"));
+        assert!(load("set_reg(16, 0);", &mut cpu).is_err());
+    }
+
+    #[test]
+    fn test_write_hooks_on_one_instruction() {
+        let mut cpu = cpu_with_program(&[
+            0x60, 0x7B, // 200: LD V0, 123
+            0xA3, 0x00, // 202: LD I, 0x300
+            0xF0, 0x33, // 204: LD B, V0
+            0x12, 0x06, // 206: JP 0x206
+        ]);
+        let (mut script, _) = load(
+            "
+            on_write(0x301, |addr, value| set_reg(1, value));
+            on_write(0x302, |addr, value| set_reg(2, value));
+            ",
+            &mut cpu,
+        )
+        .unwrap();
+        let mut backend = Backend::new(BackendKind::Interpreter).unwrap();
+        script.run_frame(&mut cpu, &mut backend, 4).unwrap();
+        assert_eq!(&cpu.registers[1..3], &[2, 3]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// emulated frames per second, the rate the CHIP-8 timers count down at
+pub const FRAME_RATE: u32 = 60;
+
+// speed hotkeys, read from the frontend once per host frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    TogglePause,
+    // run exactly one frame, pausing first if needed
+    AdvanceFrame,
+    // fast-forward while held
+    FastForward(bool),
+    ToggleSlowMotion,
+    MoreInstructions,
+    FewerInstructions,
+}
+
+// pause, frame advance, fast-forward, slow motion and live instructions per frame
+pub struct SpeedControl {
+    pub paused: bool,
+    pub instructions_per_frame: u32,
+    fast_forward: u32,
+    slow_motion: u32,
+    fast_forwarding: bool,
+    slowed: bool,
+    // frames left to run while paused
+    advance: u32,
+}
+
+impl SpeedControl {
+    pub fn new(instructions_per_frame: u32, fast_forward: u32, slow_motion: u32) -> Self {
+        SpeedControl {
+            paused: false,
+            instructions_per_frame,
+            fast_forward: fast_forward.max(1),
+            slow_motion: slow_motion.max(1),
+            fast_forwarding: false,
+            slowed: false,
+            advance: 0,
+        }
+    }
+
+    pub fn handle(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::TogglePause => self.paused = !self.paused,
+            Hotkey::AdvanceFrame => self.advance_frames(1),
+            Hotkey::FastForward(held) => self.fast_forwarding = held,
+            Hotkey::ToggleSlowMotion => self.slowed = !self.slowed,
+            // steps of about 10% so the adjuster stays useful from 1 to thousands
+            Hotkey::MoreInstructions => {
+                self.instructions_per_frame += (self.instructions_per_frame / 10).max(1);
+            }
+            Hotkey::FewerInstructions => {
+                let step = (self.instructions_per_frame / 10).max(1);
+                self.instructions_per_frame =
+                    self.instructions_per_frame.saturating_sub(step).max(1);
+            }
+        }
+    }
+
+    // pause, after running `frames` more frames one per present
+    pub fn advance_frames(&mut self, frames: u32) {
+        self.paused = true;
+        self.advance += frames;
+    }
+
+    // emulated frames to run before the next present
+    pub fn frames_to_run(&mut self) -> u32 {
+        if self.paused {
+            let advance = self.advance.min(1);
+            self.advance -= advance;
+            advance
+        } else if self.fast_forwarding {
+            self.fast_forward
+        } else {
+            1
+        }
+    }
+
+    // host time each present should take
+    pub fn frame_duration(&self) -> Duration {
+        let frame = Duration::from_secs(1) / FRAME_RATE;
+        if self.slowed && !self.fast_forwarding {
+            frame * self.slow_motion
+        } else {
+            frame
+        }
+    }
+
+    pub fn title(&self) -> String {
+        let mut title = format!("CHIP-8 Emulator - {} ipf", self.instructions_per_frame);
+        if self.paused {
+            title.push_str(" [paused]");
+        } else if self.fast_forwarding {
+            title.push_str(&format!(" [fast-forward x{}]", self.fast_forward));
+        } else if self.slowed {
+            title.push_str(&format!(" [slow motion 1/{}]", self.slow_motion));
+        }
+        title
+    }
+}
+
+// sleeps so that frames start at a steady rate
+pub struct FrameLimiter {
+    next: Instant,
+}
+
+impl Default for FrameLimiter {
+    fn default() -> Self {
+        FrameLimiter::new()
+    }
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        FrameLimiter {
+            next: Instant::now(),
+        }
+    }
+
+    // wait until `frame` has passed since the previous frame started
+    pub fn wait(&mut self, frame: Duration) {
+        self.next += frame;
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        } else if now - self.next > frame {
+            // too far behind (a slow host or a breakpoint), don't try to catch up
+            self.next = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_frame_advance() {
+        let mut speed = SpeedControl::new(10, 4, 4);
+        assert_eq!(speed.frames_to_run(), 1);
+
+        speed.handle(Hotkey::TogglePause);
+        assert_eq!(speed.frames_to_run(), 0);
+
+        speed.handle(Hotkey::AdvanceFrame);
+        assert_eq!(speed.frames_to_run(), 1);
+        assert_eq!(speed.frames_to_run(), 0);
+
+        speed.handle(Hotkey::TogglePause);
+        speed.handle(Hotkey::AdvanceFrame);
+        assert!(speed.paused);
+        assert_eq!(speed.frames_to_run(), 1);
+
+        // --frame-advance
+        let mut speed = SpeedControl::new(10, 4, 4);
+        speed.advance_frames(2);
+        assert!(speed.paused);
+        assert_eq!(speed.frames_to_run(), 1);
+        assert_eq!(speed.frames_to_run(), 1);
+        assert_eq!(speed.frames_to_run(), 0);
+    }
+
+    #[test]
+    fn test_fast_forward_and_slow_motion() {
+        let mut speed = SpeedControl::new(10, 3, 4);
+        let frame = speed.frame_duration();
+
+        speed.handle(Hotkey::FastForward(true));
+        assert_eq!(speed.frames_to_run(), 3);
+        assert!(speed.title().ends_with("[fast-forward x3]"));
+        speed.handle(Hotkey::FastForward(false));
+        assert_eq!(speed.frames_to_run(), 1);
+
+        speed.handle(Hotkey::ToggleSlowMotion);
+        assert_eq!(speed.frame_duration(), frame * 4);
+        assert_eq!(speed.frames_to_run(), 1);
+    }
+
+    #[test]
+    fn test_instructions_per_frame_adjuster() {
+        let mut speed = SpeedControl::new(2, 1, 1);
+        speed.handle(Hotkey::FewerInstructions);
+        speed.handle(Hotkey::FewerInstructions);
+        assert_eq!(speed.instructions_per_frame, 1);
+
+        speed.instructions_per_frame = 100;
+        speed.handle(Hotkey::MoreInstructions);
+        assert_eq!(speed.instructions_per_frame, 110);
+        assert_eq!(speed.title(), "CHIP-8 Emulator - 110 ipf");
+    }
+}
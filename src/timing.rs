@@ -0,0 +1,182 @@
+use crate::cpu::CPU;
+use crate::instruction::Instruction;
+use serde::Deserialize;
+
+// how much CHIP-8 code runs in a 60 Hz frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Timing {
+    /// a fixed number of instructions per frame, `instructions_per_frame`
+    #[default]
+    Fixed,
+    /// each instruction costs as many machine cycles as on a COSMAC VIP
+    Vip,
+}
+
+// the VIP's CDP1861 draws 262 lines of 14 machine cycles each per frame
+pub const CYCLES_PER_FRAME: u32 = 262 * 14;
+// the 1861 fetches 8 bytes by DMA on each of the 128 display lines, and the
+// interrupt routine that starts it also counts down the timers
+pub const VBLANK_CYCLES: u32 = 128 * 8 + 46;
+
+// fetching, decoding and dispatching an instruction
+const FETCH: u32 = 40;
+// extra cycles when a skip is taken
+const SKIP: u32 = 4;
+
+// machine cycles the VIP interpreter takes for `instruction`, given the state it runs in
+// the counts approximate the interpreter's 1802 routines, at 2 machine cycles per 1802
+// instruction and 3 per long branch. Skips are charged separately, once it is known
+// whether they were taken
+pub fn cost(instruction: Instruction, cpu: &CPU) -> u32 {
+    use crate::instruction::Instruction::*;
+
+    let v = |x: u8| cpu.registers[x as usize] as u32;
+    FETCH
+        + match instruction {
+            // clears 256 bytes of display memory, 6 cycles each
+            Cls => 24 + 256 * 6,
+            Ret => 10,
+            Sys(_) | Call(_) => 26,
+            Jp(_) => 12,
+            SeByte(..) | SneByte(..) => 10,
+            SeReg(..) | SneReg(..) | Skp(_) | Sknp(_) => 14,
+            LdByte(..) => 6,
+            AddByte(..) => 10,
+            // the ALU operations build and run a small 1802 routine
+            LdReg(..) | Or(..) | And(..) | Xor(..) | AddReg(..) | Sub(..) | Shr(..) | Subn(..)
+            | Shl(..) => 44,
+            LdI(_) => 12,
+            // adding V0 to the low byte carries into the page
+            JpV0(addr) => {
+                22 + if (addr & 0xFF) as u32 + v(0) > 0xFF {
+                    2
+                } else {
+                    0
+                }
+            }
+            Rnd(..) => 36,
+            // every sprite row is shifted into place a bit at a time, and an unaligned
+            // row spreads over two bytes of display memory
+            Drw(x, _, n) => {
+                let shift = v(x) % 8;
+                let per_row = 46 + 8 * shift + if shift == 0 { 0 } else { 12 };
+                68 + n as u32 * per_row
+            }
+            LdVxDt(_) | LdDtVx(_) | LdStVx(_) => 10,
+            // charged again for every poll while it waits
+            LdVxK(_) => 10,
+            AddI(x) => {
+                16 + if (cpu.i_register & 0xFF) + v(x) > 0xFF {
+                    4
+                } else {
+                    0
+                }
+            }
+            LdF(_) => 20,
+            // the digits are found by repeated subtraction
+            LdB(x) => {
+                let value = v(x);
+                80 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+            }
+            LdIVx(x) | LdVxI(x) => 14 + 14 * (x as u32 + 1),
+            // the variants ran on VIPs too, but their timings were never measured
+            Extended(_) => 26,
+        }
+}
+
+fn is_skip(instruction: Instruction) -> bool {
+    use crate::instruction::Instruction::*;
+    matches!(
+        instruction,
+        SeByte(..) | SneByte(..) | SeReg(..) | SneReg(..) | Skp(_) | Sknp(_)
+    ) || matches!(instruction, Extended(extended) if extended.is_skip())
+}
+
+/// Runs the CPU a frame at a time with the cycle budget a COSMAC VIP has: a frame of
+/// machine cycles less what the vblank interrupt and display DMA take. An instruction
+/// that doesn't fit in what is left of a frame still runs, and its overrun comes out
+/// of the next frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VipTiming {
+    // cycles the last frame's final instruction ran over by
+    overrun: u32,
+}
+
+impl VipTiming {
+    pub fn new() -> Self {
+        VipTiming::default()
+    }
+
+    // run one 60 Hz frame's worth of instructions, returns how many ran
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> u32 {
+        let budget = CYCLES_PER_FRAME - VBLANK_CYCLES;
+        let mut used = self.overrun;
+        let mut count = 0;
+        while used < budget {
+            let pc = cpu.program_counter;
+            let instruction = cpu.heap.decode(pc);
+            // invalid opcodes are left for `tick` to report
+            let mut cycles = instruction.map_or(FETCH, |instruction| cost(instruction, cpu));
+            cpu.tick();
+            if instruction.is_ok_and(is_skip) && cpu.program_counter == pc + 4 {
+                cycles += SKIP;
+            }
+            used += cycles;
+            count += 1;
+        }
+        self.overrun = used - budget;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+
+    #[test]
+    fn test_costs_depend_on_state() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.registers[1] = 8;
+        cpu.registers[2] = 3;
+        cpu.registers[3] = 199;
+
+        // aligned sprites are cheaper than unaligned ones, and taller ones cost more
+        let aligned = cost(Instruction::Drw(1, 0, 5), &cpu);
+        let unaligned = cost(Instruction::Drw(2, 0, 5), &cpu);
+        assert_eq!(aligned, FETCH + 68 + 5 * 46);
+        assert_eq!(unaligned, FETCH + 68 + 5 * (46 + 24 + 12));
+        assert!(cost(Instruction::Drw(1, 0, 15), &cpu) > aligned);
+
+        // BCD of 199 subtracts 1 + 9 + 9 times
+        assert_eq!(cost(Instruction::LdB(3), &cpu), FETCH + 80 + 16 * 19);
+        assert!(cost(Instruction::LdIVx(0xF), &cpu) > cost(Instruction::LdIVx(0), &cpu));
+    }
+
+    #[test]
+    fn test_frame_budget() {
+        // ADD V0, 1; SE V0, 0x80; JP 0x200; then JP 0x206 forever
+        let mut cpu = cpu_with_program(&[0x70, 0x01, 0x30, 0x80, 0x12, 0x00, 0x12, 0x06]);
+        let mut timing = VipTiming::new();
+
+        let budget = CYCLES_PER_FRAME - VBLANK_CYCLES;
+        let loop_cost = (FETCH + 10) + (FETCH + 10) + (FETCH + 12);
+        let count = timing.run_frame(&mut cpu);
+        // every instruction but the last fits in the budget
+        assert_eq!(count, (budget / loop_cost) * 3 + 1);
+        assert!(timing.overrun > 0 && timing.overrun < FETCH + 12);
+
+        // the skip out of the loop costs more than falling through
+        cpu.registers[0] = 0x7F;
+        cpu.program_counter = 0x200;
+        timing.overrun = 0;
+        let start = cpu.cycles;
+        timing.run_frame(&mut cpu);
+        let jumps = cpu.cycles - start - 2;
+        assert_eq!(
+            (FETCH + 10) + (FETCH + 10 + SKIP) + jumps as u32 * (FETCH + 12),
+            budget + timing.overrun
+        );
+    }
+}
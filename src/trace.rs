@@ -0,0 +1,591 @@
+use crate::config::invalid;
+use crate::cpu::CPU;
+use crate::disasm::disassemble;
+use crate::variant::Variant;
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// first bytes of a binary trace, then a format version and the variant it ran as
+const MAGIC: &[u8; 4] = b"C8TR";
+const VERSION: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum TraceFormat {
+    // one line per instruction, for reading
+    Text,
+    // compact records for `trace-diff`
+    Binary,
+}
+
+// something an instruction changed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    V(u8, u8),
+    I(u32),
+    Dt(u8),
+    St(u8),
+    Sp(u16),
+    // stack slot and the return address written to it
+    Stack(u16, u16),
+    Memory(u32, u8),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::V(n, value) => write!(f, "V{:X}={:02X}", n, value),
+            Change::I(value) => write!(f, "I={:03X}", value),
+            Change::Dt(value) => write!(f, "DT={:02X}", value),
+            Change::St(value) => write!(f, "ST={:02X}", value),
+            Change::Sp(value) => write!(f, "SP={}", value),
+            Change::Stack(n, value) => write!(f, "S[{}]={:03X}", n, value),
+            Change::Memory(addr, value) => write!(f, "M[{:03X}]={:02X}", addr, value),
+        }
+    }
+}
+
+// one executed instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub frame: u64,
+    pub pc: u32,
+    pub opcode: u16,
+    pub changes: Vec<Change>,
+    // the instruction set the opcode was decoded as
+    pub variant: Variant,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:>7}  {:03X}  {:04X}  ",
+            self.cycle, self.frame, self.pc, self.opcode
+        )?;
+        let mnemonic = disassemble(self.variant, self.opcode);
+        if self.changes.is_empty() {
+            return f.write_str(&mnemonic);
+        }
+        write!(f, "{:<18}", mnemonic)?;
+        for change in &self.changes {
+            write!(f, " {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+// CPU state an instruction can change, other than memory which the bus journals
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    registers: [u8; 16],
+    i_register: u32,
+    delay_timer: u8,
+    sound_timer: u8,
+    stack_pointer: usize,
+    stack: Vec<u16>,
+}
+
+impl Snapshot {
+    pub fn of(cpu: &CPU) -> Self {
+        Snapshot {
+            registers: cpu.registers,
+            i_register: cpu.i_register,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+            stack_pointer: cpu.stack_pointer,
+            stack: cpu.stack.clone(),
+        }
+    }
+
+    // what changed between `self` and `after`, plus the memory the instruction wrote
+    pub fn changes(&self, after: &Snapshot, writes: &[(usize, u8)]) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for n in 0..16 {
+            if self.registers[n] != after.registers[n] {
+                changes.push(Change::V(n as u8, after.registers[n]));
+            }
+        }
+        if self.i_register != after.i_register {
+            changes.push(Change::I(after.i_register));
+        }
+        if self.delay_timer != after.delay_timer {
+            changes.push(Change::Dt(after.delay_timer));
+        }
+        if self.sound_timer != after.sound_timer {
+            changes.push(Change::St(after.sound_timer));
+        }
+        if self.stack_pointer != after.stack_pointer {
+            changes.push(Change::Sp(after.stack_pointer as u16));
+        }
+        for (n, (&old, &new)) in self.stack.iter().zip(&after.stack).enumerate() {
+            if old != new {
+                changes.push(Change::Stack(n as u16, new));
+            }
+        }
+        changes.extend(
+            writes
+                .iter()
+                .map(|&(addr, value)| Change::Memory(addr as u32, value)),
+        );
+        changes
+    }
+}
+
+// which instructions get traced, everything by default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceFilter {
+    pub addresses: Option<RangeInclusive<usize>>,
+    // first hex digit of the opcode
+    pub classes: Vec<u8>,
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: usize, opcode: u16, frame: u64) -> bool {
+        self.addresses
+            .as_ref()
+            .is_none_or(|range| range.contains(&pc))
+            && (self.classes.is_empty() || self.classes.contains(&((opcode >> 12) as u8)))
+            && self
+                .frames
+                .as_ref()
+                .is_none_or(|range| range.contains(&frame))
+    }
+}
+
+// `START-END` in hex, both ends included
+pub fn parse_address_range(text: &str) -> Result<RangeInclusive<usize>, String> {
+    let parse = |n: &str| {
+        usize::from_str_radix(n.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("invalid address {:?}", n))
+    };
+    let (start, end) = text.split_once('-').ok_or("expected START-END")?;
+    Ok(parse(start)?..=parse(end)?)
+}
+
+// `START-END` in decimal, both ends included
+pub fn parse_frame_range(text: &str) -> Result<RangeInclusive<u64>, String> {
+    let parse = |n: &str| n.parse().map_err(|_| format!("invalid frame {:?}", n));
+    let (start, end) = text.split_once('-').ok_or("expected START-END")?;
+    Ok(parse(start)?..=parse(end)?)
+}
+
+pub fn parse_class(text: &str) -> Result<u8, String> {
+    u8::from_str_radix(text, 16)
+        .ok()
+        .filter(|&class| class <= 0xF)
+        .ok_or_else(|| format!("invalid opcode class {:?}, expected 0-F", text))
+}
+
+// writes the records `CPU::tick` hands it
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    // cycle and frame of the previous binary record, they are delta encoded
+    last: (u64, u64),
+    // the first write error, tracing stops there
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(
+        mut out: Box<dyn Write>,
+        format: TraceFormat,
+        filter: TraceFilter,
+        variant: Variant,
+    ) -> Self {
+        let header = match format {
+            TraceFormat::Text => writeln!(
+                out,
+                "{:>10} {:>7}  {:3}  {:4}  {:<18} changes",
+                "cycle", "frame", "pc", "op", "instruction"
+            ),
+            TraceFormat::Binary => out
+                .write_all(MAGIC)
+                .and_then(|_| out.write_all(&[VERSION, variant_byte(variant)])),
+        };
+        Tracer {
+            out,
+            format,
+            filter,
+            last: (0, 0),
+            error: header.err(),
+        }
+    }
+
+    pub fn create(
+        path: &Path,
+        format: TraceFormat,
+        filter: TraceFilter,
+        variant: Variant,
+    ) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::new(Box::new(file), format, filter, variant))
+    }
+
+    pub fn wants(&self, pc: usize, opcode: u16, frame: u64) -> bool {
+        self.error.is_none() && self.filter.matches(pc, opcode, frame)
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", record),
+            TraceFormat::Binary => {
+                let result = encode(&mut self.out, record, self.last);
+                self.last = (record.cycle, record.frame);
+                result
+            }
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    // flush, reporting the first error tracing ran into
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+// a binary record is
+//   cycle and frame as LEB128 deltas from the previous record
+//   pc as a little endian u32 and opcode as a little endian u16
+//   a change count, then a tag byte and a value per change
+fn encode(out: &mut dyn Write, record: &TraceRecord, last: (u64, u64)) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(16);
+    write_varint(&mut bytes, record.cycle - last.0);
+    write_varint(&mut bytes, record.frame - last.1);
+    bytes.extend_from_slice(&record.pc.to_le_bytes());
+    bytes.extend_from_slice(&record.opcode.to_le_bytes());
+    bytes.push(record.changes.len() as u8);
+    for change in &record.changes {
+        match *change {
+            Change::V(n, value) => bytes.extend_from_slice(&[n, value]),
+            Change::I(value) => {
+                bytes.push(0x10);
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Change::Dt(value) => bytes.extend_from_slice(&[0x11, value]),
+            Change::St(value) => bytes.extend_from_slice(&[0x12, value]),
+            Change::Sp(value) => {
+                bytes.push(0x13);
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Change::Stack(n, value) => {
+                bytes.push(0x20);
+                bytes.extend_from_slice(&n.to_le_bytes());
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Change::Memory(addr, value) => {
+                bytes.push(0x30);
+                bytes.extend_from_slice(&addr.to_le_bytes());
+                bytes.push(value);
+            }
+        }
+    }
+    out.write_all(&bytes)
+}
+
+// a variant is stored as its place in the list of variants
+fn variant_byte(variant: Variant) -> u8 {
+    Variant::value_variants()
+        .iter()
+        .position(|&v| v == variant)
+        .unwrap() as u8
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+// reads the records of a binary trace
+pub struct TraceReader<R: Read> {
+    input: R,
+    last: (u64, u64),
+    variant: Variant,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        File::open(path)
+            .and_then(|file| TraceReader::new(BufReader::new(file)))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid(
+                "not a binary trace, record one with --trace-format binary",
+            ));
+        }
+        if header[4] != VERSION {
+            return Err(invalid(format!("unsupported trace version {}", header[4])));
+        }
+        let mut variant = [0];
+        input.read_exact(&mut variant)?;
+        let variant = *Variant::value_variants()
+            .get(variant[0] as usize)
+            .ok_or_else(|| invalid(format!("unknown variant {}", variant[0])))?;
+        Ok(TraceReader {
+            input,
+            last: (0, 0),
+            variant,
+        })
+    }
+
+    pub fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        // a clean end of file can only come before a record starts
+        let cycle = match self.read_varint() {
+            Ok(delta) => self.last.0 + delta,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let frame = self.last.1 + self.read_varint()?;
+        self.last = (cycle, frame);
+        let pc = self.read_u32()?;
+        let opcode = self.read_u16()?;
+
+        let count = self.read_u8()?;
+        let mut changes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let change = match self.read_u8()? {
+                n @ 0x00..=0x0F => Change::V(n, self.read_u8()?),
+                0x10 => Change::I(self.read_u32()?),
+                0x11 => Change::Dt(self.read_u8()?),
+                0x12 => Change::St(self.read_u8()?),
+                0x13 => Change::Sp(self.read_u16()?),
+                0x20 => Change::Stack(self.read_u16()?, self.read_u16()?),
+                0x30 => Change::Memory(self.read_u32()?, self.read_u8()?),
+                tag => return Err(invalid(format!("unknown change tag {:#04x}", tag))),
+            };
+            changes.push(change);
+        }
+
+        Ok(Some(TraceRecord {
+            cycle,
+            frame,
+            pc,
+            opcode,
+            changes,
+            variant: self.variant,
+        }))
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+}
+
+// the `trace-diff` subcommand, prints where two binary traces diverge
+// returns whether they matched
+pub fn diff(a: &Path, b: &Path, context: usize) -> io::Result<bool> {
+    let mut a_reader = TraceReader::open(a)?;
+    let mut b_reader = TraceReader::open(b)?;
+    let mut recent = VecDeque::with_capacity(context + 1);
+    let mut count = 0;
+
+    loop {
+        let (a_record, b_record) = (a_reader.read_record()?, b_reader.read_record()?);
+        match (&a_record, &b_record) {
+            (None, None) => {
+                println!("traces match ({} records)", count);
+                return Ok(true);
+            }
+            (Some(x), Some(y)) if x == y => {
+                recent.push_back(a_record.unwrap());
+                if recent.len() > context {
+                    recent.pop_front();
+                }
+                count += 1;
+                continue;
+            }
+            _ => (),
+        }
+
+        println!("traces diverge at record {}", count);
+        for record in &recent {
+            println!("   {}", record);
+        }
+        let describe = |record: &Option<TraceRecord>, path: &Path| match record {
+            Some(record) => record.to_string(),
+            None => format!("(end of {})", path.display()),
+        };
+        println!("a: {}", describe(&a_record, a));
+        println!("b: {}", describe(&b_record, b));
+        return Ok(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+    use std::fs;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("chip8-trace-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let records = vec![
+            TraceRecord {
+                cycle: 3,
+                frame: 0,
+                pc: 0x200,
+                opcode: 0xF033,
+                variant: Variant::Megachip,
+                changes: vec![Change::Memory(0x300, 2), Change::Memory(0x302, 7)],
+            },
+            TraceRecord {
+                cycle: 300,
+                frame: 20,
+                pc: 0x206,
+                opcode: 0x2208,
+                variant: Variant::Megachip,
+                changes: vec![Change::Sp(1), Change::Stack(0, 0x208), Change::I(0x123)],
+            },
+            // MegaChip's I and memory go past 16 bits
+            TraceRecord {
+                cycle: 301,
+                frame: 20,
+                pc: 0x1_0000,
+                opcode: 0x0112,
+                variant: Variant::Megachip,
+                changes: vec![Change::I(0x12_3456), Change::Memory(0xFF_FFFF, 1)],
+            },
+            // a machine with a stack deeper than 16
+            TraceRecord {
+                cycle: 302,
+                frame: 20,
+                pc: 0x20A,
+                opcode: 0x220A,
+                variant: Variant::Megachip,
+                changes: vec![Change::Sp(0x101), Change::Stack(0x100, 0x20C)],
+            },
+        ];
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[VERSION, variant_byte(Variant::Megachip)]);
+        let mut last = (0, 0);
+        for record in &records {
+            encode(&mut bytes, record, last).unwrap();
+            last = (record.cycle, record.frame);
+        }
+
+        let mut reader = TraceReader::new(&bytes[..]).unwrap();
+        for record in &records {
+            assert_eq!(reader.read_record().unwrap().as_ref(), Some(record));
+        }
+        assert_eq!(reader.read_record().unwrap(), None);
+
+        assert!(TraceReader::new(&b"cycle frame"[..]).is_err());
+
+        // opcodes are shown as the variant's instructions
+        let exit = TraceRecord {
+            cycle: 0,
+            frame: 0,
+            pc: 0x200,
+            opcode: 0x00FD,
+            changes: Vec::new(),
+            variant: Variant::Schip,
+        };
+        assert!(exit.to_string().ends_with("00FD  EXIT"));
+    }
+
+    #[test]
+    fn test_trace_and_diff() {
+        // LD V0, 7; LD I, 0x300; LD B, V0; CALL 0x208; 0x208: ADD V0, 1
+        let program = [0x60, 0x07, 0xA3, 0x00, 0xF0, 0x33, 0x22, 0x08, 0x70, 0x01];
+        let run = |name: &str, format: TraceFormat, filter: TraceFilter, patch: bool| {
+            let path = temp_path(name);
+            let mut cpu = cpu_with_program(&program);
+            if patch {
+                cpu.heap.load(0x209, &[0x02]);
+            }
+            cpu.start_trace(Tracer::create(&path, format, filter, Variant::Chip8).unwrap());
+            for _ in 0..5 {
+                cpu.tick();
+            }
+            cpu.stop_trace().unwrap().finish().unwrap();
+            path
+        };
+
+        let text = run("text", TraceFormat::Text, TraceFilter::default(), false);
+        let lines: Vec<String> = fs::read_to_string(&text)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[3], "2 0 204 F033 LD B, V0 M[302]=07");
+        assert_eq!(lines[4], "3 0 206 2208 CALL 0x208 SP=1 S[0]=208");
+        assert_eq!(lines[5], "4 0 208 7001 ADD V0, 0x01 V0=08");
+
+        let filter = TraceFilter {
+            classes: vec![0x7],
+            ..TraceFilter::default()
+        };
+        let filtered = run("filtered", TraceFormat::Text, filter, false);
+        assert_eq!(fs::read_to_string(&filtered).unwrap().lines().count(), 2);
+
+        let a = run("a", TraceFormat::Binary, TraceFilter::default(), false);
+        let b = run("b", TraceFormat::Binary, TraceFilter::default(), false);
+        let c = run("c", TraceFormat::Binary, TraceFilter::default(), true);
+        assert!(diff(&a, &b, 2).unwrap());
+        assert!(!diff(&a, &c, 2).unwrap());
+        assert!(diff(&a, &text, 2).is_err());
+
+        for path in [text, filtered, a, b, c] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,610 @@
+use crate::cpu::CPU;
+use crate::display::{
+    Blend, Display, DISPLAY_HEIGHT, DISPLAY_WIDTH, MEGACHIP_HEIGHT, MEGACHIP_WIDTH, ZONE_HEIGHT,
+    ZONE_WIDTH,
+};
+use crate::instruction::{decode, DecodeError, Instruction};
+use crate::megachip;
+use crate::memory::MEMORY_SIZE;
+use serde::Deserialize;
+use std::fmt;
+
+// which interpreter's instruction set the ROM was written for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    /// the original COSMAC VIP instruction set
+    #[default]
+    Chip8,
+    /// RCA's CHIP-8X for the VP-590 color board and VP-580 second keypad
+    Chip8x,
+    /// CHIP-8E, with relative jumps, register ranges and arithmetic
+    Chip8e,
+    /// the two-page 64x64 hi-res CHIP-8
+    Hires,
+    /// SUPER-CHIP's exit, big font digits and RPL user flags, on the 64x32 screen
+    Schip,
+    /// MegaChip, with a 256x192 screen of 256 colors, sampled sound and 16 MB of memory
+    Megachip,
+}
+
+/// An instruction a variant adds, or gives a new meaning to. Variants are decoded
+/// ahead of the base instruction set, so these take the place of whatever the
+/// opcode means in plain CHIP-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extended {
+    /// 02A0 - BGC, CHIP-8X: step to the next background color
+    StepBackground,
+    /// 5xy1 - ADDN Vx, Vy, CHIP-8X: add each nibble of Vy to Vx's, keeping 3 bits
+    AddNibbles(u8, u8),
+    /// Bxy0 - COL Vx, Vy, CHIP-8X: color the zones Vx and Vx+1 span with Vy
+    ColorZones(u8, u8),
+    /// Bxyn - COL Vx, Vy, n, CHIP-8X: color n rows at (Vx, Vx+1) with Vy
+    ColorRows(u8, u8, u8),
+    /// ExF2 - SKP2 Vx, CHIP-8X: skip if key Vx is down on the second keypad
+    Skp2(u8),
+    /// ExF5 - SKNP2 Vx, CHIP-8X: skip if key Vx is up on the second keypad
+    Sknp2(u8),
+    /// 00ED - STOP, CHIP-8E: stop the program
+    Stop,
+    /// 0151 - WAIT DT, CHIP-8E: wait for the delay timer to run out
+    WaitDt,
+    /// 0188 - SKIP, CHIP-8E: skip the next instruction
+    Skip,
+    /// 5xy1 - SGT Vx, Vy, CHIP-8E: skip if Vx > Vy
+    Sgt(u8, u8),
+    /// 5xy2 - LD [I], Vx-Vy, CHIP-8E: store Vx to Vy at I, I moves past them
+    StoreRange(u8, u8),
+    /// 5xy3 - LD Vx-Vy, [I], CHIP-8E: load Vx to Vy from I, I moves past them
+    LoadRange(u8, u8),
+    /// 9xy1 - MUL Vx, Vy, CHIP-8E: VF:Vx = Vx * Vy
+    Mul(u8, u8),
+    /// 9xy2 - DIV Vx, Vy, CHIP-8E: Vx = Vx / Vy, VF = the remainder
+    Div(u8, u8),
+    /// 9xy3 - BCD Vx, Vy, CHIP-8E: the 5 decimal digits of Vx:Vy at I
+    Bcd16(u8, u8),
+    /// BBkk - JB kk, CHIP-8E: jump back kk bytes
+    JumpBack(u8),
+    /// BFkk - JF kk, CHIP-8E: jump forward kk bytes
+    JumpForward(u8),
+    /// Fx1B - SKIP Vx, CHIP-8E: skip Vx bytes
+    SkipBytes(u8),
+    /// Fx4F - DELAY Vx, CHIP-8E: set the delay timer to Vx and wait for it
+    Delay(u8),
+    /// 0230 - CLS, hi-res: clear the 64x64 screen
+    HiresCls,
+    /// 00FD - EXIT, SCHIP: end the program
+    Exit,
+    /// Fx30 - LD HF, Vx, SCHIP: point I at the big font glyph for Vx
+    LdHf(u8),
+    /// Fx75 - LD R, Vx, SCHIP: save V0 to Vx in the RPL user flags
+    LdRVx(u8),
+    /// Fx85 - LD Vx, R, SCHIP: restore V0 to Vx from the RPL user flags
+    LdVxR(u8),
+    /// 0010 - MEGAOFF, MegaChip: back to the 64x32 screen
+    MegaOff,
+    /// 0011 - MEGAON, MegaChip: switch to the 256x192 color screen
+    MegaOn,
+    /// 01nn nnnn - LDHI I, nnnnnn, MegaChip: a 24-bit I, its low 16 bits are the next word
+    LdHi(u8),
+    /// 02nn - LDPAL nn, MegaChip: load nn 0xAARRGGBB colors from I, from palette index 1
+    LdPal(u8),
+    /// 03nn - SPRW nn, MegaChip: sprite width, 0 for 256
+    SprW(u8),
+    /// 04nn - SPRH nn, MegaChip: sprite height, 0 for 256
+    SprH(u8),
+    /// 05nn - ALPHA nn, MegaChip: opacity of the whole screen
+    Alpha(u8),
+    /// 060n - DIGISND n, MegaChip: play the sound at I, looping if n is 0
+    DigiSnd(u8),
+    /// 0700 - STOPSND, MegaChip: stop the sound
+    StopSnd,
+    /// 080n - BMODE n, MegaChip: how sprites blend with the screen
+    BMode(u8),
+    /// 09nn - CCOL nn, MegaChip: drawing over palette index nn is a collision
+    CCol(u8),
+}
+
+impl Variant {
+    /// Decode `opcode` as this variant reads it: its own instructions first, then
+    /// the base instruction set.
+    pub fn decode(self, opcode: u16) -> Result<Instruction, DecodeError> {
+        match self.extension(opcode) {
+            Some(extended) => Ok(Instruction::Extended(extended)),
+            None => decode(opcode),
+        }
+    }
+
+    fn extension(self, opcode: u16) -> Option<Extended> {
+        use self::Extended::*;
+
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let kk = (opcode & 0x00FF) as u8;
+
+        let extended = match (self, opcode >> 12, n) {
+            (Variant::Chip8x, 0x0, _) if opcode == 0x02A0 => StepBackground,
+            (Variant::Chip8x, 0x5, 0x1) => AddNibbles(x, y),
+            (Variant::Chip8x, 0xB, 0x0) => ColorZones(x, y),
+            (Variant::Chip8x, 0xB, _) => ColorRows(x, y, n),
+            (Variant::Chip8x, 0xE, _) if kk == 0xF2 => Skp2(x),
+            (Variant::Chip8x, 0xE, _) if kk == 0xF5 => Sknp2(x),
+
+            (Variant::Chip8e, 0x0, _) => match opcode {
+                0x00ED => Stop,
+                0x0151 => WaitDt,
+                0x0188 => Skip,
+                _ => return None,
+            },
+            (Variant::Chip8e, 0x5, 0x1) => Sgt(x, y),
+            (Variant::Chip8e, 0x5, 0x2) => StoreRange(x, y),
+            (Variant::Chip8e, 0x5, 0x3) => LoadRange(x, y),
+            (Variant::Chip8e, 0x9, 0x1) => Mul(x, y),
+            (Variant::Chip8e, 0x9, 0x2) => Div(x, y),
+            (Variant::Chip8e, 0x9, 0x3) => Bcd16(x, y),
+            (Variant::Chip8e, 0xB, _) if x == 0xB => JumpBack(kk),
+            (Variant::Chip8e, 0xB, _) if x == 0xF => JumpForward(kk),
+            (Variant::Chip8e, 0xF, _) if kk == 0x1B => SkipBytes(x),
+            (Variant::Chip8e, 0xF, _) if kk == 0x4F => Delay(x),
+
+            (Variant::Hires, 0x0, _) if opcode == 0x0230 => HiresCls,
+
+            (Variant::Schip, 0x0, _) if opcode == 0x00FD => Exit,
+            (Variant::Schip, 0xF, _) => match kk {
+                0x30 => LdHf(x),
+                0x75 => LdRVx(x),
+                0x85 => LdVxR(x),
+                _ => return None,
+            },
+
+            (Variant::Megachip, 0x0, _) => match opcode >> 8 {
+                0x00 if kk == 0x10 => MegaOff,
+                0x00 if kk == 0x11 => MegaOn,
+                0x01 => LdHi(kk),
+                0x02 => LdPal(kk),
+                0x03 => SprW(kk),
+                0x04 => SprH(kk),
+                0x05 => Alpha(kk),
+                0x06 if y == 0 => DigiSnd(n),
+                0x07 if kk == 0 => StopSnd,
+                0x08 if y == 0 => BMode(n),
+                0x09 => CCol(kk),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(extended)
+    }
+
+    // the screen ROMs for this variant draw on
+    pub fn display(self) -> Display {
+        match self {
+            Variant::Chip8x => Display::new().with_colors(),
+            Variant::Hires => Display::with_size(DISPLAY_WIDTH, DISPLAY_HEIGHT * 2),
+            _ => Display::new(),
+        }
+    }
+
+    // the largest screen the variant switches to, windows are sized for it
+    pub fn screen_size(self) -> (usize, usize) {
+        match self {
+            Variant::Megachip => (MEGACHIP_WIDTH, MEGACHIP_HEIGHT),
+            _ => self.display().resolution(),
+        }
+    }
+
+    // bytes of memory, all that MegaChip's 24-bit I can reach
+    pub fn memory_size(self) -> usize {
+        match self {
+            Variant::Megachip => 1 << 24,
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    // where ROMs are loaded, CHIP-8X's interpreter takes up another page
+    pub fn load_address(self) -> usize {
+        match self {
+            Variant::Chip8x => 0x300,
+            _ => 0x200,
+        }
+    }
+
+    /// Where `rom` starts running. Hi-res ROMs start with a jump to 0x260 into the
+    /// patch they carry for the interpreter, and their CHIP-8 code starts at 0x2C0.
+    pub fn entry(self, rom: &[u8]) -> usize {
+        match self {
+            Variant::Hires if rom.starts_with(&[0x12, 0x60]) => 0x2C0,
+            _ => self.load_address(),
+        }
+    }
+}
+
+impl Extended {
+    pub fn encode(self) -> u16 {
+        use self::Extended::*;
+
+        let xy = |prefix: u16, x: u8, y: u8, n: u16| prefix | (x as u16) << 8 | (y as u16) << 4 | n;
+        let xkk = |prefix: u16, x: u8, kk: u8| prefix | (x as u16) << 8 | kk as u16;
+        match self {
+            StepBackground => 0x02A0,
+            AddNibbles(x, y) => xy(0x5000, x, y, 0x1),
+            ColorZones(x, y) => xy(0xB000, x, y, 0x0),
+            ColorRows(x, y, n) => xy(0xB000, x, y, n as u16 & 0xF),
+            Skp2(x) => xkk(0xE000, x, 0xF2),
+            Sknp2(x) => xkk(0xE000, x, 0xF5),
+            Stop => 0x00ED,
+            WaitDt => 0x0151,
+            Skip => 0x0188,
+            Sgt(x, y) => xy(0x5000, x, y, 0x1),
+            StoreRange(x, y) => xy(0x5000, x, y, 0x2),
+            LoadRange(x, y) => xy(0x5000, x, y, 0x3),
+            Mul(x, y) => xy(0x9000, x, y, 0x1),
+            Div(x, y) => xy(0x9000, x, y, 0x2),
+            Bcd16(x, y) => xy(0x9000, x, y, 0x3),
+            JumpBack(kk) => xkk(0xB000, 0xB, kk),
+            JumpForward(kk) => xkk(0xB000, 0xF, kk),
+            SkipBytes(x) => xkk(0xF000, x, 0x1B),
+            Delay(x) => xkk(0xF000, x, 0x4F),
+            HiresCls => 0x0230,
+            Exit => 0x00FD,
+            LdHf(x) => xkk(0xF000, x, 0x30),
+            LdRVx(x) => xkk(0xF000, x, 0x75),
+            LdVxR(x) => xkk(0xF000, x, 0x85),
+            MegaOff => 0x0010,
+            MegaOn => 0x0011,
+            LdHi(nn) => xkk(0x0000, 0x1, nn),
+            LdPal(nn) => xkk(0x0000, 0x2, nn),
+            SprW(nn) => xkk(0x0000, 0x3, nn),
+            SprH(nn) => xkk(0x0000, 0x4, nn),
+            Alpha(nn) => xkk(0x0000, 0x5, nn),
+            DigiSnd(n) => xkk(0x0000, 0x6, n),
+            StopSnd => 0x0700,
+            BMode(n) => xkk(0x0000, 0x8, n),
+            CCol(nn) => xkk(0x0000, 0x9, nn),
+        }
+    }
+
+    // whether it may skip the next instruction
+    pub fn is_skip(self) -> bool {
+        use self::Extended::*;
+        matches!(self, Skp2(_) | Sknp2(_) | Skip | Sgt(..))
+    }
+}
+
+impl fmt::Display for Extended {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Extended::*;
+
+        match *self {
+            StepBackground => write!(f, "BGC"),
+            AddNibbles(x, y) => write!(f, "ADDN V{:X}, V{:X}", x, y),
+            ColorZones(x, y) => write!(f, "COL V{:X}, V{:X}", x, y),
+            ColorRows(x, y, n) => write!(f, "COL V{:X}, V{:X}, {}", x, y, n),
+            Skp2(x) => write!(f, "SKP2 V{:X}", x),
+            Sknp2(x) => write!(f, "SKNP2 V{:X}", x),
+            Stop => write!(f, "STOP"),
+            WaitDt => write!(f, "WAIT DT"),
+            Skip => write!(f, "SKIP"),
+            Sgt(x, y) => write!(f, "SGT V{:X}, V{:X}", x, y),
+            StoreRange(x, y) => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            LoadRange(x, y) => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            Mul(x, y) => write!(f, "MUL V{:X}, V{:X}", x, y),
+            Div(x, y) => write!(f, "DIV V{:X}, V{:X}", x, y),
+            Bcd16(x, y) => write!(f, "BCD V{:X}, V{:X}", x, y),
+            JumpBack(kk) => write!(f, "JB 0x{:02X}", kk),
+            JumpForward(kk) => write!(f, "JF 0x{:02X}", kk),
+            SkipBytes(x) => write!(f, "SKIP V{:X}", x),
+            Delay(x) => write!(f, "DELAY V{:X}", x),
+            HiresCls => write!(f, "CLS"),
+            Exit => write!(f, "EXIT"),
+            LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            MegaOff => write!(f, "MEGAOFF"),
+            MegaOn => write!(f, "MEGAON"),
+            LdHi(nn) => write!(f, "LDHI I, 0x{:02X}", nn),
+            LdPal(nn) => write!(f, "LDPAL {}", nn),
+            SprW(nn) => write!(f, "SPRW {}", nn),
+            SprH(nn) => write!(f, "SPRH {}", nn),
+            Alpha(nn) => write!(f, "ALPHA 0x{:02X}", nn),
+            DigiSnd(n) => write!(f, "DIGISND {}", n),
+            StopSnd => write!(f, "STOPSND"),
+            BMode(n) => write!(f, "BMODE {}", n),
+            CCol(nn) => write!(f, "CCOL 0x{:02X}", nn),
+        }
+    }
+}
+
+// the registers from x to y, counting down if y comes first
+fn register_range(x: u8, y: u8) -> Vec<usize> {
+    if x <= y {
+        (x..=y).map(usize::from).collect()
+    } else {
+        (y..=x).rev().map(usize::from).collect()
+    }
+}
+
+/// run an instruction a variant adds, the program counter already points past it
+pub fn execute(cpu: &mut CPU, instruction: Extended) {
+    use self::Extended::*;
+
+    let len = cpu.heap.len();
+    let v = |cpu: &CPU, x: u8| cpu.registers[x as usize];
+    match instruction {
+        StepBackground => {
+            if let Some(colors) = cpu.display.colors_mut() {
+                colors.background = (colors.background + 1) % 4;
+            }
+        }
+        AddNibbles(x, y) => {
+            let (a, b) = (v(cpu, x), v(cpu, y));
+            let high = ((a >> 4) + (b >> 4)) & 0x7;
+            let low = ((a & 0xF) + (b & 0xF)) & 0x7;
+            cpu.registers[x as usize] = high << 4 | low;
+        }
+        // Vx holds the first zone column in its low nibble and the last in its high
+        // one, Vx+1 the same for zone rows
+        ColorZones(x, y) => {
+            let (columns, rows) = (v(cpu, x), v(cpu, (x + 1) & 0xF));
+            let color = v(cpu, y);
+            if let Some(colors) = cpu.display.colors_mut() {
+                for row in (rows & 0xF)..=(rows >> 4) {
+                    for column in (columns & 0xF)..=(columns >> 4) {
+                        colors.set_zone(column as usize, row as usize, color);
+                    }
+                }
+            }
+        }
+        // an 8 pixel wide strip at pixel (Vx, Vx+1), as a sprite of n rows would cover
+        ColorRows(x, y, n) => {
+            let (left, top) = (v(cpu, x) as usize, v(cpu, (x + 1) & 0xF) as usize);
+            let color = v(cpu, y);
+            if let Some(colors) = cpu.display.colors_mut() {
+                for row in top / ZONE_HEIGHT..=(top + n as usize - 1) / ZONE_HEIGHT {
+                    colors.set_zone(left / ZONE_WIDTH, row, color);
+                }
+            }
+        }
+        Skp2(x) => {
+            if cpu.keypad2.is_key_pressed(v(cpu, x)) {
+                cpu.program_counter += 2;
+            }
+        }
+        Sknp2(x) => {
+            if !cpu.keypad2.is_key_pressed(v(cpu, x)) {
+                cpu.program_counter += 2;
+            }
+        }
+        // runs itself forever
+        Stop => cpu.program_counter -= 2,
+        WaitDt => {
+            if cpu.delay_timer != 0 {
+                cpu.program_counter -= 2;
+            }
+        }
+        Skip => cpu.program_counter += 2,
+        Sgt(x, y) => {
+            if v(cpu, x) > v(cpu, y) {
+                cpu.program_counter += 2;
+            }
+        }
+        StoreRange(x, y) => {
+            for (offset, register) in register_range(x, y).into_iter().enumerate() {
+                let addr = (cpu.i_register as usize + offset) % len;
+                cpu.heap.write(addr, cpu.registers[register]);
+            }
+            cpu.i_register += register_range(x, y).len() as u32;
+        }
+        LoadRange(x, y) => {
+            for (offset, register) in register_range(x, y).into_iter().enumerate() {
+                let addr = (cpu.i_register as usize + offset) % len;
+                cpu.registers[register] = cpu.heap.read(addr);
+            }
+            cpu.i_register += register_range(x, y).len() as u32;
+        }
+        Mul(x, y) => {
+            let product = v(cpu, x) as u16 * v(cpu, y) as u16;
+            cpu.registers[x as usize] = product as u8;
+            cpu.registers[0xF] = (product >> 8) as u8;
+        }
+        // dividing by zero leaves both alone
+        Div(x, y) => {
+            let (a, b) = (v(cpu, x), v(cpu, y));
+            if let (Some(quotient), Some(remainder)) = (a.checked_div(b), a.checked_rem(b)) {
+                cpu.registers[x as usize] = quotient;
+                cpu.registers[0xF] = remainder;
+            }
+        }
+        Bcd16(x, y) => {
+            let mut value = (v(cpu, x) as u16) << 8 | v(cpu, y) as u16;
+            for offset in (0..5).rev() {
+                let addr = (cpu.i_register as usize + offset) % len;
+                cpu.heap.write(addr, (value % 10) as u8);
+                value /= 10;
+            }
+        }
+        // both count from the instruction after the jump
+        JumpBack(kk) => cpu.program_counter = (cpu.program_counter + len - kk as usize) % len,
+        JumpForward(kk) => cpu.program_counter = (cpu.program_counter + kk as usize) % len,
+        SkipBytes(x) => cpu.program_counter = (cpu.program_counter + v(cpu, x) as usize) % len,
+        // the timer is only loaded on the first run, later ones wait for it
+        Delay(x) => {
+            if !cpu.timer_wait {
+                cpu.delay_timer = v(cpu, x);
+            }
+            cpu.timer_wait = cpu.delay_timer != 0;
+            if cpu.timer_wait {
+                cpu.program_counter -= 2;
+            }
+        }
+        HiresCls => cpu.display.clear(),
+        // runs itself forever, like CHIP-8E's STOP
+        Exit => cpu.program_counter -= 2,
+        LdHf(x) => cpu.ld_hf(x),
+        // SCHIP had 8 flags on the HP48, XO-CHIP has 16
+        LdRVx(x) => {
+            let count = x as usize + 1;
+            cpu.rpl[..count].copy_from_slice(&cpu.registers[..count]);
+        }
+        LdVxR(x) => {
+            let count = x as usize + 1;
+            cpu.registers[..count].copy_from_slice(&cpu.rpl[..count]);
+        }
+        MegaOff => cpu.display.set_indexed(false),
+        MegaOn => cpu.display.set_indexed(true),
+        // the second word is data, step over it
+        LdHi(nn) => {
+            let pc = cpu.program_counter;
+            let low = (cpu.heap.read(pc) as u32) << 8 | cpu.heap.read(pc + 1) as u32;
+            cpu.i_register = (nn as u32) << 16 | low;
+            cpu.program_counter += 2;
+        }
+        LdPal(count) => megachip::load_palette(cpu, count),
+        DigiSnd(n) => megachip::play(cpu, n == 0),
+        StopSnd => cpu.sample = None,
+        SprW(nn) | SprH(nn) | Alpha(nn) | BMode(nn) | CCol(nn) => {
+            if let Some(indexed) = cpu.display.indexed_mut() {
+                let size = if nn == 0 { 256 } else { nn as usize };
+                match instruction {
+                    SprW(_) => indexed.sprite_width = size,
+                    SprH(_) => indexed.sprite_height = size,
+                    Alpha(_) => indexed.alpha = nn,
+                    BMode(_) => indexed.blend = Blend::from_mode(nn),
+                    _ => indexed.collision = nn,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+
+    #[test]
+    fn test_decode_and_round_trip() {
+        // the same opcode means something different in each variant
+        assert_eq!(Variant::Chip8.decode(0x5121), decode(0x5121));
+        assert_eq!(
+            Variant::Chip8x.decode(0x5121),
+            Ok(Instruction::Extended(Extended::AddNibbles(1, 2)))
+        );
+        assert_eq!(
+            Variant::Chip8e.decode(0x5121),
+            Ok(Instruction::Extended(Extended::Sgt(1, 2)))
+        );
+        assert_eq!(Variant::Hires.decode(0x0230).unwrap().to_string(), "CLS");
+        assert_eq!(Variant::Chip8.decode(0x0230), Ok(Instruction::Sys(0x230)));
+        assert_eq!(
+            Variant::Schip.decode(0x00FD),
+            Ok(Instruction::Extended(Extended::Exit))
+        );
+        assert_eq!(Variant::Chip8.decode(0x00FD), Ok(Instruction::Sys(0x0FD)));
+        assert!(Variant::Chip8.decode(0xF375).is_err());
+
+        // what the variants don't change decodes as before
+        for variant in [
+            Variant::Chip8x,
+            Variant::Chip8e,
+            Variant::Hires,
+            Variant::Schip,
+            Variant::Megachip,
+        ] {
+            assert_eq!(variant.decode(0xD125), Ok(Instruction::Drw(1, 2, 5)));
+            for opcode in 0..=0xFFFF {
+                if let Some(extended) = variant.extension(opcode) {
+                    assert_eq!(extended.encode(), opcode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_chip8x_colors() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.set_variant(Variant::Chip8x);
+        cpu.registers[0] = 0x31; // zone columns 1 to 3
+        cpu.registers[1] = 0x22; // zone row 2
+        cpu.registers[2] = 4; // green
+        cpu.registers[3] = 0x33;
+        cpu.registers[4] = 0x25;
+        cpu.heap.load(
+            0x300,
+            &[
+                0xB0, 0x20, // COL V0, V2
+                0x02, 0xA0, // BGC
+                0x53, 0x41, // ADDN V3, V4
+            ],
+        );
+        cpu.program_counter = 0x300;
+        for _ in 0..3 {
+            cpu.tick();
+        }
+
+        let colors = cpu.display.colors().unwrap();
+        assert_eq!(colors.at(8, 8), 4);
+        assert_eq!(colors.at(31, 11), 4);
+        assert_eq!(colors.at(32, 8), 1);
+        assert_eq!(colors.at(8, 12), 1);
+        assert_eq!(colors.background, 1);
+        // 3 + 5 keeps its low 3 bits
+        assert_eq!(cpu.registers[3], 0x50);
+    }
+
+    #[test]
+    fn test_chip8e_instructions() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.set_variant(Variant::Chip8e);
+        cpu.registers[1] = 200;
+        cpu.registers[2] = 7;
+        cpu.i_register = 0x400;
+        cpu.heap.load(
+            0x200,
+            &[
+                0x51, 0x21, // 200: SGT V1, V2
+                0x00, 0x00, // 202: skipped
+                0x91, 0x22, // 204: DIV V1, V2: 28 remainder 4
+                0x51, 0x22, // 206: LD [I], V1-V2
+                0x92, 0x13, // 208: BCD V2, V1: 7 * 256 + 28
+                0xBF, 0x02, // 20A: JF 2
+                0x00, 0x00, // 20C: jumped over
+                0x63, 0x30, // 20E: LD V3, 0x30
+                0x93, 0x11, // 210: MUL V3, V1
+            ],
+        );
+        for _ in 0..7 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.program_counter, 0x212);
+        assert_eq!(&cpu.heap[0x400..0x402], &[28, 7]);
+        assert_eq!(&cpu.heap[0x402..0x407], &[0, 1, 8, 2, 0]);
+        assert_eq!(cpu.i_register, 0x402);
+        // 48 * 28 = 0x540
+        assert_eq!((cpu.registers[3], cpu.registers[0xF]), (0x40, 0x05));
+
+        // DELAY loads the timer once and holds the program until it runs out
+        cpu.registers[5] = 2;
+        cpu.heap.load(0x212, &[0xF5, 0x4F, 0x00, 0xED]);
+        cpu.tick();
+        assert_eq!((cpu.program_counter, cpu.delay_timer), (0x212, 2));
+        cpu.update_timers();
+        cpu.tick();
+        cpu.update_timers();
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x214);
+        // STOP stays put
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x214);
+    }
+
+    #[test]
+    fn test_hires_layout() {
+        let display = Variant::Hires.display();
+        assert_eq!((display.width(), display.height()), (64, 64));
+        assert_eq!(Variant::Hires.entry(&[0x12, 0x60, 0x00]), 0x2C0);
+        assert_eq!(Variant::Hires.entry(&[0x12, 0x00]), 0x200);
+        assert_eq!(Variant::Chip8x.entry(&[0x12, 0x60]), 0x300);
+    }
+}
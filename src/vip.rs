@@ -0,0 +1,370 @@
+use crate::cdp1802::{Bus, Cdp1802};
+use crate::cdp1861::{Cdp1861, CYCLES_PER_FRAME};
+use crate::config::invalid;
+use crate::cpu::CPU;
+use crate::display::Display;
+use crate::keyboard::Keyboard;
+use crate::memory::Memory;
+use std::io;
+use std::sync::Arc;
+
+// a 4K VIP, the interpreter was written for one
+pub const RAM_SIZE: usize = 4096;
+// the monitor ROM is 512 bytes at 0x8000, repeated up to 0xFFFF
+const MONITOR_SIZE: usize = 0x200;
+const MONITOR_START: u16 = 0x8000;
+
+// where the VIP interpreter keeps its state, counted down from the top of RAM
+const REGISTERS_FROM_TOP: usize = 0x110;
+const DISPLAY_FROM_TOP: usize = 0x100;
+// everything from the 1802 stack up, a ROM can't be loaded over it
+pub const STACK_FROM_TOP: usize = 0x131;
+
+// give up on a machine code subroutine that hasn't returned after a second
+const MAX_SUBROUTINE_CYCLES: u32 = CYCLES_PER_FRAME * 60;
+
+// how the hex keypad is wired: OUT 2 latches the key to test, EF3 says if it's down
+struct Keypad {
+    keyboard: Arc<Keyboard>,
+    latch: u8,
+}
+
+impl Keypad {
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 2 {
+            self.latch = value & 0xF;
+        }
+    }
+
+    fn flag(&self, n: u8) -> bool {
+        n == 3 && self.keyboard.is_key_pressed(self.latch)
+    }
+}
+
+// everything the 1802 in a VIP can reach
+struct VipBus {
+    ram: Vec<u8>,
+    monitor: Option<Vec<u8>>,
+    // after a reset the monitor also shows up at 0x0000, until the first access
+    // with A15 set
+    monitor_low: bool,
+    keypad: Keypad,
+    video: Cdp1861,
+}
+
+impl Bus for VipBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        if addr >= MONITOR_START {
+            self.monitor_low = false;
+        }
+        match &self.monitor {
+            Some(monitor) if addr >= MONITOR_START || self.monitor_low => {
+                monitor[addr as usize % MONITOR_SIZE]
+            }
+            // nothing drives the bus where there is no ROM
+            None if addr >= MONITOR_START => 0xFF,
+            _ => self.ram[addr as usize % RAM_SIZE],
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr >= MONITOR_START {
+            self.monitor_low = false;
+        } else {
+            self.ram[addr as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 1 {
+            self.video.enabled = false;
+        }
+        self.keypad.output(port, value);
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.video.enabled = true;
+        }
+        0xFF
+    }
+
+    fn flag(&mut self, n: u8) -> bool {
+        match n {
+            1 => self.video.flag(),
+            _ => self.keypad.flag(n),
+        }
+    }
+}
+
+/// A COSMAC VIP: an 1802 with 4K of RAM, the monitor ROM, a CDP1861 for video,
+/// the hex keypad and a tone that Q switches on. It runs the original CHIP-8
+/// interpreter, or any other VIP program, rather than emulating CHIP-8 itself.
+pub struct Vip {
+    pub cpu: Cdp1802,
+    bus: VipBus,
+    // the picture as of the last finished frame
+    pub display: Display,
+}
+
+impl Vip {
+    /// A VIP with `interpreter` loaded at 0x0000. With a `monitor` it boots through
+    /// it as the real machine does; without one it starts the interpreter the way
+    /// the monitor leaves things, with R1 pointing at the last page of RAM.
+    pub fn new(
+        keyboard: Arc<Keyboard>,
+        interpreter: &[u8],
+        monitor: Option<Vec<u8>>,
+    ) -> io::Result<Self> {
+        if interpreter.len() > RAM_SIZE {
+            return Err(invalid(format!(
+                "the interpreter image is {} bytes, the VIP has {} bytes of RAM",
+                interpreter.len(),
+                RAM_SIZE
+            )));
+        }
+        if let Some(monitor) = &monitor {
+            if monitor.is_empty() || monitor.len() > MONITOR_SIZE {
+                return Err(invalid(format!(
+                    "the monitor ROM is {} bytes, expected at most {}",
+                    monitor.len(),
+                    MONITOR_SIZE
+                )));
+            }
+        }
+
+        let mut ram = vec![0; RAM_SIZE];
+        ram[..interpreter.len()].copy_from_slice(interpreter);
+        let mut cpu = Cdp1802::new();
+        if monitor.is_none() {
+            cpu.r[1] = (RAM_SIZE - 0x100) as u16;
+        }
+        Ok(Vip {
+            cpu,
+            bus: VipBus {
+                ram,
+                monitor_low: monitor.is_some(),
+                monitor: monitor.map(|mut monitor| {
+                    // a short image is padded with 0xFF, as an unprogrammed EPROM reads
+                    monitor.resize(MONITOR_SIZE, 0xFF);
+                    monitor
+                }),
+                keypad: Keypad { keyboard, latch: 0 },
+                video: Cdp1861::new(),
+            },
+            display: Display::new(),
+        })
+    }
+
+    // copy a program into RAM, CHIP-8 programs go at 0x200
+    pub fn load(&mut self, addr: usize, data: &[u8]) -> io::Result<()> {
+        if addr + data.len() > RAM_SIZE {
+            return Err(invalid("the program doesn't fit in the VIP's RAM"));
+        }
+        self.bus.ram[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    // whether the tone is sounding
+    pub fn sound(&self) -> bool {
+        self.cpu.q
+    }
+
+    /// Run a 60 Hz frame. DMA takes priority over the interrupt, which takes
+    /// priority over the next instruction, and both only happen between
+    /// instructions, as on the 1802.
+    pub fn run_frame(&mut self) {
+        loop {
+            let cycles = if self.bus.video.dma_pending() {
+                let byte = self.cpu.dma_out(&mut self.bus);
+                self.bus.video.dma(byte);
+                1
+            } else if self.bus.video.interrupt() && self.cpu.interrupt() {
+                1
+            } else {
+                self.cpu.step(&mut self.bus)
+            };
+            if self.bus.video.advance(cycles) {
+                break;
+            }
+        }
+        self.bus.video.show(&mut self.display);
+    }
+}
+
+// the CHIP-8 heap seen from a machine code subroutine
+struct SharedBus<'a> {
+    heap: &'a mut Memory,
+    keypad: Keypad,
+}
+
+impl Bus for SharedBus<'_> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let addr = addr as usize % self.heap.len();
+        self.heap.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        let addr = addr as usize % self.heap.len();
+        self.heap.write(addr, value);
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        self.keypad.output(port, value);
+    }
+
+    fn flag(&mut self, n: u8) -> bool {
+        self.keypad.flag(n)
+    }
+}
+
+/// Run the 1802 subroutine a CHIP-8 program calls with 0nnn, against the CHIP-8
+/// heap. The top of memory is laid out as the VIP interpreter has it: V0-VF at
+/// 0xEF0, the display at 0xF00 and the stack below 0xED0 (in 4K). The 1802
+/// registers are set as the interpreter leaves them, I in RA, the CHIP-8 PC in
+/// R5, the timers in R8 and the display page in RB.1, and the subroutine runs
+/// with R3 as its PC until it returns with D4 (SEP R4). Whatever it changed in
+/// all of those is copied back.
+pub fn call_machine_code(cpu: &mut CPU, addr: u16) {
+    let top = cpu.heap.len();
+    let registers = top - REGISTERS_FROM_TOP;
+    let display = top - DISPLAY_FROM_TOP;
+
+    let mut screen = Vec::with_capacity(DISPLAY_FROM_TOP);
+    for row in cpu.display.rows().iter().take(DISPLAY_FROM_TOP / 8) {
+        screen.extend_from_slice(&row.to_be_bytes());
+    }
+    cpu.heap.load(registers, &cpu.registers);
+    cpu.heap.load(display, &screen);
+
+    let mut cdp1802 = Cdp1802::new();
+    cdp1802.ie = false;
+    cdp1802.p = 3;
+    cdp1802.x = 2;
+    cdp1802.r[2] = (top - STACK_FROM_TOP) as u16;
+    cdp1802.r[3] = addr;
+    cdp1802.r[5] = cpu.program_counter as u16;
+    cdp1802.r[8] = (cpu.delay_timer as u16) << 8 | cpu.sound_timer as u16;
+    cdp1802.r[0xA] = cpu.i_register as u16;
+    cdp1802.r[0xB] = (display as u16) & 0xFF00;
+
+    let mut bus = SharedBus {
+        heap: &mut cpu.heap,
+        keypad: Keypad {
+            keyboard: cpu.keyboard.clone(),
+            latch: 0,
+        },
+    };
+    let mut cycles = 0;
+    while cdp1802.p != 4 && cycles < MAX_SUBROUTINE_CYCLES {
+        cycles += cdp1802.step(&mut bus);
+    }
+
+    cpu.registers
+        .copy_from_slice(&cpu.heap[registers..registers + 16]);
+    for (row, bytes) in cpu
+        .display
+        .rows_mut()
+        .iter_mut()
+        .zip(cpu.heap[display..top].chunks(8))
+    {
+        let mut word = [0; 8];
+        word.copy_from_slice(bytes);
+        *row = u64::from_be_bytes(word);
+    }
+    cpu.i_register = cdp1802.r[0xA] as u32;
+    cpu.program_counter = cdp1802.r[5] as usize % top;
+    cpu.delay_timer = (cdp1802.r[8] >> 8) as u8;
+    cpu.sound_timer = cdp1802.r[8] as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_with_program;
+
+    #[test]
+    fn test_machine_code_subroutine() {
+        let mut cpu = cpu_with_program(&[
+            0x63, 0x05, // 200: LD V3, 5
+            0x03, 0x00, // 202: SYS 0x300
+            0x12, 0x04, // 204: JP 0x204
+        ]);
+        cpu.quirks.machine_code = true;
+        cpu.heap.load(
+            0x300,
+            &[
+                0xF8, 0xF3, 0xA6, 0xF8, 0x0E, 0xB6, // R6 = 0xEF3, V3
+                0x06, 0xFC, 0x01, 0x56, // V3 += 1
+                0x8A, 0xFC, 0x02, 0xAA, // I += 2
+                0xF8, 0x00, 0xAF, 0x9B, 0xBF, // RF = the display page
+                0xF8, 0x81, 0x5F, // first display byte = 0x81
+                0x15, 0x15, // R5 += 2, skipping the JP
+                0xD4, // SEP R4, back to CHIP-8
+            ],
+        );
+        cpu.i_register = 0x123;
+        cpu.tick();
+        cpu.tick();
+
+        assert_eq!(cpu.registers[3], 6);
+        assert_eq!(cpu.i_register, 0x125);
+        assert_eq!(cpu.display.rows()[0], 0x8100_0000_0000_0000);
+        assert_eq!(cpu.program_counter, 0x206);
+        assert_eq!(cpu.heap[0xEF3], 6);
+
+        // without the quirk 0nnn still does nothing
+        let mut cpu = cpu_with_program(&[0x03, 0x00]);
+        cpu.tick();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn test_vip_video_and_keypad() {
+        let keyboard = Arc::new(Keyboard::new());
+        let interpreter = [
+            0xF8, 0x00, 0xB1, // 00: R1.1 = 0
+            0xF8, 0x41, 0xA1, // 03: R1 = 0x0041, the interrupt routine
+            0xF8, 0xFF, 0xA2, // 06: R2 = 0x00FF, the stack
+            0xF8, 0x0E, 0xA3, // 09: R3 = 0x000E
+            0xE2, 0xD3, // 0C: SEX R2; SEP R3, leaving R0 to the DMA
+            0x69, // 0E: INP 1, display on
+            0xF8, 0x0B, 0x52, // 0F: key B to the latch
+            0x62, // 12: OUT 2
+            0x22, // 13: DEC R2, OUT moved it on
+            0x36, 0x19, // 14: B3 19, key down
+            0x7A, // 16: REQ
+            0x30, 0x14, // 17: BR 14
+            0x7B, // 19: SEQ
+            0x30, 0x14, // 1A: BR 14
+        ];
+        let mut vip = Vip::new(keyboard.clone(), &interpreter, None).unwrap();
+        // the interrupt routine shows the 8 bytes at 0x300 on every line, and returns
+        // through the RET before it so R1 is left pointing at it again
+        vip.load(
+            0x40,
+            &[
+                0x70, // 40: RET
+                0x22, 0x78, // 41: DEC R2; SAV
+                0xF8, 0x03, 0xB0, 0xF8, 0x00, 0xA0, // 43: R0 = 0x300
+                0x34, 0x49, // 49: B1 49, wait for the picture
+                0xF8, 0x00, 0xA0, // 4B: R0.0 = 0
+                0x3C, 0x4B, // 4E: BN1 4B, until its last lines
+                0x30, 0x40, // 50: BR 40
+            ],
+        )
+        .unwrap();
+        vip.load(0x300, &[0xF0, 0, 0, 0, 0, 0, 0, 0x0F]).unwrap();
+
+        vip.run_frame();
+        vip.run_frame();
+        for &row in vip.display.rows() {
+            assert_eq!(row, 0xF000_0000_0000_000F);
+        }
+        assert!(!vip.sound());
+
+        keyboard.set_key(0xB, true);
+        vip.run_frame();
+        assert!(vip.sound());
+    }
+}